@@ -16,12 +16,14 @@ fn main() {
     let mut char_iter = "thisisatest".chars(); // More than 5 characters long.
     loop {
         // Make sure the client doesn't just run forever.
-        if time::Instant::now().duration_since(start_time) >= time::Duration::from_secs(3) {
+        let Some(time_left) = time::Duration::from_secs(3)
+            .checked_sub(time::Instant::now().duration_since(start_time))
+        else {
             println!("CLIENT: Done!");
             break;
-        }
-        // Check the server
-        if let Some(ch) = server_array.pop() {
+        };
+        // Wait for the server, without spinning.
+        if let Some(ch) = server_array.pop_timeout(time_left) {
             println!("CLIENT: Sending message #{ch}...");
             client_array.push(char_iter.next().unwrap());
         }
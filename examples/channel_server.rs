@@ -13,26 +13,21 @@ fn main() {
         .spawn()
         .unwrap();
 
-    // Give the client some time to start. This should be enough.
-    std::thread::sleep(std::time::Duration::from_secs(2));
-
-    // Wait to open the client array until the client has allocated it.
-    let mut client_array: Array<char> = Array::open("/tmp/CHANNEL_CLIENT").unwrap();
+    // Wait for the client to start up and allocate its array (it may have to be built first).
+    let mut client_array: Array<char> =
+        Array::open_wait("/tmp/CHANNEL_CLIENT", std::time::Duration::from_secs(60)).unwrap();
 
     let mut msg_count = 0;
     server_array.push(char::from_digit(msg_count, 10).unwrap());
-    loop {
-        // Only process the first 5 messages.
-        if msg_count >= 5 {
-            println!("SERVER: Done!");
-            let _ = client.wait().unwrap();
-            break;
-        }
-        // Process the next message, if there is any.
-        if let Some(client_message) = client_array.pop() {
-            println!("SERVER: Received message '{}' from client.", client_message);
-            msg_count += 1;
-            server_array.push(char::from_digit(msg_count, 10).unwrap());
-        }
+    // Only process the first 5 messages.
+    while msg_count < 5 {
+        // Wait for the next message, without spinning.
+        let client_message = client_array.pop_blocking();
+        println!("SERVER: Received message '{}' from client.", client_message);
+        msg_count += 1;
+        server_array.push(char::from_digit(msg_count, 10).unwrap());
     }
+
+    println!("SERVER: Done!");
+    let _ = client.wait().unwrap();
 }
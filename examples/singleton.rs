@@ -1,21 +1,22 @@
-// A FizzBuzz clone that uses the `Block` type to synchronize a singleton string.
+// A FizzBuzz clone that uses the `Mutex` type to synchronize a singleton string.
 
 
 
 use arrayvec::ArrayString;
-use ration::Block;
+use ration::{Mutex, ShmSafe, TypeLayout};
 
 
 
 fn main() {
     // Always keep an owned reference to allocated shared memory objects around for longer than
     // all other unowned references. The file won't close correctly if you don't follow this rule.
-    // This goes for arrays too.
-    let mut owned_block: Block<MySingleton> = Block::alloc("/tmp/RATION_SINGLETON").unwrap();
+    // Blocks and arrays can relax it with a different `ration::Lifetime` (see `BlockOptions` and
+    // `ArrayOptions`), or hand ownership over with `disown` and `set_owner`.
+    let owned_mutex: Mutex<MySingleton> = Mutex::alloc("/tmp/RATION_SINGLETON").unwrap();
 
-    // Make `owned_block` a valid instance of `MySingleton`. Without this, your reference
+    // Make `owned_mutex` a valid instance of `MySingleton`. Without this, your reference
     // instances will be invalid and you'll get the dreaded "undefined behavior".
-    *owned_block = MySingleton {
+    *owned_mutex.lock().unwrap() = MySingleton {
         my_pointerless_string: ArrayString::new(),
     };
 
@@ -40,42 +41,49 @@ fn main() {
     // Only print the first ten Fizzes/Buzzes/FizzBuzzes.
     // Should run for 21 seconds.
     while print_count < 10 {
-        if owned_block.my_pointerless_string.len() > 0 {
-            std::thread::sleep(std::time::Duration::from_millis(30));
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        // No other instance can touch the singleton while it's locked.
+        let mut singleton = owned_mutex.lock().unwrap();
+        if !singleton.my_pointerless_string.is_empty() {
             let seconds_since_start = std::time::Instant::now()
                 .duration_since(program_start_time)
                 .as_secs();
             println!(
                 "{}, {} seconds since program start",
-                owned_block.my_pointerless_string,
+                singleton.my_pointerless_string,
                 seconds_since_start,
             );
-            owned_block.my_pointerless_string.clear();
+            singleton.my_pointerless_string.clear();
             print_count += 1;
         }
     }
 }
 
-// Remember, absolutely no pointers allowed in shared memory objects.
+// Remember, absolutely no (normal) pointers allowed in shared memory objects. To point at something
+// in the same segment, use a `ration::RelPtr` or `ration::OffsetPtr`.
 // If you need to have a string in your shared type, use something like `arrayvec::ArrayString`.
+// Every shared object enforces this with the `ration::ShmSafe` trait, which can be derived.
+// (`ArrayString` implements it when ration's `arrayvec` feature is enabled.)
+#[derive(ShmSafe, TypeLayout)]
+#[repr(C)]
 struct MySingleton {
     pub my_pointerless_string: ArrayString<8>,
 }
 
 fn thread_a() {
-    let mut local_block: Block<MySingleton> = Block::open("/tmp/RATION_SINGLETON").unwrap();
+    let local_mutex: Mutex<MySingleton> = Mutex::open("/tmp/RATION_SINGLETON").unwrap();
 
     loop {
         std::thread::sleep(std::time::Duration::from_secs(3));
-        local_block.my_pointerless_string.push_str("Fizz");
+        local_mutex.lock().unwrap().my_pointerless_string.push_str("Fizz");
     }
 }
 
 fn thread_b() {
-    let mut local_block: Block<MySingleton> = Block::open("/tmp/RATION_SINGLETON").unwrap();
+    let local_mutex: Mutex<MySingleton> = Mutex::open("/tmp/RATION_SINGLETON").unwrap();
 
     loop {
         std::thread::sleep(std::time::Duration::from_secs(5));
-        local_block.my_pointerless_string.push_str("Buzz");
+        local_mutex.lock().unwrap().my_pointerless_string.push_str("Buzz");
     }
 }
@@ -0,0 +1,199 @@
+// Measures how many elements per second can be passed through an `Array` from a producer to a
+// consumer, and how much of that comes from keeping its cursors on separate cache lines. Run it
+// with `cargo run --release --example throughput`.
+
+
+
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use ration::Array;
+
+
+
+const ELEMENTS: u64 = 10_000_000;
+const CAPACITY: usize = 1024;
+const RUNS: usize = 5;
+
+
+
+fn main() {
+    let array = bench("Array", run_array);
+    let padded = bench("Ring, padded cursors", run_ring::<Padded>);
+    let unpadded = bench("Ring, unpadded cursors", run_ring::<Unpadded>);
+
+    println!();
+    println!("Array:                  {:.1} million elements per second", rate(array));
+    println!("Ring, padded cursors:   {:.1} million elements per second", rate(padded));
+    println!("Ring, unpadded cursors: {:.1} million elements per second", rate(unpadded));
+}
+
+/// Run `run_once` a few times, and return the best time.
+fn bench(name: &str, run_once: fn() -> Duration) -> Duration {
+    let mut best = Duration::MAX;
+    for run in 1..=RUNS {
+        let elapsed = run_once();
+        println!("{name}, run #{run}: {:.1} million elements per second", rate(elapsed));
+        best = best.min(elapsed);
+    }
+    best
+}
+
+fn rate(elapsed: Duration) -> f64 {
+    ELEMENTS as f64 / elapsed.as_secs_f64() / 1_000_000.0
+}
+
+fn run_array() -> Duration {
+    let mut array: Array<u64> = Array::alloc("/tmp/RATION_THROUGHPUT", CAPACITY).unwrap();
+
+    // The consumer gets its own mapping of the array, just like it would in another process.
+    let consumer = std::thread::spawn(|| {
+        let mut array: Array<u64> = Array::open("/tmp/RATION_THROUGHPUT").unwrap();
+        let mut expected = 0;
+        while expected < ELEMENTS {
+            if let Some(element) = array.pop() {
+                assert_eq!(element, expected);
+                expected += 1;
+            } else {
+                std::thread::yield_now();
+            }
+        }
+    });
+
+    let start = Instant::now();
+    for i in 0..ELEMENTS {
+        while !array.push(i) {
+            std::thread::yield_now();
+        }
+    }
+    consumer.join().unwrap();
+
+    start.elapsed()
+}
+
+fn run_ring<C: Cursors>() -> Duration {
+    let ring = Arc::new(Ring::<C>::new());
+
+    let consumer = std::thread::spawn({
+        let ring = ring.clone();
+        move || {
+            let mut expected = 0;
+            while expected < ELEMENTS {
+                if let Some(element) = ring.pop() {
+                    assert_eq!(element, expected);
+                    expected += 1;
+                } else {
+                    std::thread::yield_now();
+                }
+            }
+        }
+    });
+
+    let start = Instant::now();
+    for i in 0..ELEMENTS {
+        while !ring.push(i) {
+            std::thread::yield_now();
+        }
+    }
+    consumer.join().unwrap();
+
+    start.elapsed()
+}
+
+
+
+/// The same single-producer, single-consumer ring buffer as `Array`, with its cursors laid out by
+/// `C`, so that the only difference between runs is where the cursors live.
+struct Ring<C> {
+    cursors: C,
+    slots: Box<[UnsafeCell<u64>]>,
+}
+
+// SAFETY: Only one thread pushes and only one pops, and the cursors hand slots between them.
+unsafe impl<C: Sync> Sync for Ring<C> {}
+
+impl<C: Cursors> Ring<C> {
+    fn new() -> Self {
+        Self {
+            cursors: C::default(),
+            slots: (0..CAPACITY).map(|_| UnsafeCell::new(0)).collect(),
+        }
+    }
+
+    fn push(&self, element: u64) -> bool {
+        let tail = self.cursors.tail().load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.cursors.head().load(Ordering::Acquire)) == CAPACITY {
+            return false;
+        }
+
+        unsafe { *self.slots[tail % CAPACITY].get() = element };
+        self.cursors.tail().store(tail.wrapping_add(1), Ordering::Release);
+
+        true
+    }
+
+    fn pop(&self) -> Option<u64> {
+        let head = self.cursors.head().load(Ordering::Relaxed);
+        if head == self.cursors.tail().load(Ordering::Acquire) {
+            return None;
+        }
+
+        let element = unsafe { *self.slots[head % CAPACITY].get() };
+        self.cursors.head().store(head.wrapping_add(1), Ordering::Release);
+
+        Some(element)
+    }
+}
+
+trait Cursors: Default + Send + Sync + 'static {
+    /// The number of elements that have been pushed.
+    fn tail(&self) -> &AtomicUsize;
+    /// The number of elements that have been popped.
+    fn head(&self) -> &AtomicUsize;
+}
+
+/// Both cursors next to each other, on the same cache line.
+#[derive(Default)]
+#[repr(C)]
+struct Unpadded {
+    tail: AtomicUsize,
+    head: AtomicUsize,
+}
+
+impl Cursors for Unpadded {
+    fn tail(&self) -> &AtomicUsize {
+        &self.tail
+    }
+
+    fn head(&self) -> &AtomicUsize {
+        &self.head
+    }
+}
+
+/// Each cursor on its own (128-byte) cache line, the way `Array` lays them out.
+#[derive(Default)]
+#[repr(C)]
+struct Padded {
+    tail: CachePadded,
+    head: CachePadded,
+}
+
+#[derive(Default)]
+#[repr(C, align(128))]
+struct CachePadded(AtomicUsize);
+
+impl Cursors for Padded {
+    fn tail(&self) -> &AtomicUsize {
+        &self.tail.0
+    }
+
+    fn head(&self) -> &AtomicUsize {
+        &self.head.0
+    }
+}
@@ -0,0 +1,262 @@
+//! Shared Heap Box
+
+
+
+use allocator_api2::alloc::AllocError;
+use core::{alloc::Layout, marker::PhantomData};
+use std::io;
+
+use crate::SharedAllocator;
+
+
+
+/// A single value in a shared heap, which other processes can open and modify.
+///
+/// Unlike a `Box<T, SharedAllocator>`, a `SharedBox` only remembers where its value is as an
+/// offset into the heap, so it can be handed to another process (by [`offset`](SharedBox::offset)
+/// or by [`publish`](SharedBox::publish)ed name) and opened there.
+///
+/// The box that allocated the value owns it, and frees it when dropped. Boxes opened from an
+/// offset or a name don't, so make sure the owner outlives them.
+///
+/// # Example
+/// *In your "parent" process:*
+/// ```no_run
+/// use ration_allocator::{SharedAllocator, SharedBox};
+///
+/// let heap = SharedAllocator::create("/MY_HEAP", 1 << 20).unwrap();
+/// let counter = SharedBox::new_in(0_u64, &heap).unwrap();
+/// counter.publish("counter").unwrap();
+/// ```
+/// *In your "child" process:*
+/// ```no_run
+/// use ration_allocator::{SharedAllocator, SharedBox};
+///
+/// let heap = SharedAllocator::open("/MY_HEAP").unwrap();
+/// let mut counter = unsafe { SharedBox::<u64>::open(&heap, "counter") }.unwrap();
+/// *counter += 1;
+/// ```
+///
+/// # Warning
+///
+/// Nothing synchronizes access to the value. If more than one process modifies it at once, wrap
+/// it in something that does (like atomics), or guard it with a lock.
+pub struct SharedBox<T> {
+    heap: SharedAllocator,
+    offset: usize,
+    owner: bool,
+    _marker: PhantomData<T>,
+}
+
+/// Stored just before a box's value, so that [`SharedBox::open`] can check that it's being opened
+/// as the right type.
+#[repr(C)]
+struct ValueInfo {
+    size: usize,
+    align: usize,
+}
+
+impl ValueInfo {
+    fn of<T>() -> Self {
+        Self {
+            size: core::mem::size_of::<T>(),
+            align: core::mem::align_of::<T>(),
+        }
+    }
+}
+
+impl<T> SharedBox<T> {
+    /// The offset of the value from the start of its allocation. The [`ValueInfo`] sits right
+    /// before it.
+    const VALUE_START: usize = core::mem::size_of::<ValueInfo>()
+        .next_multiple_of(core::mem::align_of::<T>());
+
+    /// Move the given value into the heap.
+    pub fn new_in(value: T, heap: &SharedAllocator) -> Result<Self, AllocError> {
+        let layout = Layout::from_size_align(
+            Self::VALUE_START + core::mem::size_of::<T>(),
+            core::mem::align_of::<T>().max(core::mem::align_of::<ValueInfo>()),
+        )
+        .map_err(|_| AllocError)?;
+        let offset = heap.alloc_chunk(layout).ok_or(AllocError)? + Self::VALUE_START;
+        unsafe {
+            Self::info_ptr(heap, offset).write(ValueInfo::of::<T>());
+            (heap.ptr_at(offset) as *mut T).write(value);
+        }
+
+        Ok(Self {
+            heap: heap.clone(),
+            offset,
+            owner: true,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Open the value at the given offset into the heap.
+    ///
+    /// The returned box doesn't own the value.
+    ///
+    /// # Safety
+    ///
+    /// The offset must have come from a `SharedBox<T>` (of the same `T`) in the same heap, whose
+    /// value is still alive.
+    pub unsafe fn from_offset(heap: &SharedAllocator, offset: usize) -> Self {
+        Self {
+            heap: heap.clone(),
+            offset,
+            owner: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Open the value published under the given name, if there is one.
+    ///
+    /// The returned box doesn't own the value. Returns `None` if the published value has a
+    /// different size or alignment than `T`.
+    ///
+    /// # Safety
+    ///
+    /// The name must have been published by a `SharedBox<T>` (of the same `T`).
+    pub unsafe fn open(heap: &SharedAllocator, name: &str) -> Option<Self> {
+        let offset = heap.lookup(name)?;
+        if offset < core::mem::size_of::<ValueInfo>() {
+            return None;
+        }
+
+        let info = unsafe { Self::info_ptr(heap, offset).read_unaligned() };
+        let expected = ValueInfo::of::<T>();
+        (info.size == expected.size && info.align == expected.align)
+            .then(|| unsafe { Self::from_offset(heap, offset) })
+    }
+
+    /// Publish this box under the given name, so that other processes can
+    /// [`open`](SharedBox::open) it.
+    ///
+    /// The name is removed once the value is freed.
+    pub fn publish(&self, name: &str) -> io::Result<()> {
+        self.heap.publish(name, self.offset)
+    }
+
+    /// The offset of the value from the start of the heap.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns `true` if this box owns the value, and will free it when dropped.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    /// Give up ownership of the value without freeing it, returning its offset.
+    ///
+    /// The value can be reclaimed later with [`from_offset`](SharedBox::from_offset).
+    pub fn into_offset(mut self) -> usize {
+        self.owner = false;
+        self.offset
+    }
+
+    fn ptr(&self) -> *mut T {
+        self.heap.ptr_at(self.offset) as *mut T
+    }
+
+    fn info_ptr(heap: &SharedAllocator, offset: usize) -> *mut ValueInfo {
+        heap.ptr_at(offset - core::mem::size_of::<ValueInfo>()) as *mut ValueInfo
+    }
+}
+
+impl<T> core::ops::Deref for SharedBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl<T> core::ops::DerefMut for SharedBox<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.ptr() }
+    }
+}
+
+impl<T> Drop for SharedBox<T> {
+    fn drop(&mut self) {
+        if !self.owner {
+            return;
+        }
+
+        self.heap.unpublish_offset(self.offset);
+        unsafe { self.ptr().drop_in_place() };
+        self.heap.free_chunk(self.offset - Self::VALUE_START);
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for SharedBox<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SharedBox")
+            .field("offset", &self.offset)
+            .field("obj", &**self)
+            .finish_non_exhaustive()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharedbox_across_mappings() {
+        let heap = SharedAllocator::create("/TEST_SHAREDBOX", 1 << 16).unwrap();
+        let mut value = SharedBox::new_in((1_u32, [0_u8; 7]), &heap).unwrap();
+        assert!(value.is_owner());
+        value.publish("value").unwrap();
+
+        {
+            let ref_heap = SharedAllocator::open("/TEST_SHAREDBOX").unwrap();
+            let mut ref_value = unsafe { SharedBox::<(u32, [u8; 7])>::open(&ref_heap, "value") }
+                .unwrap();
+            assert!(!ref_value.is_owner());
+            assert_eq!(ref_value.offset(), value.offset());
+            assert_eq!(ref_value.0, 1);
+
+            ref_value.0 = 2;
+            ref_value.1[6] = 3;
+        }
+
+        assert_eq!(*value, (2, [0, 0, 0, 0, 0, 0, 3]));
+        value.1[0] = 4;
+
+        let offset = value.into_offset();
+        let value = unsafe { SharedBox::<(u32, [u8; 7])>::from_offset(&heap, offset) };
+        assert_eq!(value.1[0], 4);
+        assert_eq!(heap.lookup("value"), Some(offset));
+    }
+
+    #[test]
+    fn sharedbox_open_checks_type() {
+        let heap = SharedAllocator::create("/TEST_SHAREDBOX_TYPE", 1 << 16).unwrap();
+        let value = SharedBox::new_in([7_u32; 4], &heap).unwrap();
+        value.publish("value").unwrap();
+
+        unsafe {
+            assert!(SharedBox::<[u32; 4]>::open(&heap, "value").is_some());
+            assert!(SharedBox::<[u32; 2]>::open(&heap, "value").is_none());
+            assert!(SharedBox::<[u8; 16]>::open(&heap, "value").is_none());
+        }
+    }
+
+    #[test]
+    fn sharedbox_drop_frees() {
+        let heap = SharedAllocator::create("/TEST_SHAREDBOX_DROP", 1 << 16).unwrap();
+
+        let value = SharedBox::new_in(std::sync::Arc::new(5), &heap).unwrap();
+        let counted = std::sync::Arc::clone(&value);
+        value.publish("value").unwrap();
+        assert_eq!(std::sync::Arc::strong_count(&counted), 2);
+
+        drop(value);
+        assert_eq!(std::sync::Arc::strong_count(&counted), 1);
+        assert_eq!(heap.lookup("value"), None);
+    }
+}
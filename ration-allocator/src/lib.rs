@@ -1,25 +1,627 @@
 //! Ration shared memory allocator
+//!
+//! A [`SharedAllocator`] carves its allocations out of a single named shared memory segment (the
+//! "heap"), so anything allocated with it (the elements of a `Vec<T, SharedAllocator>`, the
+//! contents of a `Box<T, SharedAllocator>`, etc.) can be read by any other process that opens the
+//! same heap.
+//!
+//! `SharedAllocator` implements the [`Allocator`](allocator_api2::alloc::Allocator) trait from
+//! [`allocator_api2`] (re-exported here), so it can be used with that crate's `Vec` and `Box` on
+//! stable Rust. With the `nightly` feature enabled, that trait is the standard library's own
+//! `Allocator`, so `std`'s collections work too.
+//!
+//! Every process maps the heap at a different address, so allocations should be handed between
+//! processes as offsets into the heap (see [`SharedAllocator::offset_of`] and
+//! [`SharedAllocator::ptr_at`]), never as pointers. The [`SharedBox`] and [`SharedVec`] types do
+//! this for you, and can be opened in another process by offset or by name.
 
-#![feature(allocator_api)]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
 
 
+mod boxed;
+mod lock;
+mod vec;
+
+pub use boxed::SharedBox;
+pub use vec::SharedVec;
+
+pub use allocator_api2;
+
+use allocator_api2::alloc::AllocError;
 use core::{
-    alloc::{Layout, AllocError},
+    alloc::Layout,
     ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
 };
+use std::{ffi::CString, io, sync::Arc};
+
+
+
+/// The bytes that every heap segment starts with.
+const MAGIC: [u8; 8] = *b"RATIONHP";
+
+/// Every chunk starts at, and has a size that's a multiple of, this many bytes.
+const CHUNK_ALIGN: usize = 16;
+/// The size of the header at the start of every chunk.
+const CHUNK_HEADER: usize = 2 * core::mem::size_of::<usize>();
+/// Chunks smaller than this aren't worth splitting off.
+const MIN_CHUNK: usize = CHUNK_HEADER + CHUNK_ALIGN;
+
+/// The number of names that can be published in a heap at once.
+const NAME_SLOTS: usize = 32;
+/// The longest name that can be published, in bytes.
+const NAME_LEN: usize = 32;
+
+/// The header at the start of every heap segment.
+///
+/// All "pointers" stored in the heap are offsets from the start of the segment. Since the header
+/// lives at offset zero, an offset of zero means "none".
+#[repr(C)]
+struct HeapHeader {
+    magic: [u8; 8],
+    /// The size of the whole segment, in bytes.
+    size: usize,
+    /// Guards the free list and the name table. Holds the ID of the process holding it, so that
+    /// it can be taken over if that process dies.
+    lock: AtomicU32,
+    /// The offset of the first free chunk. Free chunks are kept sorted by offset, so that
+    /// neighbours can be merged when they're freed.
+    free: usize,
+    /// An offset that users can publish for other processes to find.
+    root: AtomicUsize,
+    /// Offsets that users have published under a name.
+    names: [NameEntry; NAME_SLOTS],
+}
+
+/// A published name. The entry is empty if its offset is zero.
+#[repr(C)]
+struct NameEntry {
+    name: [u8; NAME_LEN],
+    offset: usize,
+}
+
+/// The start of a chunk of the heap.
+///
+/// For free chunks, `next` is the offset of the next free chunk. For allocated chunks, the word
+/// just before the user's pointer (which is `next` unless the allocation needed extra alignment)
+/// holds the offset of the chunk, so that it can be found again when it's freed.
+#[repr(C)]
+struct ChunkHeader {
+    size: usize,
+    next: usize,
+}
+
+/// The offset that the first chunk starts at.
+const fn heap_start() -> usize {
+    core::mem::size_of::<HeapHeader>().next_multiple_of(CHUNK_ALIGN)
+}
+
+/// A mapping of a heap segment.
+struct Heap {
+    ptr: *mut u8,
+    size: usize,
+    name: CString,
+    owner: bool,
+}
 
+// SAFETY: All access to the heap's metadata goes through its lock.
+unsafe impl Send for Heap {}
+unsafe impl Sync for Heap {}
 
+impl Drop for Heap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.size);
+            if self.owner {
+                libc::shm_unlink(self.name.as_ptr());
+            }
+        }
+    }
+}
 
+/// An allocator that allocates from a named shared memory heap.
+///
+/// Cloning it is cheap; clones share the same mapping, which is unmapped once the last clone is
+/// dropped. If this instance created the heap, the heap is also unlinked at that point.
+///
+/// The heap's metadata is guarded by a lock that's taken over if the process holding it dies,
+/// which is noticed by its process ID. So every process that shares a heap has to be in the same
+/// PID namespace: a holder in another one (like a different container sharing `/dev/shm`) looks
+/// dead, and has the lock taken from it while it's still using the heap.
+///
+/// # Example
+/// *In your "parent" process:*
+/// ```no_run
+/// # #![cfg_attr(feature = "nightly", feature(allocator_api))]
+/// use ration_allocator::{allocator_api2::vec::Vec, SharedAllocator};
+///
+/// let heap = SharedAllocator::create("/MY_HEAP", 1 << 20).unwrap();
+/// let mut numbers = Vec::with_capacity_in(3, heap.clone());
+/// numbers.extend([1_u32, 2, 3]);
+///
+/// // Tell other processes where to find the numbers.
+/// heap.set_root(heap.offset_of(numbers.as_ptr()));
+/// ```
+/// *In your "child" process:*
+/// ```no_run
+/// use ration_allocator::SharedAllocator;
+///
+/// let heap = SharedAllocator::open("/MY_HEAP").unwrap();
+/// let numbers = unsafe {
+///     std::slice::from_raw_parts(heap.ptr_at(heap.root().unwrap()) as *const u32, 3)
+/// };
+/// println!("MY_HEAP: {:?}", numbers); // [1, 2, 3]
+/// ```
 #[derive(Clone)]
-pub struct SharedAllocator;
+pub struct SharedAllocator {
+    heap: Arc<Heap>,
+}
+
+impl SharedAllocator {
+    /// Create a new heap of `size` bytes, identified by the given name.
+    ///
+    /// The name follows the rules of `shm_open`; it should start with a slash and contain no
+    /// other slashes.
+    pub fn create(name: &str, size: usize) -> io::Result<Self> {
+        let size = size.max(heap_start() + MIN_CHUNK).next_multiple_of(CHUNK_ALIGN);
+        let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        unsafe {
+            let fd = libc::shm_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0o600,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::ftruncate(fd, size as libc::off_t) != 0 {
+                let error = io::Error::last_os_error();
+                libc::close(fd);
+                libc::shm_unlink(name.as_ptr());
+                return Err(error);
+            }
+            let ptr = map(fd, size);
+            libc::close(fd);
+            let ptr = match ptr {
+                Ok(ptr) => ptr,
+                Err(error) => {
+                    libc::shm_unlink(name.as_ptr());
+                    return Err(error);
+                }
+            };
+
+            let heap = Heap { ptr, size, name, owner: true };
+
+            let first = heap_start();
+            (ptr.add(first) as *mut ChunkHeader).write(ChunkHeader {
+                size: size - first,
+                next: 0,
+            });
+            (ptr as *mut HeapHeader).write(HeapHeader {
+                magic: MAGIC,
+                size,
+                lock: AtomicU32::new(0),
+                free: first,
+                root: AtomicUsize::new(0),
+                names: [const { NameEntry { name: [0; NAME_LEN], offset: 0 } }; NAME_SLOTS],
+            });
+
+            Ok(Self { heap: Arc::new(heap) })
+        }
+    }
+
+    /// Open an existing heap identified by the given name.
+    pub fn open(name: &str) -> io::Result<Self> {
+        let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        unsafe {
+            let fd = libc::shm_open(name.as_ptr(), libc::O_RDWR, 0o600);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut stat: libc::stat = core::mem::zeroed();
+            if libc::fstat(fd, &mut stat) != 0 {
+                let error = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(error);
+            }
+            let size = stat.st_size as usize;
+            if size < heap_start() + MIN_CHUNK {
+                libc::close(fd);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "heap is too small"));
+            }
+            let ptr = map(fd, size);
+            libc::close(fd);
+            let ptr = ptr?;
+
+            let heap = Heap { ptr, size, name, owner: false };
+            let header = &*(ptr as *const HeapHeader);
+            if header.magic != MAGIC || header.size != size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not a ration heap"));
+            }
+
+            Ok(Self { heap: Arc::new(heap) })
+        }
+    }
+
+    /// Returns `true` if this instance created the heap (and will unlink it once dropped).
+    pub fn is_owner(&self) -> bool {
+        self.heap.owner
+    }
+
+    /// The size of the whole heap, in bytes.
+    pub fn size(&self) -> usize {
+        self.heap.size
+    }
+
+    /// Get the offset of the given pointer from the start of the heap.
+    ///
+    /// Unlike the pointer itself, the offset means the same thing in every process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pointer doesn't point into the heap.
+    pub fn offset_of<T: ?Sized>(&self, ptr: *const T) -> usize {
+        self.try_offset_of(ptr).expect("pointer does not point into the heap")
+    }
+
+    /// Get the offset of the given pointer from the start of the heap, if it points into it.
+    fn try_offset_of<T: ?Sized>(&self, ptr: *const T) -> Option<usize> {
+        let offset = (ptr as *const u8 as usize).wrapping_sub(self.heap.ptr as usize);
+        (offset < self.heap.size).then_some(offset)
+    }
+
+    /// Get a pointer to the given offset into the heap, in this process' mapping of it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the offset is outside of the heap.
+    pub fn ptr_at(&self, offset: usize) -> *mut u8 {
+        assert!(offset < self.heap.size, "offset is outside of the heap");
+        unsafe { self.heap.ptr.add(offset) }
+    }
+
+    /// Publish an offset for other processes to find with [`root`](SharedAllocator::root).
+    pub fn set_root(&self, offset: usize) {
+        self.header().root.store(offset, Ordering::Release);
+    }
+
+    /// Get the offset last published with [`set_root`](SharedAllocator::set_root), if there is
+    /// one.
+    pub fn root(&self) -> Option<usize> {
+        match self.header().root.load(Ordering::Acquire) {
+            0 => None,
+            offset => Some(offset),
+        }
+    }
+
+    /// Publish an offset under the given name, for other processes to find with
+    /// [`lookup`](SharedAllocator::lookup).
+    ///
+    /// Names are at most 32 bytes long, and a heap can hold at most 32 of them at once.
+    pub fn publish(&self, name: &str, offset: usize) -> io::Result<()> {
+        let key = name_key(name)
+            .filter(|_| !name.is_empty())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid name length"))?;
+        if offset == 0 || offset >= self.heap.size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "offset is outside of the heap"));
+        }
+
+        self.with_lock(|header| {
+            let names = unsafe { &mut (*header).names };
+            if names.iter().any(|entry| entry.offset != 0 && entry.name == key) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "name is already published"));
+            }
+            let entry = names
+                .iter_mut()
+                .find(|entry| entry.offset == 0)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::OutOfMemory, "name table is full"))?;
+            entry.name = key;
+            entry.offset = offset;
+
+            Ok(())
+        })
+    }
+
+    /// Get the offset published under the given name, if there is one.
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        let key = name_key(name)?;
+
+        self.with_lock(|header| {
+            let names = unsafe { &(*header).names };
+            names
+                .iter()
+                .find(|entry| entry.offset != 0 && entry.name == key)
+                .map(|entry| entry.offset)
+        })
+    }
+
+    /// Remove the given name, returning the offset that was published under it.
+    pub fn unpublish(&self, name: &str) -> Option<usize> {
+        let key = name_key(name)?;
+
+        self.with_lock(|header| {
+            let names = unsafe { &mut (*header).names };
+            let entry = names
+                .iter_mut()
+                .find(|entry| entry.offset != 0 && entry.name == key)?;
+
+            Some(std::mem::take(&mut entry.offset))
+        })
+    }
+
+    /// Remove every name that the given offset is published under.
+    pub(crate) fn unpublish_offset(&self, offset: usize) {
+        self.with_lock(|header| {
+            let names = unsafe { &mut (*header).names };
+            for entry in names.iter_mut().filter(|entry| entry.offset == offset) {
+                entry.offset = 0;
+            }
+        })
+    }
 
-unsafe impl core::alloc::Allocator for SharedAllocator {
+    fn header(&self) -> &HeapHeader {
+        unsafe { &*(self.heap.ptr as *const HeapHeader) }
+    }
+
+    fn chunk(&self, offset: usize) -> *mut ChunkHeader {
+        unsafe { self.heap.ptr.add(offset) as *mut ChunkHeader }
+    }
+
+    /// Run `f` while holding the heap's lock, which guards the free list and the name table.
+    ///
+    /// If a process died while holding the lock, it's taken over. Whatever that process was in
+    /// the middle of is left as it was.
+    fn with_lock<R>(&self, f: impl FnOnce(*mut HeapHeader) -> R) -> R {
+        let lock = &self.header().lock;
+        lock::acquire(lock);
+
+        let result = f(self.heap.ptr as *mut HeapHeader);
+
+        lock::release(lock);
+        result
+    }
+
+    /// Allocate a chunk that fits `layout`, returning the offset of the usable memory in it.
+    pub(crate) fn alloc_chunk(&self, layout: Layout) -> Option<usize> {
+        self.with_lock(|header| unsafe {
+            let free = &mut (*header).free;
+            // Walk the free list looking for the first chunk that fits.
+            let mut prev: Option<usize> = None;
+            let mut current = *free;
+            while current != 0 {
+                let chunk = self.chunk(current);
+                let size = (*chunk).size;
+                let next = (*chunk).next;
+
+                let start = self.heap.ptr as usize + current;
+                let user = (start + CHUNK_HEADER).next_multiple_of(layout.align());
+                let needed = (user + layout.size() - start)
+                    .next_multiple_of(CHUNK_ALIGN)
+                    .max(MIN_CHUNK);
+
+                if needed <= size {
+                    // Split off whatever's left over, if it's big enough to be useful.
+                    let (taken, replacement) = if size - needed >= MIN_CHUNK {
+                        let rest = current + needed;
+                        self.chunk(rest).write(ChunkHeader { size: size - needed, next });
+                        (needed, rest)
+                    } else {
+                        (size, next)
+                    };
+                    match prev {
+                        Some(prev) => (*self.chunk(prev)).next = replacement,
+                        None => *free = replacement,
+                    }
+
+                    (*chunk).size = taken;
+                    // Remember where the chunk starts, just before the user's pointer.
+                    ((user - core::mem::size_of::<usize>()) as *mut usize).write(current);
+
+                    return Some(user - self.heap.ptr as usize);
+                }
+
+                prev = Some(current);
+                current = next;
+            }
+
+            None
+        })
+    }
+
+    /// Free the chunk whose usable memory starts at the given offset.
+    pub(crate) fn free_chunk(&self, user: usize) {
+        self.with_lock(|header| unsafe {
+            let free = &mut (*header).free;
+            let offset = (self.heap.ptr.add(user - core::mem::size_of::<usize>()) as *const usize)
+                .read();
+            let size = (*self.chunk(offset)).size;
+
+            // Find the free chunks on either side of this one.
+            let mut prev: Option<usize> = None;
+            let mut next = *free;
+            while next != 0 && next < offset {
+                prev = Some(next);
+                next = (*self.chunk(next)).next;
+            }
+
+            // Merge with the next chunk, if they touch.
+            let chunk = self.chunk(offset);
+            if next != 0 && offset + size == next {
+                let next_chunk = self.chunk(next);
+                chunk.write(ChunkHeader {
+                    size: size + (*next_chunk).size,
+                    next: (*next_chunk).next,
+                });
+            } else {
+                chunk.write(ChunkHeader { size, next });
+            }
+
+            // Merge with the previous chunk, if they touch.
+            match prev {
+                Some(prev) => {
+                    let prev_chunk = self.chunk(prev);
+                    if prev + (*prev_chunk).size == offset {
+                        (*prev_chunk).size += (*chunk).size;
+                        (*prev_chunk).next = (*chunk).next;
+                    } else {
+                        (*prev_chunk).next = offset;
+                    }
+                }
+                None => *free = offset,
+            }
+        })
+    }
+}
+
+impl core::fmt::Debug for SharedAllocator {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SharedAllocator")
+            .field("name", &self.heap.name)
+            .field("addr", &self.heap.ptr)
+            .field("size", &self.heap.size)
+            .finish_non_exhaustive()
+    }
+}
+
+unsafe impl allocator_api2::alloc::Allocator for SharedAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        todo!()
+        if layout.size() == 0 {
+            let dangling = core::ptr::without_provenance_mut::<u8>(layout.align());
+            return Ok(NonNull::slice_from_raw_parts(
+                NonNull::new(dangling).ok_or(AllocError)?,
+                0,
+            ));
+        }
+
+        let offset = self.alloc_chunk(layout).ok_or(AllocError)?;
+        let ptr = NonNull::new(self.ptr_at(offset)).ok_or(AllocError)?;
+
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        todo!()
+        if layout.size() == 0 {
+            return;
+        }
+
+        // Never panic here; a pointer from some other allocator is just ignored.
+        let offset = self.try_offset_of(ptr.as_ptr());
+        debug_assert!(offset.is_some(), "pointer does not point into the heap");
+        if let Some(offset) = offset {
+            self.free_chunk(offset);
+        }
+    }
+}
+
+/// Pad a name out to the size of a name table entry.
+fn name_key(name: &str) -> Option<[u8; NAME_LEN]> {
+    if name.len() > NAME_LEN {
+        return None;
+    }
+    let mut key = [0; NAME_LEN];
+    key[..name.len()].copy_from_slice(name.as_bytes());
+
+    Some(key)
+}
+
+unsafe fn map(fd: libc::c_int, size: usize) -> io::Result<*mut u8> {
+    let ptr = unsafe {
+        libc::mmap(
+            core::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ptr as *mut u8)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use allocator_api2::{boxed::Box, vec::Vec};
+
+    #[test]
+    fn allocator_vec_across_mappings() {
+        let heap = SharedAllocator::create("/TEST_ALLOCATOR_VEC", 1 << 16).unwrap();
+        assert!(heap.is_owner());
+
+        let mut numbers: Vec<u64, _> = Vec::new_in(heap.clone());
+        numbers.extend(0..1000);
+        heap.set_root(heap.offset_of(numbers.as_ptr()));
+
+        let ref_heap = SharedAllocator::open("/TEST_ALLOCATOR_VEC").unwrap();
+        assert!(!ref_heap.is_owner());
+        let ref_numbers = unsafe {
+            let ptr = ref_heap.ptr_at(ref_heap.root().unwrap()) as *mut u64;
+            core::slice::from_raw_parts_mut(ptr, 1000)
+        };
+        assert!(ref_numbers.iter().copied().eq(0..1000));
+
+        ref_numbers[0] = 71;
+        assert_eq!(numbers[0], 71);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn allocator_std_collections() {
+        let heap = SharedAllocator::create("/TEST_ALLOCATOR_STD", 1 << 16).unwrap();
+
+        let mut numbers = std::vec::Vec::new_in(heap.clone());
+        numbers.extend([1_u8, 2, 3]);
+        let boxed = std::boxed::Box::new_in(4_u8, heap.clone());
+
+        let offset = heap.offset_of(numbers.as_ptr());
+        assert_eq!(unsafe { *heap.ptr_at(offset + 2) }, 3);
+        assert_eq!(*boxed, 4);
+    }
+
+    #[test]
+    fn allocator_box_alignment() {
+        #[repr(align(256))]
+        struct Aligned(u8);
+
+        let heap = SharedAllocator::create("/TEST_ALLOCATOR_BOX", 1 << 16).unwrap();
+
+        let small = Box::new_in(1_u8, heap.clone());
+        let aligned = Box::new_in(Aligned(2), heap.clone());
+        assert_eq!(&*aligned as *const Aligned as usize % 256, 0);
+        assert_eq!((*small, aligned.0), (1, 2));
+    }
+
+    #[test]
+    fn allocator_reuses_freed_memory() {
+        let heap = SharedAllocator::create("/TEST_ALLOCATOR_REUSE", 1 << 16).unwrap();
+
+        // Fill the heap with small allocations...
+        let mut boxes = Vec::new();
+        while let Ok(b) = Box::try_new_in([0_u8; 100], heap.clone()) {
+            boxes.push(b);
+        }
+        assert!(boxes.len() > 100);
+        assert!(Box::try_new_in([0_u8; 100], heap.clone()).is_err());
+
+        // ...free them in an awkward order, and make sure they merge back into one big chunk.
+        let mut odd = Vec::new();
+        for (i, b) in boxes.into_iter().enumerate() {
+            if i % 2 == 1 {
+                odd.push(b);
+            }
+        }
+        drop(odd);
+
+        let big: Box<[u8; 60_000], _> = Box::try_new_in([0; 60_000], heap.clone()).unwrap();
+        assert_eq!(big.len(), 60_000);
     }
 }
@@ -0,0 +1,140 @@
+//! Heap Lock
+//!
+//! The lock that guards a heap's metadata lives in the heap itself, so it has to work across
+//! processes, and survive one of them dying while holding it.
+//!
+//! The holder is recorded by its process ID, which only means something inside one PID namespace.
+//! Processes in different namespaces can't share a heap safely, since each sees the other's ID as
+//! dead.
+
+
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+
+
+/// The lock isn't held by anyone. Otherwise, the lock word holds the ID of the process holding it.
+const UNLOCKED: u32 = 0;
+/// Set in the lock word when somebody may be waiting for the lock.
+const CONTENDED: u32 = 1 << 31;
+/// The bits of the lock word that hold the ID of the process holding the lock.
+const HOLDER_MASK: u32 = !CONTENDED;
+
+/// How often waiters check whether the process holding the lock is still alive.
+const PROBE_INTERVAL: Duration = Duration::from_millis(50);
+
+
+
+/// Acquire the lock, waiting for whoever holds it to release it first.
+///
+/// If the process holding the lock died without releasing it, the lock is taken over.
+pub(crate) fn acquire(lock: &AtomicU32) {
+    let pid = std::process::id();
+
+    if lock.compare_exchange(UNLOCKED, pid, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+        return;
+    }
+
+    loop {
+        let current = lock.load(Ordering::Relaxed);
+
+        if current == UNLOCKED || !is_alive(current & HOLDER_MASK) {
+            // Either the lock was released, or its holder died without releasing it. Keep the
+            // lock marked as contended either way, since there might be other waiters.
+            if lock
+                .compare_exchange(current, pid | CONTENDED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+            continue;
+        }
+
+        // Mark the lock as contended so that whoever holds it wakes us up, and then wait for it
+        // to be released. Wake up every so often to make sure the holder is still alive.
+        if current & CONTENDED != 0
+            || lock
+                .compare_exchange(current, current | CONTENDED, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            wait(lock, current | CONTENDED, PROBE_INTERVAL);
+        }
+    }
+}
+
+/// Release the lock, waking up a waiter if there might be one.
+pub(crate) fn release(lock: &AtomicU32) {
+    if lock.swap(UNLOCKED, Ordering::Release) & CONTENDED != 0 {
+        wake_one(lock);
+    }
+}
+
+/// Returns `true` if the process with the given ID still exists.
+///
+/// IDs are recycled by the system, so this can return `true` for a dead process whose ID has
+/// since been handed to some unrelated process. It returns `false` for a live process in another
+/// PID namespace.
+fn is_alive(pid: u32) -> bool {
+    // Signal 0 doesn't deliver anything, it only checks that the target exists.
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return true;
+    }
+    // The target exists, we just aren't allowed to signal it.
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Block the current thread while `lock` holds `expected`, until it's woken by [`wake_one`] or
+/// until `timeout` has passed.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn wait(lock: &AtomicU32, expected: u32, timeout: Duration) {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as _,
+    };
+
+    // NOTE: The futex is deliberately *not* process-private.
+    unsafe {
+        libc::syscall(libc::SYS_futex, lock.as_ptr(), libc::FUTEX_WAIT, expected, &timeout);
+    }
+}
+
+/// Wake up to one thread (in any process) that is waiting on `lock`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn wake_one(lock: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, lock.as_ptr(), libc::FUTEX_WAKE, 1);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn wait(lock: &AtomicU32, expected: u32, timeout: Duration) {
+    if lock.load(Ordering::Relaxed) == expected {
+        std::thread::sleep(timeout.min(Duration::from_micros(100)));
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn wake_one(_lock: &AtomicU32) {}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_taken_over_from_dead_process() {
+        // Find the ID of a process that has certainly exited.
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        let lock = AtomicU32::new(pid);
+        acquire(&lock);
+        assert_eq!(lock.load(Ordering::Relaxed) & HOLDER_MASK, std::process::id());
+
+        release(&lock);
+        assert_eq!(lock.load(Ordering::Relaxed), UNLOCKED);
+    }
+}
@@ -0,0 +1,377 @@
+//! Shared Heap Vector
+
+
+
+use allocator_api2::alloc::AllocError;
+use core::{alloc::Layout, marker::PhantomData};
+use std::io;
+
+use crate::SharedAllocator;
+
+
+
+/// A growable array in a shared heap, which other processes can open and modify.
+///
+/// Unlike a `Vec<T, SharedAllocator>`, a `SharedVec` keeps its length, capacity, and the location
+/// of its elements inside the heap (as offsets). It only remembers where that information is, so it
+/// can be handed to another process (by [`offset`](SharedVec::offset) or by
+/// [`publish`](SharedVec::publish)ed name) and opened there, and every process sees the elements
+/// that the others push.
+///
+/// The vector that was created with [`new_in`](SharedVec::new_in) owns the elements, and frees
+/// them when dropped. Vectors opened from an offset or a name don't, so make sure the owner
+/// outlives them.
+///
+/// # Example
+/// *In your "parent" process:*
+/// ```no_run
+/// use ration_allocator::{SharedAllocator, SharedVec};
+///
+/// let heap = SharedAllocator::create("/MY_HEAP", 1 << 20).unwrap();
+/// let mut names: SharedVec<[u8; 8]> = SharedVec::new_in(&heap).unwrap();
+/// names.push(*b"ration\0\0");
+/// names.publish("names").unwrap();
+/// ```
+/// *In your "child" process:*
+/// ```no_run
+/// use ration_allocator::{SharedAllocator, SharedVec};
+///
+/// let heap = SharedAllocator::open("/MY_HEAP").unwrap();
+/// let names = unsafe { SharedVec::<[u8; 8]>::open(&heap, "names") }.unwrap();
+/// println!("MY_HEAP: {} names", names.len()); // 1 names
+/// ```
+///
+/// # Warning
+///
+/// Nothing synchronizes access to the vector. If more than one process modifies it at once, guard
+/// it with a lock.
+pub struct SharedVec<T> {
+    heap: SharedAllocator,
+    offset: usize,
+    owner: bool,
+    _marker: PhantomData<T>,
+}
+
+/// The part of a vector that lives in the heap.
+#[repr(C)]
+struct RawVec {
+    /// The offset of the elements, or zero if none have been allocated yet.
+    data: usize,
+    len: usize,
+    capacity: usize,
+    elem_size: usize,
+    elem_align: usize,
+}
+
+impl<T> SharedVec<T> {
+    /// Create a new, empty vector in the heap.
+    pub fn new_in(heap: &SharedAllocator) -> Result<Self, AllocError> {
+        let offset = heap.alloc_chunk(Layout::new::<RawVec>()).ok_or(AllocError)?;
+        unsafe {
+            (heap.ptr_at(offset) as *mut RawVec).write(RawVec {
+                data: 0,
+                len: 0,
+                capacity: 0,
+                elem_size: core::mem::size_of::<T>(),
+                elem_align: core::mem::align_of::<T>(),
+            });
+        }
+
+        Ok(Self {
+            heap: heap.clone(),
+            offset,
+            owner: true,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Create a new, empty vector in the heap, with room for at least `capacity` elements.
+    pub fn with_capacity_in(capacity: usize, heap: &SharedAllocator) -> Result<Self, AllocError> {
+        let mut vec = Self::new_in(heap)?;
+        vec.try_reserve(capacity)?;
+
+        Ok(vec)
+    }
+
+    /// Open the vector at the given offset into the heap.
+    ///
+    /// The returned vector doesn't own the elements.
+    ///
+    /// # Safety
+    ///
+    /// The offset must have come from a `SharedVec<T>` (of the same `T`) in the same heap, which
+    /// is still alive.
+    pub unsafe fn from_offset(heap: &SharedAllocator, offset: usize) -> Self {
+        Self {
+            heap: heap.clone(),
+            offset,
+            owner: false,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Open the vector published under the given name, if there is one.
+    ///
+    /// The returned vector doesn't own the elements. Returns `None` if the published vector's
+    /// elements have a different size or alignment than `T`.
+    ///
+    /// # Safety
+    ///
+    /// The name must have been published by a `SharedVec<T>` (of the same `T`).
+    pub unsafe fn open(heap: &SharedAllocator, name: &str) -> Option<Self> {
+        let offset = heap.lookup(name)?;
+        let vec = unsafe { Self::from_offset(heap, offset) };
+
+        let raw = vec.raw();
+        (raw.elem_size == core::mem::size_of::<T>() && raw.elem_align == core::mem::align_of::<T>())
+            .then_some(vec)
+    }
+
+    /// Publish this vector under the given name, so that other processes can
+    /// [`open`](SharedVec::open) it.
+    ///
+    /// The name is removed once the vector is freed.
+    pub fn publish(&self, name: &str) -> io::Result<()> {
+        self.heap.publish(name, self.offset)
+    }
+
+    /// The offset of the vector from the start of the heap.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns `true` if this vector owns the elements, and will free them when dropped.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    /// Give up ownership of the vector without freeing it, returning its offset.
+    ///
+    /// The vector can be reclaimed later with [`from_offset`](SharedVec::from_offset).
+    pub fn into_offset(mut self) -> usize {
+        self.owner = false;
+        self.offset
+    }
+
+    fn raw(&self) -> &RawVec {
+        unsafe { &*(self.heap.ptr_at(self.offset) as *const RawVec) }
+    }
+
+    fn raw_mut(&mut self) -> &mut RawVec {
+        unsafe { &mut *(self.heap.ptr_at(self.offset) as *mut RawVec) }
+    }
+
+    fn data(&self) -> *mut T {
+        match self.raw().data {
+            0 => core::ptr::NonNull::dangling().as_ptr(),
+            data => self.heap.ptr_at(data) as *mut T,
+        }
+    }
+
+    /// The number of elements in the vector.
+    pub fn len(&self) -> usize {
+        self.raw().len
+    }
+
+    /// Returns `true` if the vector has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of elements the vector can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.raw().capacity
+    }
+
+    /// Make sure there's room for at least `additional` more elements.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), AllocError> {
+        let RawVec { data, len, capacity, .. } = *self.raw();
+        let needed = len.checked_add(additional).ok_or(AllocError)?;
+        if needed <= capacity {
+            return Ok(());
+        }
+
+        let capacity = needed.max(capacity * 2).max(4);
+        let layout = Layout::array::<T>(capacity).map_err(|_| AllocError)?;
+        let new_data = self.heap.alloc_chunk(layout).ok_or(AllocError)?;
+        if data != 0 {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.data(),
+                    self.heap.ptr_at(new_data) as *mut T,
+                    len,
+                );
+            }
+            self.heap.free_chunk(data);
+        }
+
+        let raw = self.raw_mut();
+        raw.data = new_data;
+        raw.capacity = capacity;
+
+        Ok(())
+    }
+
+    /// Make sure there's room for at least `additional` more elements.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the heap doesn't have enough room left.
+    pub fn reserve(&mut self, additional: usize) {
+        self.try_reserve(additional).expect("shared heap is out of memory");
+    }
+
+    /// Append an element to the back of the vector.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the vector needs to grow, and the heap doesn't have enough room left.
+    pub fn push(&mut self, value: T) {
+        self.reserve(1);
+
+        let len = self.len();
+        unsafe { self.data().add(len).write(value) };
+        self.raw_mut().len = len + 1;
+    }
+
+    /// Remove the last element of the vector, and return it.
+    pub fn pop(&mut self) -> Option<T> {
+        let len = self.len().checked_sub(1)?;
+        self.raw_mut().len = len;
+
+        Some(unsafe { self.data().add(len).read() })
+    }
+
+    /// Drop every element after the first `len`.
+    pub fn truncate(&mut self, len: usize) {
+        let old_len = self.len();
+        if len >= old_len {
+            return;
+        }
+        self.raw_mut().len = len;
+
+        unsafe {
+            core::ptr::slice_from_raw_parts_mut(self.data().add(len), old_len - len)
+                .drop_in_place();
+        }
+    }
+
+    /// Drop every element.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Get the elements as a slice.
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.data(), self.len()) }
+    }
+
+    /// Get the elements as a mutable slice.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.data(), self.len()) }
+    }
+}
+
+impl<T> core::ops::Deref for SharedVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl<T> core::ops::DerefMut for SharedVec<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.as_mut_slice()
+    }
+}
+
+impl<T> Extend<T> for SharedVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for value in iter {
+            self.push(value);
+        }
+    }
+}
+
+impl<T> Drop for SharedVec<T> {
+    fn drop(&mut self) {
+        if !self.owner {
+            return;
+        }
+
+        self.heap.unpublish_offset(self.offset);
+        self.clear();
+        let data = self.raw().data;
+        if data != 0 {
+            self.heap.free_chunk(data);
+        }
+        self.heap.free_chunk(self.offset);
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for SharedVec<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharedvec_across_mappings() {
+        let heap = SharedAllocator::create("/TEST_SHAREDVEC", 1 << 16).unwrap();
+        let mut numbers: SharedVec<u64> = SharedVec::new_in(&heap).unwrap();
+        assert!(numbers.is_owner());
+        numbers.extend(0..100);
+        numbers.publish("numbers").unwrap();
+
+        let ref_heap = SharedAllocator::open("/TEST_SHAREDVEC").unwrap();
+        {
+            let mut ref_numbers = unsafe { SharedVec::<u64>::open(&ref_heap, "numbers") }.unwrap();
+            assert!(!ref_numbers.is_owner());
+            assert!(ref_numbers.iter().copied().eq(0..100));
+
+            // Growing the vector from here moves its elements, which the owner has to notice.
+            ref_numbers.extend(100..1000);
+            ref_numbers[0] = 71;
+        }
+
+        assert_eq!(numbers.len(), 1000);
+        assert!(numbers.capacity() >= 1000);
+        assert_eq!(numbers[0], 71);
+        assert!(numbers[1..].iter().copied().eq(1..1000));
+
+        assert_eq!(numbers.pop(), Some(999));
+        numbers.truncate(10);
+        assert_eq!(numbers.len(), 10);
+
+        assert!(unsafe { SharedVec::<u32>::open(&ref_heap, "numbers") }.is_none());
+    }
+
+    #[test]
+    fn sharedvec_drop_frees() {
+        let heap = SharedAllocator::create("/TEST_SHAREDVEC_DROP", 1 << 16).unwrap();
+
+        let counted = std::sync::Arc::new(5);
+        let mut values = SharedVec::with_capacity_in(2, &heap).unwrap();
+        values.push(std::sync::Arc::clone(&counted));
+        values.push(std::sync::Arc::clone(&counted));
+        values.publish("values").unwrap();
+        assert_eq!(std::sync::Arc::strong_count(&counted), 3);
+
+        drop(values);
+        assert_eq!(std::sync::Arc::strong_count(&counted), 1);
+        assert_eq!(heap.lookup("values"), None);
+
+        // All of the vector's memory went back to the heap.
+        let big = SharedVec::<u8>::with_capacity_in(60_000, &heap);
+        assert!(big.is_ok());
+    }
+}
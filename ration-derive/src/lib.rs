@@ -0,0 +1,358 @@
+//! Ration Derive Macros
+//!
+//! These are re-exported by `ration`, so use them from there.
+
+
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{quote, quote_spanned};
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Fields};
+
+
+
+/// The representations that give a type a layout every process agrees on.
+const STABLE_REPRS: &[&str] = &[
+    "C", "transparent", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64",
+    "i128", "isize",
+];
+
+/// The representations that set the type of an enum's discriminant.
+const INT_REPRS: &[&str] = &[
+    "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize",
+];
+
+/// Derive `ration::ShmSafe` for a `#[repr(C)]` struct or enum, as long as every one of its fields
+/// is `ShmSafe` too.
+#[proc_macro_derive(ShmSafe)]
+pub fn derive_shm_safe(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match shm_safe_impl(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn shm_safe_impl(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    check_repr(input)?;
+
+    let fields: Vec<_> = match &input.data {
+        Data::Struct(data) => data.fields.iter().collect(),
+        Data::Enum(data) => data.variants.iter().flat_map(|variant| &variant.fields).collect(),
+        Data::Union(data) => {
+            return Err(Error::new(
+                data.union_token.span(),
+                "`ShmSafe` can't be derived for unions",
+            ));
+        }
+    };
+    if let Data::Enum(data) = &input.data {
+        if data.variants.is_empty() {
+            return Err(Error::new(Span::call_site(), "`ShmSafe` can't be derived for empty enums"));
+        }
+    }
+
+    // Every field has to be `ShmSafe` itself. Checking that in a function, instead of bounding the
+    // impl on every field, lets fields point back at the type itself (through a `RelPtr`, say).
+    // Spanning each check to its field points any error at the field that isn't.
+    let checks = fields.iter().map(|field| {
+        let ty = &field.ty;
+        quote_spanned!(ty.span()=> assert_shm_safe::<#ty>();)
+    });
+
+    let name = &input.ident;
+    let bounds = param_bounds(input, quote!(::ration::ShmSafe));
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let predicates = where_clause.map(|clause| &clause.predicates);
+
+    Ok(quote! {
+        unsafe impl #impl_generics ::ration::ShmSafe for #name #ty_generics
+        where
+            #predicates
+            #(#bounds,)*
+        {}
+
+        const _: () = {
+            fn assert_shm_safe<T: ?::core::marker::Sized + ::ration::ShmSafe>() {}
+
+            #[allow(dead_code)]
+            fn assert_fields #impl_generics ()
+            where
+                #predicates
+                #(#bounds,)*
+            {
+                #(#checks)*
+            }
+        };
+    })
+}
+
+/// Derive `ration::TypeLayout` for a struct or enum, as long as every one of its fields is
+/// `TypeLayout` too.
+///
+/// Structs are described by the name, layout, and offset of every field (like
+/// `Position { x: f32 @ 0, y: f32 @ 4 }`), and enums by their `#[repr]` and the name,
+/// discriminant, and fields of every variant (like `repr(u8) Shape { Empty = 0, Circle(f32 @ 4) =
+/// 1 }`). A variant's field offsets are left out unless the enum's `#[repr]` fixes them.
+#[proc_macro_derive(TypeLayout)]
+pub fn derive_type_layout(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match type_layout_impl(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn type_layout_impl(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let name_str = name.to_string();
+
+    let describe = match &input.data {
+        Data::Struct(data) => {
+            let described = data.fields.iter().enumerate().map(|(index, field)| {
+                let ty = &field.ty;
+                let member = match &field.ident {
+                    Some(ident) => quote!(#ident),
+                    None => {
+                        let index = syn::Index::from(index);
+                        quote!(#index)
+                    }
+                };
+                let prefix = match &field.ident {
+                    Some(ident) => format!("{ident}: "),
+                    None => String::new(),
+                };
+
+                quote! {
+                    ::std::format!(
+                        "{}{} @ {}",
+                        #prefix,
+                        <#ty as ::ration::TypeLayout>::describe_layout(),
+                        ::core::mem::offset_of!(Self, #member),
+                    )
+                }
+            });
+            let described = quote! {
+                let fields: ::std::vec::Vec<::std::string::String> = ::std::vec![#(#described),*];
+            };
+            match &data.fields {
+                Fields::Named(_) => quote! {
+                    #described
+                    if fields.is_empty() {
+                        ::std::format!("{} {{}}", #name_str)
+                    } else {
+                        ::std::format!("{} {{ {} }}", #name_str, fields.join(", "))
+                    }
+                },
+                Fields::Unnamed(_) => quote! {
+                    #described
+                    ::std::format!("{}({})", #name_str, fields.join(", "))
+                },
+                Fields::Unit => quote!(::std::string::String::from(#name_str)),
+            }
+        }
+        Data::Enum(data) => {
+            let reprs = reprs(input)?;
+            let int_repr = reprs.iter().find(|repr| INT_REPRS.contains(&repr.as_str()));
+            let c_repr = reprs.iter().any(|repr| repr == "C");
+            let transparent = reprs.iter().any(|repr| repr == "transparent");
+            let repr_str = match reprs.is_empty() {
+                true => String::new(),
+                false => format!("repr({}) ", reprs.join(", ")),
+            };
+
+            // A fieldless copy of the enum, with the same discriminants, gives away their values
+            // and the size of the tag that holds them.
+            let tag_repr = match int_repr {
+                Some(int) => {
+                    let int = syn::Ident::new(int, Span::call_site());
+                    quote!(#[repr(#int)])
+                }
+                None if c_repr => quote!(#[repr(C)]),
+                None => quote!(),
+            };
+            let discriminant_ty = match int_repr {
+                Some(int) => syn::Ident::new(int, Span::call_site()),
+                None => syn::Ident::new("isize", Span::call_site()),
+            };
+            let tag_variants = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                match &variant.discriminant {
+                    Some((_, value)) => quote!(#ident = #value),
+                    None => quote!(#ident),
+                }
+            });
+
+            // Where the fields of every variant start, if the `#[repr]` says so. With just a
+            // primitive `#[repr]`, every variant is laid out like a `#[repr(C)]` struct that starts
+            // with the tag. With `#[repr(C)]`, the tag is followed by a union of `#[repr(C)]`
+            // structs, one per variant.
+            let fields_start = if transparent {
+                Some(quote!(0))
+            } else if c_repr {
+                let all_fields = data.variants.iter().flat_map(|variant| &variant.fields);
+                let aligns = all_fields.map(|field| {
+                    let ty = &field.ty;
+                    quote!(::core::mem::align_of::<#ty>())
+                });
+                Some(quote! {
+                    ::core::mem::size_of::<__RationTag>()
+                        .next_multiple_of([1 #(, #aligns)*].into_iter().max().unwrap_or(1))
+                })
+            } else if int_repr.is_some() {
+                Some(quote!(::core::mem::size_of::<__RationTag>()))
+            } else {
+                None
+            };
+
+            let variants = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let variant_name = ident.to_string();
+                let described = variant.fields.iter().map(|field| {
+                    let ty = &field.ty;
+                    let prefix = match &field.ident {
+                        Some(ident) => format!("{ident}: "),
+                        None => String::new(),
+                    };
+                    match &fields_start {
+                        Some(_) => quote! {{
+                            let offset = end.next_multiple_of(::core::mem::align_of::<#ty>());
+                            end = offset + ::core::mem::size_of::<#ty>();
+                            ::std::format!(
+                                "{}{} @ {}",
+                                #prefix,
+                                <#ty as ::ration::TypeLayout>::describe_layout(),
+                                offset,
+                            )
+                        }},
+                        None => quote! {
+                            ::std::format!(
+                                "{}{}",
+                                #prefix,
+                                <#ty as ::ration::TypeLayout>::describe_layout(),
+                            )
+                        },
+                    }
+                });
+                let start = fields_start.as_ref().map(|start| {
+                    quote! {
+                        #[allow(unused_mut, unused_variables, unused_assignments)]
+                        let mut end: usize = #start;
+                    }
+                });
+                let described = quote! {{
+                    #start
+                    <[::std::string::String]>::join(&[#(#described),*], ", ")
+                }};
+                let discriminant = match transparent {
+                    true => quote!(::std::string::String::new()),
+                    false => quote! {
+                        ::std::format!(" = {}", __RationTag::#ident as #discriminant_ty)
+                    },
+                };
+
+                match &variant.fields {
+                    Fields::Named(_) => quote! {
+                        ::std::format!("{} {{ {} }}{}", #variant_name, #described, #discriminant)
+                    },
+                    Fields::Unnamed(_) => quote! {
+                        ::std::format!("{}({}){}", #variant_name, #described, #discriminant)
+                    },
+                    Fields::Unit => quote! {
+                        ::std::format!("{}{}", #variant_name, #discriminant)
+                    },
+                }
+            });
+            quote! {
+                #[allow(dead_code, non_camel_case_types, clippy::enum_clike_unportable_variant)]
+                #tag_repr
+                enum __RationTag {
+                    #(#tag_variants,)*
+                }
+
+                let variants: ::std::vec::Vec<::std::string::String> =
+                    ::std::vec![#(#variants),*];
+                ::std::format!("{}{} {{ {} }}", #repr_str, #name_str, variants.join(", "))
+            }
+        }
+        Data::Union(data) => {
+            return Err(Error::new(
+                data.union_token.span(),
+                "`TypeLayout` can't be derived for unions",
+            ));
+        }
+    };
+
+    // Describing every field already requires it to be `TypeLayout`, so only the type parameters
+    // are bounded. Bounding every field would keep fields from pointing back at the type itself.
+    let bounds = param_bounds(input, quote!(::ration::TypeLayout));
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let predicates = where_clause.map(|clause| &clause.predicates);
+
+    Ok(quote! {
+        impl #impl_generics ::ration::TypeLayout for #name #ty_generics
+        where
+            #predicates
+            #(#bounds,)*
+        {
+            fn describe_layout() -> ::std::string::String {
+                ::ration::describe_tracked::<Self>(|| { #describe })
+            }
+        }
+    })
+}
+
+/// Bound every type parameter of the input by the given trait.
+fn param_bounds(
+    input: &DeriveInput,
+    bound: proc_macro2::TokenStream,
+) -> Vec<proc_macro2::TokenStream> {
+    input
+        .generics
+        .type_params()
+        .map(|param| {
+            let ident = &param.ident;
+            quote!(#ident: #bound)
+        })
+        .collect()
+}
+
+/// Make sure the type has a `#[repr]` with a layout that doesn't change between compilations.
+fn check_repr(input: &DeriveInput) -> syn::Result<()> {
+    let stable = reprs(input)?.iter().any(|repr| STABLE_REPRS.contains(&repr.as_str()));
+
+    if !stable {
+        return Err(Error::new(
+            input.ident.span(),
+            "`ShmSafe` can only be derived for `#[repr(C)]` types, so that every process agrees \
+             on their layout",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Every representation in the type's `#[repr]` attributes, like `["C", "u8"]` or `["align(8)"]`.
+fn reprs(input: &DeriveInput) -> syn::Result<Vec<String>> {
+    let mut reprs = Vec::new();
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            let name = meta.path.get_ident().map(ToString::to_string).unwrap_or_default();
+            if meta.input.peek(syn::token::Paren) {
+                // Keep the arguments of `align(N)` & `packed(N)`.
+                let content;
+                syn::parenthesized!(content in meta.input);
+                let arg = content.parse::<syn::LitInt>()?;
+                reprs.push(format!("{name}({})", arg.base10_digits()));
+            } else {
+                reprs.push(name);
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(reprs)
+}
@@ -2,14 +2,40 @@
 
 
 
-use std::{path::Path, sync::atomic::{AtomicIsize, AtomicU8, Ordering}};
-
-use crate::{Error, Result};
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    futex,
+    header::{Header, Lifetime, ObjectKind, Peer},
+    region::Region,
+    Backend,
+    Error,
+    Result,
+    ShmemBackend,
+    ShmSafe,
+};
 
 
 
 /// A shared array that can store `capacity` elements of type `T`.
 ///
+/// Arrays are single-producer, single-consumer ring buffers. Both the read cursor (`head`) and the
+/// write cursor (`tail`) live in shared memory, so any array instance can pick up where a
+/// previous one left off. For example, a consumer process can be restarted and will resume
+/// popping from exactly where the last consumer stopped.
+///
+/// Besides the non-blocking [`push`](Array::push) and [`pop`](Array::pop), arrays can also be
+/// waited on with [`push_blocking`](Array::push_blocking), [`pop_blocking`](Array::pop_blocking),
+/// and their timed variants. Waiting instances are parked on a futex in shared memory, so they
+/// don't use any CPU time until the other side wakes them.
+///
 /// # Example
 /// *In your "parent" process:*
 /// ```no_run
@@ -30,153 +56,284 @@ use crate::{Error, Result};
 /// }
 /// println!("MY_ARRAY: {}", s); // "ration"
 /// ```
-// TODO: Some sort of mutable access check.
-pub struct Array<T: Sized> {
-    shm: shared_memory::Shmem,
+///
+/// # Warning
+///
+/// Only one process (or thread) should push to an array at a time, and only one should pop from
+/// it at a time. Nothing stops you from doing otherwise, but elements may be lost or duplicated.
+pub struct Array<T: ShmSafe> {
+    region: Region,
 
-    empty_flag: *mut AtomicU8,
+    cursors: *mut Cursors,
     base: *mut Option<T>,
-    capacity: isize,
-    first: isize,
-    last: *mut AtomicIsize,
-    len: *mut AtomicIsize,
+    capacity: usize,
+}
+
+/// The read and write positions of an array, shared by every instance of it.
+///
+/// Both are running totals that are never wrapped, so `tail - head` is always the number of
+/// elements in the array, and `position % capacity` is the slot the position refers to.
+///
+/// The fields written by the producer and the fields written by the consumer are kept on separate
+/// cache lines, so that pushing and popping at the same time don't keep taking the same line away
+/// from each other (false sharing).
+#[repr(C)]
+struct Cursors {
+    producer: CachePadded<ProducerCursors>,
+    consumer: CachePadded<ConsumerCursors>,
+}
+
+#[repr(C)]
+struct ProducerCursors {
+    /// The number of elements that have been pushed.
+    tail: AtomicUsize,
+    /// Bumped after every push, so that consumers can wait on it.
+    pushed: AtomicU32,
+    /// The number of producers waiting on `popped`.
+    push_waiters: AtomicU32,
+}
+
+#[repr(C)]
+struct ConsumerCursors {
+    /// The number of elements that have been popped.
+    head: AtomicUsize,
+    /// Bumped after every pop, so that producers can wait on it.
+    popped: AtomicU32,
+    /// The number of consumers waiting on `pushed`.
+    pop_waiters: AtomicU32,
 }
 
-impl<T: Sized> Array<T> {
+/// Pads and aligns a value to 128 bytes, which is at least one cache line on every common
+/// platform, and covers the pairs of lines that some x86 processors prefetch together.
+#[repr(C, align(128))]
+struct CachePadded<T>(T);
+
+impl<T> std::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T: ShmSafe> Array<T> {
     /// Allocate an array to shared memory identified by the given path, with the given capacity.
+    ///
+    /// Fails with [`Error::InvalidCapacity`] if the capacity is zero, or too large to fit in memory.
+    ///
+    /// To allocate an array with a different [`Lifetime`], use [`ArrayOptions`].
     pub fn alloc(path: impl AsRef<Path>, capacity: usize) -> Result<Self> {
-        let block_size
-            = (std::mem::size_of::<Option<T>>() * capacity) // elements
-            + std::mem::size_of::<AtomicU8>()               // empty_flag
-            + (std::mem::size_of::<AtomicIsize>() * 2);     // last & len
-
-        let shm = match shared_memory::ShmemConf::new().flink(&path).size(block_size).create() {
-            Ok(shmem) => shmem,
-            Err(shared_memory::ShmemError::LinkExists) => {
-                return Err(Error::BlockAlreadyAllocated);
-            }
-            Err(e) => { return Err(Error::Shm(e)); }
-        };
+        ArrayOptions::new().alloc(path, capacity)
+    }
+
+    /// Open an array in shared memory identified by the given path.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        ArrayOptions::new().open(path)
+    }
 
+    /// Open an array in shared memory identified by the given path, waiting for it to be allocated
+    /// if it hasn't been yet.
+    ///
+    /// Waits forever if `timeout` is `None`, or fails with [`Error::Timeout`] once it has passed.
+    /// Fails with [`Error::Closed`] if the process that allocated the array exits before
+    /// initializing it.
+    pub fn open_wait(
+        path: impl AsRef<Path>,
+        timeout: impl Into<Option<Duration>>,
+    ) -> Result<Self> {
+        ArrayOptions::new().open_wait(path, timeout)
+    }
+
+    /// Create a new array with the given capacity in the given region.
+    ///
+    /// # Safety
+    ///
+    /// The region must be writable, at least [`block_size`](Array::block_size) bytes long, and
+    /// aligned for the header, the cursors, and `T`.
+    pub(crate) unsafe fn init(mut region: Region, capacity: usize, lifetime: Lifetime) -> Self {
         unsafe {
-            let empty_flag = shm.as_ptr() as *mut AtomicU8;
-            let len = empty_flag.offset(1) as *mut AtomicIsize;
-            let first = 1;
-            let last = len.offset(1);
-            let base = len.offset(2) as *mut Option<T>;
-            let capacity = capacity as isize;
-
-            (&*len).store(0, Ordering::SeqCst);
-            (&*last).store(first, Ordering::SeqCst);
+            let header = Header::new::<T>(ObjectKind::Array, capacity)
+                .with_layout::<T>()
+                .with_lifetime(lifetime);
+            (region.as_ptr() as *mut Header).write(header);
+            region.attach();
+
+            let cursors = region.as_ptr().add(Self::cursors_offset()) as *mut Cursors;
+            let base = region.as_ptr().add(Self::slots_offset()) as *mut Option<T>;
+
+            cursors.write(Cursors {
+                producer: CachePadded(ProducerCursors {
+                    tail: AtomicUsize::new(0),
+                    pushed: AtomicU32::new(0),
+                    push_waiters: AtomicU32::new(0),
+                }),
+                consumer: CachePadded(ConsumerCursors {
+                    head: AtomicUsize::new(0),
+                    popped: AtomicU32::new(0),
+                    pop_waiters: AtomicU32::new(0),
+                }),
+            });
             for i in 0..capacity {
-                base.offset(i).write(None);
+                base.add(i).write(None);
             }
+            (*(region.as_ptr() as *const Header)).mark_initialized();
 
-            Ok(Self {
-                shm,
-                empty_flag,
+            Self {
+                region,
+                cursors,
                 base,
                 capacity,
-                first,
-                last,
-                len,
-            })
+            }
         }
     }
 
-    /// Open an array in shared memory identified by the given path.
-    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let shm = shared_memory::ShmemConf::new()
-            .flink(path)
-            .open()
-            .map_err(|e| Error::Shm(e))?;
-
-        let metadata_size
-            = std::mem::size_of::<AtomicU8>()               // empty_flag
-            + (std::mem::size_of::<AtomicIsize>() * 2);     // last & len
+    /// Open the array that was created in the given region.
+    pub(crate) fn attach(mut region: Region) -> Result<Self> {
+        // Make sure the array was allocated by `ration`, and for this type.
+        let header = unsafe {
+            Header::validate_layout::<T>(region.as_ptr(), region.len(), ObjectKind::Array)?
+        };
+        let capacity = header.capacity();
+        if capacity == 0 {
+            return Err(Error::InvalidCapacity { capacity });
+        }
+        let size = Self::block_size(capacity).ok_or(Error::InvalidCapacity { capacity })?;
 
-        let array_size = shm.len() - metadata_size;
-        let slot_size = std::mem::size_of::<Option<T>>();
-        let capacity = array_size / slot_size;
+        if region.len() < size {
+            return Err(Error::InvalidBlockSize {
+                expected: size,
+                found: region.len(),
+            });
+        }
+        region.attach();
 
         unsafe {
-            let empty_flag = shm.as_ptr() as *mut AtomicU8;
-            let len = empty_flag.offset(1) as *mut AtomicIsize;
-            let first = 1;
-            let last = len.offset(1);
-            let base = len.offset(2) as *mut Option<T>;
-            let capacity = capacity as isize;
+            let cursors = region.as_ptr().add(Self::cursors_offset()) as *mut Cursors;
+            let base = region.as_ptr().add(Self::slots_offset()) as *mut Option<T>;
 
             Ok(Self {
-                shm,
-                empty_flag,
+                region,
+                cursors,
                 base,
                 capacity,
-                first,
-                last,
-                len,
             })
         }
     }
 
+    // The segment is laid out as:
+    //
+    // | Header | (padding) | Cursors | (padding) | Option<T> * capacity |
+    //
+    // where the padding keeps the cursors and every slot aligned. Mappings are page-aligned, so
+    // offsets that are aligned are also aligned in memory.
+
+    fn cursors_offset() -> usize {
+        std::mem::size_of::<Header>().next_multiple_of(std::mem::align_of::<Cursors>())
+    }
+
+    fn slots_offset() -> usize {
+        (Self::cursors_offset() + std::mem::size_of::<Cursors>())
+            .next_multiple_of(std::mem::align_of::<Option<T>>())
+    }
+
+    /// The number of bytes needed to hold an array of this type with the given capacity, or `None`
+    /// if that many slots wouldn't fit in memory.
+    pub(crate) fn block_size(capacity: usize) -> Option<usize> {
+        std::mem::size_of::<Option<T>>().checked_mul(capacity)?.checked_add(Self::slots_offset())
+    }
+
+    /// The alignment that an array of this type has to start at.
+    pub(crate) fn block_align() -> usize {
+        std::mem::align_of::<Cursors>().max(std::mem::align_of::<Option<T>>())
+    }
+
+    fn cursors(&self) -> &Cursors {
+        unsafe { &*self.cursors }
+    }
+
+    /// Returns the number of elements the array can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of elements in the array.
+    pub fn len(&self) -> usize {
+        let cursors = self.cursors();
+        let tail = cursors.producer.tail.load(Ordering::Acquire);
+        let head = cursors.consumer.head.load(Ordering::Acquire);
+
+        tail.wrapping_sub(head)
+    }
+
     /// Returns `true` if the array contains no elements.
     pub fn is_empty(&self) -> bool {
-        unsafe { &*self.empty_flag }.load(Ordering::Relaxed) == 0
+        self.len() == 0
     }
 
     /// Returns the number of array slots that are empty.
     pub fn slots_remaining(&self) -> usize {
-        (self.capacity - unsafe { &*self.len }.load(Ordering::SeqCst)).unsigned_abs()
+        self.capacity.saturating_sub(self.len())
     }
 
     /// Push an element to the back of the array.
+    ///
+    /// Returns `false` (dropping the element) if the array is full.
     pub fn push(&mut self, element: T) -> bool {
         // Ensure the internal ring buffer isn't full.
-        let count = unsafe { &*self.len }.fetch_add(1, Ordering::SeqCst);
-        if count >= self.capacity {
+        if self.slots_remaining() == 0 {
             // The buffer is full; give up.
-            unsafe { &*self.len }.fetch_sub(1, Ordering::SeqCst);
             return false;
         }
 
         self.push_unchecked(element);
 
-        // Signal.
-        unsafe { &mut *self.empty_flag }.store(1, Ordering::Relaxed);
-
         true
     }
 
     /// Push an iterator of elements to the back of the array.
+    ///
+    /// Elements that don't fit into the array are not taken from the iterator.
     pub fn push_many(&mut self, elements: impl IntoIterator<Item = T>) {
         let slots_remaining = self.slots_remaining();
+        let tail = self.cursors().producer.tail.load(Ordering::Relaxed);
+
+        let mut count = 0;
         for element in elements.into_iter().take(slots_remaining) {
-            let _ = unsafe { &*self.len }.fetch_add(1, Ordering::SeqCst);
-            self.push_unchecked(element);
+            unsafe {
+                self.slot(tail.wrapping_add(count)).write(Some(element));
+            }
+            count += 1;
         }
 
-        // Signal.
-        unsafe { &mut *self.empty_flag }.store(1, Ordering::Relaxed);
+        // Publish all of the new elements at once.
+        self.cursors().producer.tail.store(tail.wrapping_add(count), Ordering::Release);
+        self.notify_pushed();
     }
 
-    /// Push an element to the back of the array without checking for overflows, raising the empty
-    /// flag, or checking access.
+    /// Push an element to the back of the array without checking for overflows or checking
+    /// access.
+    ///
+    /// The array must not be full. Pushing to a full array is a logic error: the oldest element
+    /// is overwritten without the consumer's cursor moving past it, which leaves the array unable
+    /// to pop anything ever again. This is only checked in debug builds.
     pub fn push_unchecked(&mut self, element: T) {
-        // Get the next available index, wrapping if need be.
-        let index = unsafe { &*self.last }.fetch_add(1, Ordering::SeqCst) % self.capacity;
-        if index == 0 {
-            // Just mod on overflow; the buffer is circular.
-            unsafe { &*self.last }.fetch_sub(self.capacity, Ordering::SeqCst);
-        }
+        debug_assert!(self.len() < self.capacity, "pushed to a full array");
+        let tail = self.cursors().producer.tail.load(Ordering::Relaxed);
 
         // Write the element into the shared memory.
         unsafe {
-            self.base.offset(index).write(Some(element));
+            self.slot(tail).write(Some(element));
         }
+
+        // Publish the element to the consumer.
+        self.cursors().producer.tail.store(tail.wrapping_add(1), Ordering::Release);
+        self.notify_pushed();
     }
 
-    /// Push an iterator of elements to the back of the array without checking for overflows,
-    /// raising the empty flag, or checking access.
+    /// Push an iterator of elements to the back of the array without checking for overflows or
+    /// checking access.
+    ///
+    /// Like [`push_unchecked`](Array::push_unchecked), the array must have room for every element.
     pub fn push_many_unchecked(&mut self, elements: impl Iterator<Item = T>) {
         for elem in elements {
             self.push_unchecked(elem)
@@ -189,43 +346,213 @@ impl<T: Sized> Array<T> {
             return None;
         }
 
-        let result = unsafe { &mut *self.base.offset(self.first) }.take();
-        if !result.is_none() {
-            self.first = (self.first + 1) % self.capacity;
-            unsafe { &*self.len }.fetch_sub(1, Ordering::SeqCst);
-        } else {
-            // Signal.
-            unsafe { &mut *self.empty_flag }.store(0, Ordering::Relaxed);
+        self.pop_unchecked()
+    }
+
+    /// Pop an element from the front of the array without checking whether the producer has
+    /// finished writing it, or checking access.
+    pub fn pop_unchecked(&mut self) -> Option<T> {
+        let head = self.cursors().consumer.head.load(Ordering::Relaxed);
+
+        let result = unsafe { &mut *self.slot(head) }.take();
+        if result.is_some() {
+            // Hand the slot back to the producer.
+            self.cursors().consumer.head.store(head.wrapping_add(1), Ordering::Release);
+            self.notify_popped();
         }
 
         result
     }
 
-    /// Pop an element from the front of the array without checking for overflows, raising the
-    /// empty flag, or checking access.
-    pub fn pop_unchecked(&mut self) -> Option<T> {
-        let result = unsafe { &mut *self.base.offset(self.first) }.take();
-        if !result.is_none() {
-            self.first = (self.first + 1) % self.capacity;
-            unsafe { &*self.len }.fetch_sub(1, Ordering::SeqCst);
+    /// Push an element to the back of the array, waiting for a slot to open up if it's full.
+    pub fn push_blocking(&mut self, element: T) {
+        let pushed = self.push_timeout(element, None);
+        debug_assert!(pushed);
+    }
+
+    /// Push an element to the back of the array, waiting up to `timeout` for a slot to open up if
+    /// it's full.
+    ///
+    /// Returns `false` (dropping the element) if the array was still full after `timeout`.
+    pub fn push_timeout(&mut self, element: T, timeout: impl Into<Option<Duration>>) -> bool {
+        let deadline = timeout.into().and_then(|timeout| Instant::now().checked_add(timeout));
+        let cursors = unsafe { &*self.cursors };
+
+        let popped = &cursors.consumer.popped;
+        let has_room = || self.slots_remaining() > 0;
+        if !Self::wait_until(popped, &cursors.producer.push_waiters, deadline, has_room) {
+            return false;
         }
 
-        result
+        self.push_unchecked(element);
+
+        true
+    }
+
+    /// Pop an element from the front of the array, waiting for one to be pushed if it's empty.
+    pub fn pop_blocking(&mut self) -> T {
+        loop {
+            if let Some(element) = self.pop_timeout(None) {
+                return element;
+            }
+        }
+    }
+
+    /// Pop an element from the front of the array, waiting up to `timeout` for one to be pushed if
+    /// it's empty.
+    pub fn pop_timeout(&mut self, timeout: impl Into<Option<Duration>>) -> Option<T> {
+        let deadline = timeout.into().and_then(|timeout| Instant::now().checked_add(timeout));
+        let cursors = unsafe { &*self.cursors };
+
+        let pushed = &cursors.producer.pushed;
+        let has_elements = || !self.is_empty();
+        if !Self::wait_until(pushed, &cursors.consumer.pop_waiters, deadline, has_elements) {
+            return None;
+        }
+
+        self.pop_unchecked()
+    }
+
+    /// Park on `futex` until `ready` returns `true`, or until the deadline has passed.
+    ///
+    /// Returns the final result of `ready`.
+    fn wait_until(
+        futex: &AtomicU32,
+        waiters: &AtomicU32,
+        deadline: Option<Instant>,
+        ready: impl Fn() -> bool,
+    ) -> bool {
+        loop {
+            // Register as a waiter *before* checking, so that the other side either sees us
+            // waiting or we see its change.
+            waiters.fetch_add(1, Ordering::SeqCst);
+            let seen = futex.load(Ordering::SeqCst);
+            if ready() {
+                waiters.fetch_sub(1, Ordering::SeqCst);
+                return true;
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => Some(timeout),
+                    _ => {
+                        waiters.fetch_sub(1, Ordering::SeqCst);
+                        return false;
+                    }
+                },
+                None => None,
+            };
+
+            futex::wait(futex, seen, timeout);
+            waiters.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn notify_pushed(&self) {
+        let cursors = self.cursors();
+        cursors.producer.pushed.fetch_add(1, Ordering::SeqCst);
+        if cursors.consumer.pop_waiters.load(Ordering::SeqCst) > 0 {
+            futex::wake_all(&cursors.producer.pushed);
+        }
+    }
+
+    fn notify_popped(&self) {
+        let cursors = self.cursors();
+        cursors.consumer.popped.fetch_add(1, Ordering::SeqCst);
+        if cursors.producer.push_waiters.load(Ordering::SeqCst) > 0 {
+            futex::wake_all(&cursors.consumer.popped);
+        }
+    }
+
+    /// Get a pointer to the slot that the given cursor position refers to.
+    fn slot(&self, position: usize) -> *mut Option<T> {
+        unsafe { self.base.add(position % self.capacity) }
     }
 }
 
-impl<T> Array<T> {
+impl<T: ShmSafe> Array<T> {
     /// Returns `true` if the underlying shared memory mapping is owned by this array instance.
     pub fn is_owner(&self) -> bool {
-        self.shm.is_owner()
+        self.region.is_owner()
+    }
+
+    /// Make this instance the array's owner, or stop it from being one.
+    ///
+    /// With [`Lifetime::Owner`], the array is unlinked when its owner is dropped, so this can be
+    /// used to hand ownership over to another instance once this one has been
+    /// [`disown`](Array::disown)ed.
+    pub fn set_owner(&mut self, owner: bool) {
+        self.region.set_owner(owner);
+    }
+
+    /// Stop this instance from being the array's owner, so that dropping it doesn't unlink the
+    /// array.
+    pub fn disown(&mut self) {
+        self.set_owner(false);
+    }
+
+    /// The instances (in any process, including this one) that have the array open.
+    ///
+    /// Only the first [`MAX_PEERS`](crate::MAX_PEERS) instances to open the array are listed, not
+    /// counting ones whose process has exited.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.region.header().peers()
+    }
+
+    /// Returns `true` if the process that owns the array is still running.
+    ///
+    /// This is `false` once the owner has been dropped (or [`disown`](Array::disown)ed without
+    /// another instance taking over), and if its process died without dropping it.
+    pub fn owner_alive(&self) -> bool {
+        self.region.header().owner_alive()
+    }
+
+    /// Returns `true` if every other process that opened the array has exited without dropping
+    /// it, or if no other process has it open at all.
+    ///
+    /// Supervisors can use this to tell when a segment can be reclaimed or recreated.
+    pub fn is_abandoned(&self) -> bool {
+        self.region.header().is_abandoned()
+    }
+
+    /// Record that this instance is still alive, updating its
+    /// [`last_heartbeat`](Peer::last_heartbeat).
+    ///
+    /// Call this regularly to let other processes tell a hung process apart from one that's
+    /// still working.
+    pub fn heartbeat(&self) {
+        self.region.heartbeat();
+    }
+
+    /// When the array's shared memory is unlinked.
+    pub fn lifetime(&self) -> Lifetime {
+        self.region.header().lifetime()
+    }
+
+    /// Unlink the array's shared memory (and the path linked to it), no matter what its lifetime
+    /// is.
+    ///
+    /// Instances that already have the array open keep working, but nothing can open it anymore.
+    pub fn unlink(mut self) {
+        self.region.unlink_on_drop();
+    }
+}
+
+impl<T: ShmSafe> crate::Mapping for Array<T> {
+    fn base_ptr(&self) -> *mut u8 {
+        self.region.mapping_ptr()
+    }
+
+    fn mapped_len(&self) -> usize {
+        self.region.mapping_len()
     }
 }
 
-impl<T: std::fmt::Debug> std::fmt::Debug for Array<T> {
+impl<T: ShmSafe + std::fmt::Debug> std::fmt::Debug for Array<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Array")
             .field("capacity", &self.capacity)
-            .field("len", &self.len)
+            .field("len", &self.len())
             // .field("elements", self.iter().collect())
             .finish_non_exhaustive()
     }
@@ -233,35 +560,111 @@ impl<T: std::fmt::Debug> std::fmt::Debug for Array<T> {
 
 
 
+/// Options for allocating (and opening) an [`Array`], like when its shared memory is unlinked, and
+/// which [`Backend`] it lives in.
+///
+/// # Example
+/// ```no_run
+/// use ration::{Array, ArrayOptions, Lifetime};
+///
+/// // Leave the array (and anything still in it) behind for the next process to pick up.
+/// let array: Array<u32> = ArrayOptions::new()
+///     .lifetime(Lifetime::Persist)
+///     .alloc("/tmp/MY_ARRAY", 64)
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ArrayOptions {
+    lifetime: Lifetime,
+    backend: Arc<dyn Backend>,
+}
+
+impl Default for ArrayOptions {
+    fn default() -> Self {
+        Self {
+            lifetime: Lifetime::default(),
+            backend: Arc::new(ShmemBackend),
+        }
+    }
+}
+
+impl ArrayOptions {
+    /// The default options, which are what [`Array::alloc`] uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set when the array's shared memory is unlinked. Defaults to [`Lifetime::Owner`].
+    pub fn lifetime(mut self, lifetime: Lifetime) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Set the backend that the array's shared memory is created (or opened) with. Defaults to
+    /// [`ShmemBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Allocate an array to shared memory identified by the given path, with the given capacity.
+    /// See [`Array::alloc`].
+    pub fn alloc<T: ShmSafe>(&self, path: impl AsRef<Path>, capacity: usize) -> Result<Array<T>> {
+        if capacity == 0 {
+            return Err(Error::InvalidCapacity { capacity });
+        }
+        let size = Array::<T>::block_size(capacity).ok_or(Error::InvalidCapacity { capacity })?;
+        let memory = self.backend.create(path.as_ref(), size)?;
+
+        Ok(unsafe { Array::init(Region::whole(memory, true), capacity, self.lifetime) })
+    }
+
+    /// Open an array in shared memory identified by the given path. See [`Array::open`].
+    pub fn open<T: ShmSafe>(&self, path: impl AsRef<Path>) -> Result<Array<T>> {
+        Array::attach(Region::whole(self.backend.open(path.as_ref())?, false))
+    }
+
+    /// Open an array in shared memory identified by the given path, waiting for it to be allocated
+    /// if it hasn't been yet. See [`Array::open_wait`].
+    pub fn open_wait<T: ShmSafe>(
+        &self,
+        path: impl AsRef<Path>,
+        timeout: impl Into<Option<Duration>>,
+    ) -> Result<Array<T>> {
+        let memory = crate::open_wait(&*self.backend, path.as_ref(), timeout.into())?;
+
+        Array::attach(Region::whole(memory, false))
+    }
+}
+
+
+
 /// # Warning
 ///
 /// This can be wildly unsafe if the array is being mutated while you are iterating over its
 /// elements. **Use at your own risk.**
-pub struct ArrayIter<'a, T> {
+pub struct ArrayIter<'a, T: ShmSafe> {
     array: &'a Array<T>,
-    index: isize,
-    capacity: isize,
-    // FIXME: There is probably a better way to stop the iteration than counting up to length.
-    count: isize,
-    len: isize,
+    index: usize,
+    count: usize,
+    len: usize,
 }
 
-impl<'a, T> Iterator for ArrayIter<'a, T> {
+impl<'a, T: ShmSafe> Iterator for ArrayIter<'a, T> {
     type Item = &'a T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let elem = unsafe { &*self.array.base.offset(self.index) }.as_ref();
-        if elem.is_some() {
-            self.index = (self.index + 1) % self.capacity;
-            self.count += 1;
-        }
-        if self.count > self.len {
-            // NOTE: This is a ring buffer, so the iterator will continue indefinitely if the
-            //       array is full without this check.
-            None
-        } else {
-            elem
+        // NOTE: This is a ring buffer, so the iterator would continue indefinitely if the array
+        //       is full without this check.
+        if self.count >= self.len {
+            return None;
         }
+
+        let elem = unsafe { &*self.array.slot(self.index) }.as_ref();
+        self.index = self.index.wrapping_add(1);
+        self.count += 1;
+
+        elem
     }
 }
 
@@ -269,36 +672,33 @@ impl<'a, T> Iterator for ArrayIter<'a, T> {
 ///
 /// This can be wildly unsafe if the array is being mutated while you are iterating over its
 /// elements. **Use at your own risk.**
-pub struct ArrayIterMut<'a, T> {
+pub struct ArrayIterMut<'a, T: ShmSafe> {
     array: &'a mut Array<T>,
-    index: isize,
-    capacity: isize,
-    // FIXME: There is probably a better way to stop the iteration than counting up to length.
-    count: isize,
-    len: isize,
+    index: usize,
+    count: usize,
+    len: usize,
 }
 
-impl<'a, T> Iterator for ArrayIterMut<'a, T> {
+impl<'a, T: ShmSafe> Iterator for ArrayIterMut<'a, T> {
     type Item = &'a mut T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let elem = unsafe { &mut *self.array.base.offset(self.index) }.as_mut();
-        if elem.is_some() {
-            self.index = (self.index + 1) % self.capacity;
-            self.count += 1;
-        }
-        if self.count > self.len {
-            // NOTE: This is a ring buffer, so the iterator will continue indefinitely if the
-            //       array is full without this check.
-            None
-        } else {
-            elem
+        // NOTE: This is a ring buffer, so the iterator would continue indefinitely if the array
+        //       is full without this check.
+        if self.count >= self.len {
+            return None;
         }
+
+        let elem = unsafe { &mut *self.array.slot(self.index) }.as_mut();
+        self.index = self.index.wrapping_add(1);
+        self.count += 1;
+
+        elem
     }
 }
 
 // Iteration methods.
-impl<T: Sized> Array<T> {
+impl<T: ShmSafe> Array<T> {
     /// Iterate over this array's elements.
     ///
     /// # Warning
@@ -308,10 +708,9 @@ impl<T: Sized> Array<T> {
     pub fn iter(&self) -> ArrayIter<'_, T> {
         ArrayIter {
             array: self,
-            index: self.first,
-            capacity: self.capacity,
+            index: self.cursors().consumer.head.load(Ordering::Acquire),
             count: 0,
-            len: unsafe { &*self.len }.load(Ordering::Relaxed),
+            len: self.len(),
         }
     }
 
@@ -322,14 +721,12 @@ impl<T: Sized> Array<T> {
     /// This can be wildly unsafe if the array is being mutated while you are iterating over its
     /// elements. **Use at your own risk.**
     pub fn iter_mut(&mut self) -> ArrayIterMut<'_, T> {
-        let index = self.first.clone();
-        let capacity = self.capacity.clone();
-        let len = unsafe { &*self.len }.load(Ordering::Relaxed).clone();
+        let index = self.cursors().consumer.head.load(Ordering::Acquire);
+        let len = self.len();
 
         ArrayIterMut {
             array: self,
             index,
-            capacity,
             count: 0,
             len,
         }
@@ -341,10 +738,16 @@ impl<T: Sized> Array<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{HeapBackend, TypeLayout};
+
+    /// Arrays in this process' heap, so that tests don't leave anything behind.
+    fn heap() -> ArrayOptions {
+        ArrayOptions::new().backend(HeapBackend)
+    }
 
     #[test]
     fn array_test_1() {
-        let mut array_1: Array<char> = Array::alloc("/tmp/TEST_ARRAY_1", 16).unwrap();
+        let mut array_1: Array<char> = heap().alloc("TEST_ARRAY_1", 16).unwrap();
         assert!(array_1.is_owner());
         assert!(array_1.is_empty());
 
@@ -356,7 +759,7 @@ mod tests {
         assert_eq!(array_1.slots_remaining(), 4);
 
         {
-            let mut ref_array_1: Array<char> = Array::open("/tmp/TEST_ARRAY_1").unwrap();
+            let mut ref_array_1: Array<char> = heap().open("TEST_ARRAY_1").unwrap();
             assert!(!ref_array_1.is_owner());
             assert!(!ref_array_1.is_empty());
             assert_eq!(array_1.capacity, ref_array_1.capacity);
@@ -373,9 +776,21 @@ mod tests {
         assert!(array_1.is_empty());
     }
 
+    #[test]
+    fn array_rejects_other_types() {
+        let _array: Array<u16> = heap().alloc("TEST_ARRAY_OTHERTYPES", 8).unwrap();
+
+        // Same size, different type.
+        assert!(matches!(
+            heap().open::<i16>("TEST_ARRAY_OTHERTYPES"),
+            Err(Error::LayoutMismatch { .. }),
+        ));
+        assert_eq!(heap().open::<u16>("TEST_ARRAY_OTHERTYPES").unwrap().capacity, 8);
+    }
+
     #[test]
     fn array_push_overflow() {
-        let mut array: Array<u8> = Array::alloc("/tmp/TEST_ARRAY_OVERFLOW", 8).unwrap();
+        let mut array: Array<u8> = heap().alloc("TEST_ARRAY_OVERFLOW", 8).unwrap();
 
         let mut stopped_at = 0;
         for i in 0..16 {
@@ -389,9 +804,45 @@ mod tests {
         assert_eq!(array.slots_remaining(), 0);
     }
 
+    #[test]
+    fn array_capacity_overflow() {
+        assert!(matches!(
+            heap().alloc::<u64>("TEST_ARRAY_CAPACITYOVERFLOW", usize::MAX / 8),
+            Err(Error::InvalidCapacity { capacity }) if capacity == usize::MAX / 8,
+        ));
+        assert!(heap().open::<u64>("TEST_ARRAY_CAPACITYOVERFLOW").is_err());
+    }
+
+    #[test]
+    fn array_capacity_zero() {
+        assert!(matches!(
+            heap().alloc::<u8>("TEST_ARRAY_CAPACITYZERO", 0),
+            Err(Error::InvalidCapacity { capacity: 0 }),
+        ));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn array_push_unchecked_full() {
+        let mut array: Array<u8> = heap().alloc("TEST_ARRAY_PUSHUNCHECKEDFULL", 2).unwrap();
+        array.push_unchecked(1);
+        array.push_unchecked(2);
+
+        // Pushing to a full array is caught before anything is overwritten.
+        let pushed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            array.push_unchecked(3);
+        }));
+        assert!(pushed.is_err());
+        assert_eq!(array.len(), 2);
+        assert_eq!(array.pop(), Some(1));
+        assert_eq!(array.pop(), Some(2));
+        assert_eq!(array.pop(), None);
+        assert!(array.is_empty());
+    }
+
     #[test]
     fn array_slots_update_correctly() {
-        let mut array: Array<u8> = Array::alloc("/tmp/TEST_ARRAY_SLOTSUPDATE", 8).unwrap();
+        let mut array: Array<u8> = heap().alloc("TEST_ARRAY_SLOTSUPDATE", 8).unwrap();
 
         for i in 0..9 {
             if !array.push(i) {
@@ -414,7 +865,7 @@ mod tests {
 
     #[test]
     fn array_traverse_full() {
-        let mut array = Array::alloc("/tmp/TEST_ARRAY_ITERFULL", 16).unwrap();
+        let mut array = heap().alloc("TEST_ARRAY_ITERFULL", 16).unwrap();
         // 16th item is 'j'.
         array.push_many("This is a test just to see if the array iterates correctly.".chars());
 
@@ -432,7 +883,7 @@ mod tests {
 
     #[test]
     fn array_traverse_partial() {
-        let mut array = Array::alloc("/tmp/TEST_ARRAY_ITERPARTIAL", 16).unwrap();
+        let mut array = heap().alloc("TEST_ARRAY_ITERPARTIAL", 16).unwrap();
         array.push_many("Testing...".chars());
 
         let iter = array.iter();
@@ -448,10 +899,109 @@ mod tests {
         assert_eq!(&s, "Testing...");
     }
 
+    #[test]
+    fn array_consumer_resumes() {
+        let mut array: Array<u8> = heap().alloc("TEST_ARRAY_RESUME", 8).unwrap();
+        array.push_many(0..6);
+
+        {
+            let mut consumer: Array<u8> = heap().open("TEST_ARRAY_RESUME").unwrap();
+            assert_eq!(consumer.pop(), Some(0));
+            assert_eq!(consumer.pop(), Some(1));
+        }
+
+        // A new consumer picks up exactly where the last one stopped.
+        let mut consumer: Array<u8> = heap().open("TEST_ARRAY_RESUME").unwrap();
+        assert_eq!(consumer.len(), 4);
+        assert_eq!(consumer.iter().copied().collect::<Vec<_>>(), vec![2, 3, 4, 5]);
+        assert_eq!(consumer.pop(), Some(2));
+
+        // Both instances agree on where the head is.
+        assert_eq!(array.pop(), Some(3));
+        assert_eq!(consumer.pop(), Some(4));
+        assert_eq!(array.len(), 1);
+    }
+
+    fn check_alignment<T>(path: &str, values: [T; 3])
+    where
+        T: ShmSafe + Copy + PartialEq + std::fmt::Debug,
+    {
+        let mut array: Array<T> = heap().alloc(path, 4).unwrap();
+        assert!(array.cursors.is_aligned());
+        assert_eq!(array.cursors as usize % 128, 0);
+        assert!(array.base.is_aligned());
+
+        array.push_many(values);
+        assert!(array.push(values[0]));
+        assert_eq!(array.pop(), Some(values[0]));
+
+        let ref_array: Array<T> = heap().open(path).unwrap();
+        assert!(ref_array.base.is_aligned());
+        assert_eq!(
+            ref_array.iter().copied().collect::<Vec<_>>(),
+            vec![values[1], values[2], values[0]],
+        );
+    }
+
+    #[test]
+    fn array_aligned_elements() {
+        #[derive(Clone, Copy, Debug, PartialEq, ShmSafe, TypeLayout)]
+        #[repr(C, align(32))]
+        struct Simd([f32; 8]);
+
+        #[derive(Clone, Copy, Debug, PartialEq, ShmSafe, TypeLayout)]
+        #[repr(C, align(64))]
+        struct CacheLine(u8);
+
+        check_alignment("TEST_ARRAY_ALIGN_U64", [1_u64, u64::MAX, 3]);
+        check_alignment("TEST_ARRAY_ALIGN_U128", [1_u128, u128::MAX, 3]);
+        check_alignment(
+            "TEST_ARRAY_ALIGN_SIMD",
+            [Simd([1.0; 8]), Simd([2.0; 8]), Simd([3.0; 8])],
+        );
+        check_alignment("TEST_ARRAY_ALIGN_LINE", [CacheLine(1), CacheLine(2), CacheLine(3)]);
+    }
+
+    #[test]
+    fn array_pop_blocking() {
+        let mut array: Array<u32> = heap().alloc("TEST_ARRAY_POPBLOCKING", 4).unwrap();
+
+        let handle = std::thread::spawn(|| {
+            let mut ref_array: Array<u32> = heap().open("TEST_ARRAY_POPBLOCKING").unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            ref_array.push(7);
+            std::thread::sleep(Duration::from_millis(20));
+            ref_array.push(11);
+        });
+
+        assert_eq!(array.pop_timeout(Duration::from_millis(1)), None);
+        assert_eq!(array.pop_blocking(), 7);
+        assert_eq!(array.pop_timeout(Duration::MAX), Some(11));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn array_push_blocking() {
+        let mut array: Array<u32> = heap().alloc("TEST_ARRAY_PUSHBLOCKING", 2).unwrap();
+        array.push_many([1, 2]);
+        assert!(!array.push_timeout(3, Duration::from_millis(1)));
+
+        let handle = std::thread::spawn(|| {
+            let mut ref_array: Array<u32> = heap().open("TEST_ARRAY_PUSHBLOCKING").unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            ref_array.pop()
+        });
+
+        assert!(array.push_timeout(3, Duration::MAX));
+        assert_eq!(handle.join().unwrap(), Some(1));
+        assert_eq!(array.iter().copied().collect::<Vec<_>>(), vec![2, 3]);
+    }
+
     #[test]
     fn array_traverse_mutable() {
-        let mut array = Array::alloc("/tmp/TEST_ARRAY_ITERMUT", 16).unwrap();
-        array.push_many([1, 2, 3, 4, 5].into_iter());
+        let mut array = heap().alloc("TEST_ARRAY_ITERMUT", 16).unwrap();
+        array.push_many([1, 2, 3, 4, 5]);
 
         for n in array.iter_mut() {
             *n += 1;
@@ -460,4 +1010,40 @@ mod tests {
         let result = array.iter().copied().collect::<Vec<_>>();
         assert_eq!(result, vec![2, 3, 4, 5, 6]);
     }
+
+    #[test]
+    fn array_open_wait() {
+        let handle = std::thread::spawn(|| {
+            let mut array: Array<u8> =
+                heap().open_wait("TEST_ARRAY_OPENWAIT", Duration::from_secs(5)).unwrap();
+            array.pop_timeout(Duration::from_secs(5))
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        let mut array: Array<u8> = heap().alloc("TEST_ARRAY_OPENWAIT", 4).unwrap();
+        array.push(9);
+
+        assert_eq!(handle.join().unwrap(), Some(9));
+        assert!(matches!(
+            heap().open_wait::<u8>("TEST_ARRAY_OPENWAIT_MISSING", Duration::ZERO),
+            Err(Error::Timeout),
+        ));
+    }
+
+    #[test]
+    fn array_lifetime_last_detach() {
+        let path = "TEST_ARRAY_LASTDETACH";
+        let mut array: Array<u8> = heap()
+            .lifetime(Lifetime::LastDetach)
+            .alloc(path, 4)
+            .unwrap();
+        array.push(1);
+
+        // The producer can leave before the consumer has popped everything.
+        let mut consumer: Array<u8> = heap().open(path).unwrap();
+        drop(array);
+        assert_eq!(consumer.pop(), Some(1));
+
+        drop(consumer);
+        assert!(heap().open::<u8>(path).is_err());
+    }
 }
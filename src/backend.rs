@@ -0,0 +1,715 @@
+//! Shared Memory Backends
+//!
+//! A [`Backend`] is a way of creating (and opening) the shared memory that an object lives in.
+//! Every object (a [`Block`](crate::Block), [`Array`](crate::Array), [`Mutex`](crate::Mutex),
+//! [`Segment`](crate::Segment), etc.) uses the [`ShmemBackend`] unless it's given another one
+//! through its options:
+//! ```no_run
+//! use ration::{Block, BlockOptions, FileBackend};
+//!
+//! // Keep the block in a plain file, which outlives a reboot.
+//! let block: Block<u64> = BlockOptions::new()
+//!     .backend(FileBackend)
+//!     .alloc_with("/var/lib/my_app/counter", 0)
+//!     .unwrap();
+//! ```
+//! Every instance that opens an object has to use the same backend as the one that allocated it.
+
+
+
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use shared_memory::Shmem;
+
+use crate::{Error, Result};
+
+
+
+/// A way of creating (and opening) shared memory, identified by a path.
+pub trait Backend: std::fmt::Debug + Send + Sync {
+    /// Create a new, zero-filled mapping of `size` bytes, identified by the given path.
+    ///
+    /// Fails with [`Error::BlockAlreadyAllocated`] if something is already identified by the
+    /// path. The mapping mustn't be visible to [`open`](Backend::open) until it has its full size.
+    fn create(&self, path: &Path, size: usize) -> Result<Box<dyn Memory>>;
+
+    /// Open the mapping identified by the given path.
+    ///
+    /// Fails with [`Error::NotFound`] if nothing is.
+    fn open(&self, path: &Path) -> Result<Box<dyn Memory>>;
+
+    /// The file that's created (or written) when memory is created at the given path, if there is
+    /// one.
+    ///
+    /// Waiting for memory to be created (like [`Block::open_wait`](crate::Block::open_wait) does)
+    /// watches the directory that holds this file. Without one, which is the default, waiting
+    /// polls instead.
+    fn watch_path(&self, path: &Path) -> Option<PathBuf> {
+        let _ = path;
+        None
+    }
+}
+
+/// A mapping of shared memory, created (or opened) by a [`Backend`].
+///
+/// The memory stays mapped until the handle is dropped.
+pub trait Memory {
+    /// A pointer to the start of the mapping.
+    fn as_ptr(&self) -> *mut u8;
+
+    /// The size of the mapping, in bytes.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if the mapping is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove the memory (and its path) once this handle is dropped, so that nothing else can open
+    /// it. Instances that already have it open keep it mapped until they drop it too.
+    fn unlink(&self);
+}
+
+
+
+/// The default backend, which uses the [`shared_memory`] crate.
+///
+/// The path is a file that holds the ID of the mapping, which lives in `/dev/shm` on Linux.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShmemBackend;
+
+impl Backend for ShmemBackend {
+    fn create(&self, path: &Path, size: usize) -> Result<Box<dyn Memory>> {
+        let mut shm = crate::create_shm(path, size)?;
+        shm.set_owner(false);
+
+        Ok(Box::new(ShmemMemory {
+            shm,
+            unlink: Cell::new(false),
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Memory>> {
+        Ok(Box::new(ShmemMemory {
+            shm: crate::open_shm(path)?,
+            unlink: Cell::new(false),
+        }))
+    }
+
+    fn watch_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_path_buf())
+    }
+}
+
+struct ShmemMemory {
+    shm: Shmem,
+    unlink: Cell<bool>,
+}
+
+impl Memory for ShmemMemory {
+    fn as_ptr(&self) -> *mut u8 {
+        self.shm.as_ptr()
+    }
+
+    fn len(&self) -> usize {
+        self.shm.len()
+    }
+
+    fn unlink(&self) {
+        self.unlink.set(true);
+    }
+}
+
+impl Drop for ShmemMemory {
+    fn drop(&mut self) {
+        // The owner of a mapping removes it (and its link) when dropped.
+        self.shm.set_owner(self.unlink.get());
+    }
+}
+
+
+
+/// A backend that calls `shm_open` directly, without any link files.
+///
+/// The path is turned into the name of the shared memory object by replacing its slashes with
+/// dots, so `/tmp/MY_BLOCK` lives at `/dev/shm/tmp.MY_BLOCK` on Linux.
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PosixBackend;
+
+#[cfg(unix)]
+impl PosixBackend {
+    fn name(path: &Path) -> Result<std::ffi::CString> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = path.as_os_str().as_bytes();
+        let mut name = vec![b'/'];
+        name.extend(path.strip_prefix(b"/").unwrap_or(path).iter().map(|&b| match b {
+            b'/' => b'.',
+            b => b,
+        }));
+        if name.len() < 2 || name.len() > 255 {
+            return Err(Error::InvalidName);
+        }
+
+        std::ffi::CString::new(name).map_err(|_| Error::InvalidName)
+    }
+}
+
+#[cfg(unix)]
+impl Backend for PosixBackend {
+    fn create(&self, path: &Path, size: usize) -> Result<Box<dyn Memory>> {
+        let name = Self::name(path)?;
+        unsafe {
+            let fd = libc::shm_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0o600,
+            );
+            if fd < 0 {
+                return Err(Error::from_io(std::io::Error::last_os_error(), path));
+            }
+            let mapped = mmap::resize(fd, size).and_then(|()| mmap::map(fd, size));
+            libc::close(fd);
+            match mapped {
+                Ok(ptr) => Ok(Box::new(mmap::Mmap::new(ptr, size, mmap::Unlink::Shm(name)))),
+                Err(e) => {
+                    libc::shm_unlink(name.as_ptr());
+                    Err(Error::from_io(e, path))
+                }
+            }
+        }
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Memory>> {
+        let name = Self::name(path)?;
+        unsafe {
+            let fd = libc::shm_open(name.as_ptr(), libc::O_RDWR, 0o600);
+            if fd < 0 {
+                return Err(Error::from_io(std::io::Error::last_os_error(), path));
+            }
+            let mapped = mmap::map_whole(fd);
+            libc::close(fd);
+            let (ptr, len) = mapped.map_err(|e| Error::from_io(e, path))?;
+
+            Ok(Box::new(mmap::Mmap::new(ptr, len, mmap::Unlink::Shm(name))))
+        }
+    }
+
+    fn watch_path(&self, path: &Path) -> Option<PathBuf> {
+        use std::os::unix::ffi::OsStrExt;
+
+        // Linux keeps POSIX shared memory objects in a `tmpfs` at `/dev/shm`.
+        if !cfg!(any(target_os = "linux", target_os = "android")) {
+            return None;
+        }
+        let name = Self::name(path).ok()?;
+        let name = std::ffi::OsStr::from_bytes(&name.as_bytes()[1..]);
+
+        Some(Path::new("/dev/shm").join(name))
+    }
+}
+
+
+
+/// A backend that maps anonymous memory from `memfd_create`.
+///
+/// The path is a file that holds the `/proc/<pid>/fd/<fd>` path of the memory, so it can only be
+/// opened while the instance that allocated it is still around. The memory itself lasts until
+/// every instance that has it open is dropped.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemfdBackend;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Backend for MemfdBackend {
+    fn create(&self, path: &Path, size: usize) -> Result<Box<dyn Memory>> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        let fd = unsafe {
+            let fd = libc::memfd_create(c"ration".as_ptr(), libc::MFD_CLOEXEC);
+            if fd < 0 {
+                return Err(Error::Io(std::io::Error::last_os_error()));
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+        let ptr = unsafe {
+            let fd = fd.as_raw_fd();
+            mmap::resize(fd, size).and_then(|()| mmap::map(fd, size))
+        }
+        .map_err(Error::Io)?;
+        let memory = mmap::Mmap::new(ptr, size, mmap::Unlink::File(path.to_path_buf()));
+
+        let link = format!("/proc/{}/fd/{}", std::process::id(), fd.as_raw_fd());
+        mmap::publish(path, link.as_bytes())?;
+
+        Ok(Box::new(memory.holding(fd)))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Memory>> {
+        use std::os::fd::AsRawFd;
+
+        let link = std::fs::read_to_string(path).map_err(|e| Error::from_io(e, path))?;
+        // Fails with `NotFound` once the allocator's descriptor is gone.
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(link.trim())
+            .map_err(|e| Error::from_io(e, path))?;
+        let (ptr, len) =
+            unsafe { mmap::map_whole(file.as_raw_fd()) }.map_err(|e| Error::from_io(e, path))?;
+
+        Ok(Box::new(mmap::Mmap::new(ptr, len, mmap::Unlink::File(path.to_path_buf()))))
+    }
+
+    fn watch_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_path_buf())
+    }
+}
+
+
+
+/// A backend that maps a plain file, which is the path itself.
+///
+/// Unlike the other backends, the contents of the file are kept on disk (unless the file is on a
+/// `tmpfs`), so they can outlive a reboot.
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileBackend;
+
+#[cfg(unix)]
+impl Backend for FileBackend {
+    fn create(&self, path: &Path, size: usize) -> Result<Box<dyn Memory>> {
+        use std::os::fd::AsRawFd;
+
+        // Build the file up next to the path, so that nothing opens it before it's full size.
+        let staging = mmap::staging_path(path);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&staging)
+            .map_err(|e| Error::from_io(e, path))?;
+        let mapped = file
+            .set_len(size as u64)
+            .and_then(|()| unsafe { mmap::map(file.as_raw_fd(), size) })
+            .map_err(|e| Error::from_io(e, path));
+        let result = mapped.and_then(|ptr| {
+            let memory = mmap::Mmap::new(ptr, size, mmap::Unlink::File(path.to_path_buf()));
+            std::fs::hard_link(&staging, path).map_err(|e| Error::from_io(e, path))?;
+            Ok(memory)
+        });
+        let _ = std::fs::remove_file(&staging);
+
+        Ok(Box::new(result?))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Memory>> {
+        use std::os::fd::AsRawFd;
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| Error::from_io(e, path))?;
+        let (ptr, len) =
+            unsafe { mmap::map_whole(file.as_raw_fd()) }.map_err(|e| Error::from_io(e, path))?;
+
+        Ok(Box::new(mmap::Mmap::new(ptr, len, mmap::Unlink::File(path.to_path_buf()))))
+    }
+
+    fn watch_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_path_buf())
+    }
+}
+
+
+
+/// A backend that keeps memory on the heap of the current process, in a registry of every heap
+/// mapping by path.
+///
+/// Nothing outside of the current process can open these mappings, and nothing touches the
+/// filesystem, so it's most useful for tests.
+///
+/// # Example
+/// ```
+/// use ration::{Block, BlockOptions, HeapBackend};
+///
+/// let options = BlockOptions::new().backend(HeapBackend);
+/// let block: Block<u32> = options.alloc_with("counter", 71).unwrap();
+/// assert_eq!(*options.open::<u32>("counter").unwrap(), 71);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapBackend;
+
+/// Heap mappings are aligned like pages, the same as the mappings of the other backends.
+const HEAP_ALIGN: usize = 4096;
+
+static HEAPS: std::sync::Mutex<BTreeMap<PathBuf, Arc<HeapBuffer>>> =
+    std::sync::Mutex::new(BTreeMap::new());
+
+impl Backend for HeapBackend {
+    fn create(&self, path: &Path, size: usize) -> Result<Box<dyn Memory>> {
+        let mut heaps = HEAPS.lock().unwrap_or_else(|e| e.into_inner());
+        if heaps.contains_key(path) {
+            return Err(Error::BlockAlreadyAllocated);
+        }
+        let buffer = Arc::new(HeapBuffer::new(size)?);
+        heaps.insert(path.to_path_buf(), Arc::clone(&buffer));
+
+        Ok(Box::new(HeapMemory {
+            buffer,
+            path: path.to_path_buf(),
+            unlink: Cell::new(false),
+        }))
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Memory>> {
+        let heaps = HEAPS.lock().unwrap_or_else(|e| e.into_inner());
+        let buffer = heaps.get(path).ok_or_else(|| Error::NotFound {
+            path: path.to_path_buf(),
+        })?;
+
+        Ok(Box::new(HeapMemory {
+            buffer: Arc::clone(buffer),
+            path: path.to_path_buf(),
+            unlink: Cell::new(false),
+        }))
+    }
+}
+
+struct HeapBuffer {
+    ptr: *mut u8,
+    layout: std::alloc::Layout,
+}
+
+// The buffer is only ever accessed through raw pointers, just like shared memory.
+unsafe impl Send for HeapBuffer {}
+unsafe impl Sync for HeapBuffer {}
+
+impl HeapBuffer {
+    fn new(size: usize) -> Result<Self> {
+        let layout = std::alloc::Layout::from_size_align(size.max(1), HEAP_ALIGN)
+            .map_err(|_| Error::Io(std::io::ErrorKind::OutOfMemory.into()))?;
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+
+        Ok(Self { ptr, layout })
+    }
+}
+
+impl Drop for HeapBuffer {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+struct HeapMemory {
+    buffer: Arc<HeapBuffer>,
+    path: PathBuf,
+    unlink: Cell<bool>,
+}
+
+impl Memory for HeapMemory {
+    fn as_ptr(&self) -> *mut u8 {
+        self.buffer.ptr
+    }
+
+    fn len(&self) -> usize {
+        self.buffer.layout.size()
+    }
+
+    fn unlink(&self) {
+        self.unlink.set(true);
+    }
+}
+
+impl Drop for HeapMemory {
+    fn drop(&mut self) {
+        if !self.unlink.get() {
+            return;
+        }
+        let mut heaps = HEAPS.lock().unwrap_or_else(|e| e.into_inner());
+        // Leave the path alone if it has been allocated again since.
+        if heaps.get(&self.path).is_some_and(|buffer| Arc::ptr_eq(buffer, &self.buffer)) {
+            heaps.remove(&self.path);
+        }
+    }
+}
+
+
+
+/// Helpers for the backends that `mmap` a file descriptor.
+#[cfg(unix)]
+mod mmap {
+    use std::{
+        cell::Cell,
+        ffi::CString,
+        io,
+        os::fd::OwnedFd,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use crate::{Error, Result};
+
+    use super::Memory;
+
+    /// How to remove a mapping's path.
+    pub enum Unlink {
+        /// With `shm_unlink`.
+        Shm(CString),
+        /// By removing a file.
+        File(PathBuf),
+    }
+
+    pub struct Mmap {
+        ptr: *mut u8,
+        len: usize,
+        target: Unlink,
+        unlink: Cell<bool>,
+        /// The descriptor of the mapped memory, kept open for as long as the mapping.
+        _fd: Option<OwnedFd>,
+    }
+
+    impl Mmap {
+        pub fn new(ptr: *mut u8, len: usize, target: Unlink) -> Self {
+            Self {
+                ptr,
+                len,
+                target,
+                unlink: Cell::new(false),
+                _fd: None,
+            }
+        }
+
+        /// Keep the given descriptor open for as long as the mapping.
+        #[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
+        pub fn holding(mut self, fd: OwnedFd) -> Self {
+            self._fd = Some(fd);
+            self
+        }
+    }
+
+    impl Memory for Mmap {
+        fn as_ptr(&self) -> *mut u8 {
+            self.ptr
+        }
+
+        fn len(&self) -> usize {
+            self.len
+        }
+
+        fn unlink(&self) {
+            self.unlink.set(true);
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+            if !self.unlink.get() {
+                return;
+            }
+            match &self.target {
+                Unlink::Shm(name) => unsafe {
+                    libc::shm_unlink(name.as_ptr());
+                },
+                Unlink::File(path) => {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+    }
+
+    /// Set the size of the memory behind the given descriptor, which fills it with zeroes.
+    pub unsafe fn resize(fd: libc::c_int, size: usize) -> io::Result<()> {
+        if unsafe { libc::ftruncate(fd, size as libc::off_t) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    pub unsafe fn map(fd: libc::c_int, size: usize) -> io::Result<*mut u8> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ptr as *mut u8)
+    }
+
+    /// Map all of the memory behind the given descriptor.
+    pub unsafe fn map_whole(fd: libc::c_int) -> io::Result<(*mut u8, usize)> {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = stat.st_size as usize;
+        // It's still being created.
+        if len == 0 {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+
+        Ok((unsafe { map(fd, len)? }, len))
+    }
+
+    /// Where to build up a file before linking it to the given path.
+    pub fn staging_path(path: &Path) -> PathBuf {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}.{}.tmp", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+        path.with_file_name(name)
+    }
+
+    /// Write a file with the given contents to the given path, all at once. Fails with
+    /// [`Error::BlockAlreadyAllocated`] if the path already exists.
+    #[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
+    pub fn publish(path: &Path, contents: &[u8]) -> Result<()> {
+        let staging = staging_path(path);
+        let result = std::fs::write(&staging, contents)
+            .and_then(|()| std::fs::hard_link(&staging, path))
+            .map_err(|e| Error::from_io(e, path));
+        let _ = std::fs::remove_file(&staging);
+
+        result
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Array,
+        ArrayOptions,
+        Block,
+        BlockOptions,
+        Mutex,
+        MutexOptions,
+        Queue,
+        QueueOptions,
+        RwLock,
+        RwLockOptions,
+        SegmentOptions,
+        SeqBlock,
+        SeqBlockOptions,
+    };
+
+    fn share_objects(name: &str, backend: impl Backend + Copy + 'static) {
+        let path = PathBuf::from(format!("/tmp/TEST_BACKEND_{name}"));
+        let options = BlockOptions::new().backend(backend);
+
+        let mut block: Block<u64> = options.alloc_with(&path, 71).unwrap();
+        assert!(matches!(options.alloc::<u64>(&path), Err(Error::BlockAlreadyAllocated)));
+        let ref_block: Block<u64> = options.open(&path).unwrap();
+        *block = 72;
+        assert_eq!(*ref_block, 72);
+
+        drop(ref_block);
+        drop(block);
+        assert!(matches!(options.open::<u64>(&path), Err(Error::NotFound { .. })));
+
+        let options = ArrayOptions::new().backend(backend);
+        let mut array: Array<u32> = options.alloc(&path, 4).unwrap();
+        let mut ref_array: Array<u32> = options.open(&path).unwrap();
+        assert!(array.push(73));
+        assert_eq!(ref_array.pop(), Some(73));
+        drop((array, ref_array));
+
+        let options = MutexOptions::new().backend(backend);
+        let mutex: Mutex<u64> = options.alloc(&path).unwrap();
+        *mutex.lock().unwrap() = 75;
+        assert_eq!(*options.open::<u64>(&path).unwrap().lock().unwrap(), 75);
+        drop(mutex);
+
+        let options = RwLockOptions::new().backend(backend);
+        let lock: RwLock<u64> = options.alloc(&path).unwrap();
+        *lock.write() = 76;
+        assert_eq!(*options.open::<u64>(&path).unwrap().read(), 76);
+        drop(lock);
+
+        let options = SeqBlockOptions::new().backend(backend);
+        let seq_block: SeqBlock<u64> = options.alloc(&path).unwrap();
+        seq_block.store(77);
+        assert_eq!(options.open::<u64>(&path).unwrap().read(), 77);
+        drop(seq_block);
+
+        let options = QueueOptions::new().backend(backend);
+        let queue: Queue<u32> = options.alloc(&path, 4).unwrap();
+        queue.push(78).unwrap();
+        assert_eq!(options.open::<u32>(&path).unwrap().pop(), Some(78));
+        drop(queue);
+
+        let options = SegmentOptions::new().backend(backend);
+        let segment = options.alloc(&path, 1 << 12).unwrap();
+        *segment.block::<u64>("block").unwrap() = 79;
+        assert_eq!(*options.open(&path).unwrap().block::<u64>("block").unwrap(), 79);
+        drop(segment);
+        assert!(matches!(options.open(&path), Err(Error::NotFound { .. })));
+
+        // Waiting wakes up as soon as the block is allocated, well before the timeout.
+        let waiter = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let start = std::time::Instant::now();
+                let options = BlockOptions::new().backend(backend);
+                let block: Block<u64> =
+                    options.open_wait(&path, std::time::Duration::from_secs(10)).unwrap();
+                (*block, start.elapsed())
+            }
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let options = BlockOptions::new().backend(backend);
+        let _block: Block<u64> = options.alloc_with(&path, 74).unwrap();
+
+        let (value, elapsed) = waiter.join().unwrap();
+        assert_eq!(value, 74);
+        assert!(elapsed < std::time::Duration::from_secs(5), "waited for {elapsed:?}");
+    }
+
+    #[test]
+    fn backend_shmem() {
+        share_objects("shmem", ShmemBackend);
+    }
+
+    #[test]
+    fn backend_posix() {
+        share_objects("posix", PosixBackend);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn backend_memfd() {
+        share_objects("memfd", MemfdBackend);
+    }
+
+    #[test]
+    fn backend_file() {
+        share_objects("file", FileBackend);
+    }
+
+    #[test]
+    fn backend_heap() {
+        share_objects("heap", HeapBackend);
+    }
+}
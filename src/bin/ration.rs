@@ -0,0 +1,80 @@
+//! The `ration` command-line tool.
+//!
+//! ```text
+//! ration gc [--dry-run] [DIR]...
+//! ```
+//!
+//! Removes orphaned segments (see [`ration::gc`]) from each of the given directories, or from
+//! `/tmp` and `/dev/shm` if none are given.
+
+
+
+use std::{path::PathBuf, process::ExitCode};
+
+
+
+const USAGE: &str = "\
+Usage: ration gc [--dry-run] [DIR]...
+
+Remove shared memory segments left behind by processes that exited without dropping them.
+
+Options:
+    -n, --dry-run    List orphaned segments without removing them
+    -h, --help       Print this message
+
+Scans /tmp and /dev/shm if no directories are given.";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("gc") => {}
+        Some("-h" | "--help") => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut dry_run = false;
+    let mut dirs = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-n" | "--dry-run" => dry_run = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("unknown option: {arg}\n\n{USAGE}");
+                return ExitCode::FAILURE;
+            }
+            _ => dirs.push(PathBuf::from(arg)),
+        }
+    }
+    if dirs.is_empty() {
+        dirs = vec![PathBuf::from("/tmp"), PathBuf::from("/dev/shm")];
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for dir in dirs {
+        let result = if dry_run { ration::find_orphans(&dir) } else { ration::gc(&dir) };
+        match result {
+            Ok(orphans) => {
+                for orphan in orphans {
+                    let kind = orphan.kind().map_or("unknown".to_string(), |k| format!("{k:?}"));
+                    let action = if dry_run { "would remove" } else { "removed" };
+                    println!("{action} {} ({kind})", orphan.path().display());
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {e}", dir.display());
+                status = ExitCode::FAILURE;
+            }
+        }
+    }
+
+    status
+}
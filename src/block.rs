@@ -2,12 +2,28 @@
 
 
 
-use std::{ops::Deref, path::Path};
+use std::{
+    ops::Deref,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::{Error, Result};
+use crate::{
+    header::{Header, Lifetime, ObjectKind, Peer},
+    region::Region,
+    Backend,
+    Error,
+    Result,
+    ShmemBackend,
+    ShmSafe,
+};
 
 
 
+/// How long [`Block::open_or_alloc`] waits for another instance to initialize the block.
+const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// A typed, shared block of memory.
 ///
 /// # Example
@@ -25,61 +41,211 @@ use crate::{Error, Result};
 /// let block: Block<u64> = Block::open("/dev/shm/MY_BLOCK").unwrap();
 /// println!("MY_BLOCK: {}", *block); // 71
 /// ```
-pub struct Block<T: Sized> {
-    shm: shared_memory::Shmem,
+pub struct Block<T: ShmSafe> {
+    region: Region,
     ptr: *mut T,
 }
 
-impl<T: Sized> Block<T> {
+impl<T: ShmSafe> Block<T> {
     /// Allocate a new shared block of memory at the given path, and of the given type.
+    ///
+    /// The value starts off uninitialized, so make sure to write one before anything reads it, or
+    /// use [`alloc_with`](Block::alloc_with) instead.
+    ///
+    /// To allocate a block with a different [`Lifetime`], use [`BlockOptions`].
     pub fn alloc(path: impl AsRef<Path>) -> Result<Self> {
-        let size = std::mem::size_of::<T>();
-        let shm = match shared_memory::ShmemConf::new().flink(&path).size(size).create() {
-            Ok(shmem) => shmem,
-            Err(shared_memory::ShmemError::LinkExists) => {
-                return Err(Error::BlockAlreadyAllocated);
+        BlockOptions::new().alloc(path)
+    }
+
+    /// Allocate a new shared block of memory at the given path, holding the given value.
+    ///
+    /// Other instances can't open the block until the value has been written.
+    pub fn alloc_with(path: impl AsRef<Path>, value: T) -> Result<Self> {
+        BlockOptions::new().alloc_with(path, value)
+    }
+
+    /// Open a shared block of memory identified by the given path and type.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        BlockOptions::new().open(path)
+    }
+
+    /// Open a shared block of memory identified by the given path and type, waiting for it to be
+    /// allocated and initialized if it hasn't been yet.
+    ///
+    /// Waits forever if `timeout` is `None`, or fails with [`Error::Timeout`] once it has passed.
+    /// Fails with [`Error::Closed`] if the process that allocated the block exits before
+    /// initializing it.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use ration::Block;
+    ///
+    /// // Returns as soon as the peer has allocated (and initialized) the block.
+    /// let block: Block<u64> = Block::open_wait("/tmp/MY_BLOCK", Duration::from_secs(5)).unwrap();
+    /// ```
+    pub fn open_wait(
+        path: impl AsRef<Path>,
+        timeout: impl Into<Option<Duration>>,
+    ) -> Result<Self> {
+        BlockOptions::new().open_wait(path, timeout)
+    }
+
+    /// Open the shared block of memory identified by the given path and type, or allocate it
+    /// with the value returned by `init` if it doesn't exist yet.
+    ///
+    /// Only one of any number of instances racing to allocate the block calls its `init`, and the
+    /// others wait for it to finish, so the returned block always holds a fully initialized value.
+    /// Waiting gives up with [`Error::Closed`] if the allocator dies part of the way through, or
+    /// with [`Error::Uninitialized`] if the block still isn't initialized after a few seconds.
+    ///
+    /// Use [`is_owner`](Block::is_owner) to tell whether this call allocated the block.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use ration::Block;
+    ///
+    /// // Every process can run this, and they'll all share the same counter.
+    /// let counter: Block<u64> = Block::open_or_alloc("/tmp/MY_COUNTER", || 0).unwrap();
+    /// ```
+    pub fn open_or_alloc(path: impl AsRef<Path>, init: impl FnOnce() -> T) -> Result<Self> {
+        BlockOptions::new().open_or_alloc(path, init)
+    }
+
+    /// The number of bytes needed to hold a block of this type.
+    pub(crate) fn block_size() -> usize {
+        Header::data_offset::<T>() + std::mem::size_of::<T>()
+    }
+
+    /// The alignment that a block of this type has to start at.
+    pub(crate) fn block_align() -> usize {
+        std::mem::align_of::<Header>().max(std::mem::align_of::<T>())
+    }
+
+    /// Create a new block in the given region, holding the given value.
+    ///
+    /// Like [`alloc`](Block::alloc), the value is left uninitialized if it's `None`.
+    ///
+    /// # Safety
+    ///
+    /// The region must be writable, at least [`block_size`](Block::block_size) bytes long, and
+    /// aligned for both the header and `T`.
+    pub(crate) unsafe fn init(mut region: Region, value: Option<T>, lifetime: Lifetime) -> Self {
+        let ptr = unsafe {
+            let header = region.as_ptr() as *mut Header;
+            header.write(
+                Header::new::<T>(ObjectKind::Block, 1).with_layout::<T>().with_lifetime(lifetime),
+            );
+            region.attach();
+
+            let ptr = region.as_ptr().add(Header::data_offset::<T>()) as *mut T;
+            if let Some(value) = value {
+                ptr.write(value);
             }
-            Err(e) => { return Err(Error::Shm(e)); }
-        };
+            (*header).mark_initialized();
 
-        let ptr = shm.as_ptr() as *mut T;
+            ptr
+        };
 
-        Ok(Self {
-            shm,
+        Self {
+            region,
             ptr,
-        })
+        }
     }
 
-    /// Open a shared block of memory identified by the given path and type.
-    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let shm = shared_memory::ShmemConf::new()
-            .flink(path)
-            .open()
-            .map_err(|e| Error::Shm(e))?;
+    /// Open the block that was created in the given region.
+    pub(crate) fn attach(mut region: Region) -> Result<Self> {
+        // Make sure the block was allocated by `ration`, and for this type.
+        unsafe { Header::validate_layout::<T>(region.as_ptr(), region.len(), ObjectKind::Block)? };
 
         // Check if the expected type's size matches the allocated block's size.
-        let size = std::mem::size_of::<T>();
-        if shm.len() != size {
-            return Err(Error::InvalidBlockSize);
+        if region.len() < Self::block_size() {
+            return Err(Error::InvalidBlockSize {
+                expected: Self::block_size(),
+                found: region.len(),
+            });
         }
+        region.attach();
 
-        let ptr = shm.as_ptr() as *mut T;
+        let ptr = unsafe { region.as_ptr().add(Header::data_offset::<T>()) as *mut T };
 
         Ok(Self {
-            shm,
+            region,
             ptr,
         })
     }
 }
 
-impl<T> Block<T> {
+impl<T: ShmSafe> Block<T> {
     /// Returns `true` if the underlying shared memory mapping is owned by this block instance.
     pub fn is_owner(&self) -> bool {
-        self.shm.is_owner()
+        self.region.is_owner()
+    }
+
+    /// Make this instance the block's owner, or stop it from being one.
+    ///
+    /// With [`Lifetime::Owner`], the block is unlinked when its owner is dropped, so this can be
+    /// used to hand ownership over to another instance (which calls `set_owner(true)`), after
+    /// this one calls [`disown`](Block::disown).
+    pub fn set_owner(&mut self, owner: bool) {
+        self.region.set_owner(owner);
+    }
+
+    /// Stop this instance from being the block's owner, so that dropping it doesn't unlink the
+    /// block.
+    pub fn disown(&mut self) {
+        self.set_owner(false);
+    }
+
+    /// The instances (in any process, including this one) that have the block open.
+    ///
+    /// Only the first [`MAX_PEERS`](crate::MAX_PEERS) instances to open the block are listed, not
+    /// counting ones whose process has exited.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.region.header().peers()
+    }
+
+    /// Returns `true` if the process that owns the block is still running.
+    ///
+    /// This is `false` once the owner has been dropped (or [`disown`](Block::disown)ed without
+    /// another instance taking over), and if its process died without dropping it.
+    pub fn owner_alive(&self) -> bool {
+        self.region.header().owner_alive()
+    }
+
+    /// Returns `true` if every other process that opened the block has exited without dropping
+    /// it, or if no other process has it open at all.
+    ///
+    /// Supervisors can use this to tell when a segment can be reclaimed or recreated.
+    pub fn is_abandoned(&self) -> bool {
+        self.region.header().is_abandoned()
+    }
+
+    /// Record that this instance is still alive, updating its
+    /// [`last_heartbeat`](Peer::last_heartbeat).
+    ///
+    /// Call this regularly to let other processes tell a hung process apart from one that's
+    /// still working.
+    pub fn heartbeat(&self) {
+        self.region.heartbeat();
+    }
+
+    /// When the block's shared memory is unlinked.
+    pub fn lifetime(&self) -> Lifetime {
+        self.region.header().lifetime()
+    }
+
+    /// Unlink the block's shared memory (and the path linked to it), no matter what its lifetime
+    /// is.
+    ///
+    /// Other instances that already have the block open keep working, but nothing can open it
+    /// anymore.
+    pub fn unlink(mut self) {
+        self.region.unlink_on_drop();
     }
 }
 
-impl<T> std::ops::Deref for Block<T> {
+impl<T: ShmSafe> std::ops::Deref for Block<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -87,13 +253,23 @@ impl<T> std::ops::Deref for Block<T> {
     }
 }
 
-impl<T> std::ops::DerefMut for Block<T> {
+impl<T: ShmSafe> std::ops::DerefMut for Block<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { &mut *self.ptr }
     }
 }
 
-impl<T: std::fmt::Debug> std::fmt::Debug for Block<T> {
+impl<T: ShmSafe> crate::Mapping for Block<T> {
+    fn base_ptr(&self) -> *mut u8 {
+        self.region.mapping_ptr()
+    }
+
+    fn mapped_len(&self) -> usize {
+        self.region.mapping_len()
+    }
+}
+
+impl<T: ShmSafe + std::fmt::Debug> std::fmt::Debug for Block<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Block")
             .field("addr", &self.ptr)
@@ -103,11 +279,132 @@ impl<T: std::fmt::Debug> std::fmt::Debug for Block<T> {
 }
 
 
+/// Options for allocating (and opening) a [`Block`], like when its shared memory is unlinked, and
+/// which [`Backend`] it lives in.
+///
+/// # Example
+/// ```no_run
+/// use ration::{Block, BlockOptions, Lifetime};
+///
+/// // Keep the block around until the last instance that has it open is dropped.
+/// let block: Block<u64> = BlockOptions::new()
+///     .lifetime(Lifetime::LastDetach)
+///     .alloc_with("/tmp/MY_BLOCK", 71)
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct BlockOptions {
+    lifetime: Lifetime,
+    backend: Arc<dyn Backend>,
+}
+
+impl Default for BlockOptions {
+    fn default() -> Self {
+        Self {
+            lifetime: Lifetime::default(),
+            backend: Arc::new(ShmemBackend),
+        }
+    }
+}
+
+impl BlockOptions {
+    /// The default options, which are what [`Block::alloc`] uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set when the block's shared memory is unlinked. Defaults to [`Lifetime::Owner`].
+    pub fn lifetime(mut self, lifetime: Lifetime) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Set the backend that the block's shared memory is created (or opened) with. Defaults to
+    /// [`ShmemBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Allocate a new shared block of memory at the given path. See [`Block::alloc`].
+    pub fn alloc<T: ShmSafe>(&self, path: impl AsRef<Path>) -> Result<Block<T>> {
+        let memory = self.backend.create(path.as_ref(), Block::<T>::block_size())?;
+
+        Ok(unsafe { Block::init(Region::whole(memory, true), None, self.lifetime) })
+    }
+
+    /// Allocate a new shared block of memory at the given path, holding the given value. See
+    /// [`Block::alloc_with`].
+    pub fn alloc_with<T: ShmSafe>(&self, path: impl AsRef<Path>, value: T) -> Result<Block<T>> {
+        let memory = self.backend.create(path.as_ref(), Block::<T>::block_size())?;
+
+        Ok(unsafe { Block::init(Region::whole(memory, true), Some(value), self.lifetime) })
+    }
+
+    /// Open a shared block of memory identified by the given path and type. See [`Block::open`].
+    pub fn open<T: ShmSafe>(&self, path: impl AsRef<Path>) -> Result<Block<T>> {
+        Block::attach(Region::whole(self.backend.open(path.as_ref())?, false))
+    }
+
+    /// Open a shared block of memory identified by the given path and type, waiting for it to be
+    /// allocated and initialized if it hasn't been yet. See [`Block::open_wait`].
+    pub fn open_wait<T: ShmSafe>(
+        &self,
+        path: impl AsRef<Path>,
+        timeout: impl Into<Option<Duration>>,
+    ) -> Result<Block<T>> {
+        let memory = crate::open_wait(&*self.backend, path.as_ref(), timeout.into())?;
+
+        Block::attach(Region::whole(memory, false))
+    }
+
+    /// Open the shared block of memory identified by the given path and type, or allocate it
+    /// with the value returned by `init` if it doesn't exist yet. See [`Block::open_or_alloc`].
+    pub fn open_or_alloc<T: ShmSafe>(
+        &self,
+        path: impl AsRef<Path>,
+        init: impl FnOnce() -> T,
+    ) -> Result<Block<T>> {
+        let path = path.as_ref();
+        let start = Instant::now();
+        loop {
+            match self.backend.create(path, Block::<T>::block_size()) {
+                Ok(memory) => {
+                    let region = Region::whole(memory, true);
+                    return Ok(unsafe { Block::init(region, Some(init()), self.lifetime) });
+                }
+                Err(Error::BlockAlreadyAllocated) => {}
+                Err(e) => return Err(e),
+            }
+
+            match self.open(path) {
+                // The allocator hasn't finished mapping or initializing the block yet (or it was
+                // freed in the meantime, and can be allocated again).
+                Err(Error::NotFound { .. } | Error::Shm(_) | Error::Uninitialized)
+                    if start.elapsed() < INIT_TIMEOUT =>
+                {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{ArrayOptions, HeapBackend, OffsetPtr, TypeLayout};
 
+    /// Blocks in this process' heap, so that tests don't leave anything behind.
+    fn heap() -> BlockOptions {
+        BlockOptions::new().backend(HeapBackend)
+    }
+
+    #[derive(ShmSafe, TypeLayout)]
+    #[repr(C)]
     struct TestDatatype {
         field_a: u32,
         field_b: [char; 16],
@@ -115,7 +412,7 @@ mod tests {
 
     #[test]
     fn block_test_1() {
-        let mut block: Block<TestDatatype> = Block::alloc("/tmp/TEST_BLOCK_1").unwrap();
+        let mut block: Block<TestDatatype> = heap().alloc("TEST_BLOCK_1").unwrap();
         assert!(block.is_owner());
 
         *block = TestDatatype {
@@ -126,7 +423,7 @@ mod tests {
         };
 
         {
-            let mut ref_block: Block<TestDatatype> = Block::open("/tmp/TEST_BLOCK_1").unwrap();
+            let mut ref_block: Block<TestDatatype> = heap().open("TEST_BLOCK_1").unwrap();
             assert!(!ref_block.is_owner());
 
             assert_eq!(ref_block.field_a, 0xffffffff);
@@ -143,21 +440,231 @@ mod tests {
 
     #[test]
     fn block_responsive_afterward() {
-        let mut block: Block<u8> = Block::alloc("/tmp/TEST_BLOCK_RESPAFTER").unwrap();
-        let ref_block: Block<u8> = Block::open("/tmp/TEST_BLOCK_RESPAFTER").unwrap();
+        let mut block: Block<u8> = heap().alloc("TEST_BLOCK_RESPAFTER").unwrap();
+        let ref_block: Block<u8> = heap().open("TEST_BLOCK_RESPAFTER").unwrap();
         *block = 11;
         assert_eq!(*ref_block, 11);
     }
 
     #[test]
     fn block_multithreading() {
-        let mut block: Block<u8> = Block::alloc("/tmp/TEST_BLOCK_MTHREADING").unwrap();
+        let mut block: Block<u8> = heap().alloc("TEST_BLOCK_MTHREADING").unwrap();
         let handle = std::thread::spawn(move || {
-            let ref_block: Block<u8> = Block::open("/tmp/TEST_BLOCK_MTHREADING").unwrap();
+            let ref_block: Block<u8> = heap().open("TEST_BLOCK_MTHREADING").unwrap();
             std::thread::sleep(std::time::Duration::from_millis(5));
             *ref_block
         });
         *block = 11;
         assert_eq!(handle.join().unwrap(), 11);
     }
+
+    #[test]
+    fn block_alloc_with() {
+        let block = heap().alloc_with("TEST_BLOCK_ALLOCWITH", [7_u16; 3]).unwrap();
+        assert!(block.is_owner());
+
+        let ref_block: Block<[u16; 3]> = heap().open("TEST_BLOCK_ALLOCWITH").unwrap();
+        assert_eq!(*ref_block, [7; 3]);
+    }
+
+    #[test]
+    fn block_open_or_alloc() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static INITS: AtomicUsize = AtomicUsize::new(0);
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let block = heap().open_or_alloc("TEST_BLOCK_OPENORALLOC", || {
+                        INITS.fetch_add(1, Ordering::Relaxed);
+                        std::thread::sleep(std::time::Duration::from_millis(20));
+                        (71_u32, 72_u32)
+                    })
+                    .unwrap();
+                    assert_eq!(*block, (71, 72));
+
+                    // Keep every instance (including the owner) alive until all have checked.
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    block.is_owner()
+                })
+            })
+            .collect();
+
+        let owners = handles.into_iter().filter_map(|h| h.join().unwrap().then_some(())).count();
+        assert_eq!(owners, 1);
+        assert_eq!(INITS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn block_lifetime_last_detach() {
+        let path = "TEST_BLOCK_LASTDETACH";
+        let block: Block<u32> = heap()
+            .lifetime(Lifetime::LastDetach)
+            .alloc_with(path, 71)
+            .unwrap();
+        assert_eq!(block.lifetime(), Lifetime::LastDetach);
+
+        let ref_block: Block<u32> = heap().open(path).unwrap();
+        drop(block);
+
+        // The owner is gone, but the block is still open somewhere.
+        assert_eq!(*heap().open::<u32>(path).unwrap(), 71);
+
+        drop(ref_block);
+        assert!(heap().open::<u32>(path).is_err());
+    }
+
+    #[test]
+    fn block_lifetime_persist() {
+        let path = "TEST_BLOCK_PERSIST";
+        let block: Block<u32> = heap()
+            .lifetime(Lifetime::Persist)
+            .alloc_with(path, 71)
+            .unwrap();
+        drop(block);
+
+        let ref_block: Block<u32> = heap().open(path).unwrap();
+        assert_eq!(*ref_block, 71);
+        ref_block.unlink();
+
+        assert!(heap().open::<u32>(path).is_err());
+    }
+
+    #[test]
+    fn block_disown() {
+        let path = "TEST_BLOCK_DISOWN";
+        let mut block: Block<u32> = heap().alloc_with(path, 71).unwrap();
+        let mut ref_block: Block<u32> = heap().open(path).unwrap();
+
+        // Hand ownership over to `ref_block`.
+        block.disown();
+        ref_block.set_owner(true);
+        assert!(!block.is_owner());
+        assert!(ref_block.is_owner());
+
+        drop(block);
+        assert_eq!(*heap().open::<u32>(path).unwrap(), 71);
+
+        drop(ref_block);
+        assert!(heap().open::<u32>(path).is_err());
+    }
+
+    #[test]
+    fn block_peers() {
+        let path = "TEST_BLOCK_PEERS";
+        let block: Block<u32> = heap().alloc_with(path, 71).unwrap();
+        assert!(block.owner_alive());
+
+        let ref_block: Block<u32> = heap().open(path).unwrap();
+        let peers = block.peers();
+        assert_eq!(peers.len(), 2);
+        assert!(peers.iter().all(|peer| peer.pid() == std::process::id()));
+
+        // Nothing outside of this process has the block open.
+        assert!(ref_block.is_abandoned());
+
+        let before = ref_block.peers()[1].last_heartbeat();
+        std::thread::sleep(Duration::from_millis(5));
+        ref_block.heartbeat();
+        assert!(ref_block.peers()[1].last_heartbeat() > before);
+
+        drop(block);
+        assert!(!ref_block.owner_alive());
+        assert_eq!(ref_block.peers().len(), 1);
+    }
+
+    #[test]
+    fn block_open_wait() {
+        let handle = std::thread::spawn(|| {
+            heap().open_wait::<u32>("TEST_BLOCK_OPENWAIT", Duration::from_secs(5))
+                .map(|block| *block)
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        let _block = heap().alloc_with("TEST_BLOCK_OPENWAIT", 71_u32).unwrap();
+
+        assert_eq!(handle.join().unwrap().unwrap(), 71);
+    }
+
+    #[test]
+    fn block_open_wait_timeout() {
+        let start = Instant::now();
+        assert!(matches!(
+            heap().open_wait::<u32>("TEST_BLOCK_OPENWAITTIMEOUT", Duration::from_millis(50)),
+            Err(Error::Timeout),
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // The block exists, but never gets initialized.
+        let size = Block::<u32>::block_size();
+        let memory = HeapBackend.create(Path::new("TEST_BLOCK_OPENWAITTIMEOUT"), size).unwrap();
+        let header = Header::new::<u32>(ObjectKind::Block, 1).with_layout::<u32>();
+        unsafe { (memory.as_ptr() as *mut Header).write(header) };
+        assert!(matches!(
+            heap().open_wait::<u32>("TEST_BLOCK_OPENWAITTIMEOUT", Duration::from_millis(50)),
+            Err(Error::Timeout),
+        ));
+    }
+
+    #[test]
+    fn block_rejects_uninitialized() {
+        let memory =
+            HeapBackend.create(Path::new("TEST_BLOCK_UNINIT"), Block::<u64>::block_size()).unwrap();
+        let header = Header::new::<u64>(ObjectKind::Block, 1).with_layout::<u64>();
+        unsafe { (memory.as_ptr() as *mut Header).write(header) };
+
+        assert!(matches!(heap().open::<u64>("TEST_BLOCK_UNINIT"), Err(Error::Uninitialized)));
+
+        unsafe { (*(memory.as_ptr() as *const Header)).mark_initialized() };
+        assert!(heap().open::<u64>("TEST_BLOCK_UNINIT").is_ok());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "spawns a process")]
+    fn block_reports_missing_and_abandoned() {
+        match heap().open::<u32>("TEST_BLOCK_MISSING") {
+            Err(error @ Error::NotFound { .. }) => assert_eq!(
+                error.to_string(),
+                "nothing has been allocated at TEST_BLOCK_MISSING",
+            ),
+            other => panic!("expected NotFound, got {:?}", other.map(|_| ())),
+        }
+
+        // The process that allocated the block exits before initializing it.
+        let size = Block::<u32>::block_size();
+        let memory = HeapBackend.create(Path::new("TEST_BLOCK_ABANDONED"), size).unwrap();
+        let header = Header::new::<u32>(ObjectKind::Block, 1).with_layout::<u32>();
+        unsafe { (memory.as_ptr() as *mut Header).write(header) };
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        unsafe { &*(memory.as_ptr() as *const Header) }.abandon_to(child.id());
+
+        assert!(matches!(heap().open::<u32>("TEST_BLOCK_ABANDONED"), Err(Error::Closed)));
+        assert!(matches!(
+            heap().open_wait::<u32>("TEST_BLOCK_ABANDONED", None),
+            Err(Error::Closed),
+        ));
+    }
+
+    #[test]
+    fn block_rejects_other_types() {
+        let _block: Block<u32> = heap().alloc("TEST_BLOCK_OTHERTYPES").unwrap();
+
+        assert!(matches!(
+            heap().open::<i32>("TEST_BLOCK_OTHERTYPES"),
+            Err(Error::LayoutMismatch { .. }),
+        ));
+        assert!(matches!(
+            ArrayOptions::new().backend(HeapBackend).open::<u32>("TEST_BLOCK_OTHERTYPES"),
+            Err(Error::KindMismatch { expected: ObjectKind::Array, .. }),
+        ));
+        assert!(heap().open::<u32>("TEST_BLOCK_OTHERTYPES").is_ok());
+
+        // Pointers are told apart by what they point to.
+        let _pointer: Block<OffsetPtr<u64>> = heap().alloc("TEST_BLOCK_OTHERPOINTERS").unwrap();
+        assert!(matches!(
+            heap().open::<OffsetPtr<u32>>("TEST_BLOCK_OTHERPOINTERS"),
+            Err(Error::LayoutMismatch { .. }),
+        ));
+    }
 }
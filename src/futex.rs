@@ -0,0 +1,69 @@
+//! Futex Wakeups
+//!
+//! Thin wrappers around the futex syscall that work across processes, as long as the futex word
+//! lives in shared memory. On platforms without futexes, waiting falls back to polling.
+
+
+
+use std::{sync::atomic::AtomicU32, time::Duration};
+
+
+
+/// Block the current thread while `futex` holds `expected`, until it's woken by [`wake_one`] or
+/// [`wake_all`], or until `timeout` has passed.
+///
+/// This can return spuriously, so callers should always recheck whatever they're waiting for.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as _,
+    });
+
+    // NOTE: The futex is deliberately *not* process-private.
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            futex.as_ptr(),
+            libc::FUTEX_WAIT,
+            expected,
+            timeout.as_ref().map_or(std::ptr::null(), |t| t as *const libc::timespec),
+        );
+    }
+}
+
+/// Wake up to one thread (in any process) that is waiting on `futex`.
+///
+/// Returns `true` if a thread was woken up.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn wake_one(futex: &AtomicU32) -> bool {
+    unsafe { libc::syscall(libc::SYS_futex, futex.as_ptr(), libc::FUTEX_WAKE, 1) > 0 }
+}
+
+/// Wake every thread (in any process) that is waiting on `futex`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn wake_all(futex: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, futex.as_ptr(), libc::FUTEX_WAKE, i32::MAX);
+    }
+}
+
+
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+    if futex.load(std::sync::atomic::Ordering::Acquire) == expected {
+        std::thread::sleep(timeout.map_or(POLL_INTERVAL, |t| t.min(POLL_INTERVAL)));
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn wake_one(_futex: &AtomicU32) -> bool {
+    false
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn wake_all(_futex: &AtomicU32) {}
@@ -0,0 +1,235 @@
+//! Orphaned Segment Cleanup
+//!
+//! Processes that crash (or are killed) never drop their objects, so the segments they owned are
+//! left behind, along with the files linked to them. Allocating at one of those paths again fails
+//! with [`Error::BlockAlreadyAllocated`](crate::Error::BlockAlreadyAllocated) until they're
+//! removed.
+
+
+
+use std::{
+    fs::File,
+    io::Read,
+    mem::MaybeUninit,
+    path::{Path, PathBuf},
+};
+
+use shared_memory::{Shmem, ShmemConf};
+
+use crate::{header::Header, Error, ObjectKind, Result};
+
+
+
+/// The longest that a file linked to a segment can be. Link files only hold the ID of the mapping
+/// (like `/shmem_1a2b3c4d`).
+const MAX_LINK_LEN: u64 = 256;
+
+/// A segment that nothing is ever going to remove, found by [`gc`] or [`find_orphans`].
+#[derive(Clone, Debug)]
+pub struct Orphan {
+    path: PathBuf,
+    kind: Option<ObjectKind>,
+}
+
+impl Orphan {
+    /// The path that the segment was found at. This is either a file linked to the segment, or
+    /// the segment itself (for segments found in `/dev/shm`).
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The kind of object that the segment holds.
+    pub fn kind(&self) -> Option<ObjectKind> {
+        self.kind
+    }
+}
+
+/// Remove every orphaned segment in the given directory, returning the ones that were removed.
+///
+/// A segment is orphaned if it was created by `ration` (it starts with the [`MAGIC`] bytes), its
+/// [`Lifetime`] isn't [`Persist`](crate::Lifetime::Persist), and neither the process that owns it
+/// nor any process that has it open is still running. Both the files linked to segments (like the
+/// paths given to [`Block::alloc`](crate::Block::alloc)) and the segments themselves (in
+/// `/dev/shm`) are found, and anything else in the directory is left alone.
+///
+/// # Example
+/// ```no_run
+/// // Clean up after test runs that crashed.
+/// for orphan in ration::gc("/tmp").unwrap() {
+///     println!("removed {}", orphan.path().display());
+/// }
+/// ```
+///
+/// # Note
+///
+/// Objects that aren't blocks or arrays don't keep track of the processes that open them, so
+/// there's no telling whether they're still in use. They're never considered orphaned.
+///
+/// [`MAGIC`]: crate::MAGIC
+/// [`Lifetime`]: crate::Lifetime
+pub fn gc(dir: impl AsRef<Path>) -> Result<Vec<Orphan>> {
+    let mut removed = Vec::new();
+    for (orphan, segment) in scan(dir.as_ref())? {
+        match segment {
+            Segment::Link(mut shm) => {
+                // The owner of a mapping removes it (and its link) when dropped.
+                shm.set_owner(true);
+                drop(shm);
+            }
+            Segment::Mapping => match std::fs::remove_file(&orphan.path) {
+                Ok(()) => {}
+                // Something else got to it first.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(Error::Io(e)),
+            },
+        }
+        removed.push(orphan);
+    }
+
+    Ok(removed)
+}
+
+/// Find every orphaned segment in the given directory, without removing any of them.
+///
+/// This is a dry run of [`gc`].
+pub fn find_orphans(dir: impl AsRef<Path>) -> Result<Vec<Orphan>> {
+    Ok(scan(dir.as_ref())?.into_iter().map(|(orphan, _)| orphan).collect())
+}
+
+/// How an orphaned segment was found.
+enum Segment {
+    /// Through the file linked to it, holding the segment's mapping.
+    Link(Shmem),
+    /// As the segment's own file.
+    Mapping,
+}
+
+fn scan(dir: &Path) -> Result<Vec<(Orphan, Segment)>> {
+    let mut orphans = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(Error::Io)? {
+        let entry = entry.map_err(Error::Io)?;
+        // Skip directories, and anything that might block when read (like FIFOs).
+        if !entry.file_type().is_ok_and(|file_type| file_type.is_file()) {
+            continue;
+        }
+
+        let path = entry.path();
+        if let Some((kind, segment)) = inspect(&path) {
+            orphans.push((Orphan { path, kind }, segment));
+        }
+    }
+
+    Ok(orphans)
+}
+
+/// Check whether the file at the given path is an orphaned segment, or is linked to one.
+fn inspect(path: &Path) -> Option<(Option<ObjectKind>, Segment)> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+
+    if len >= std::mem::size_of::<Header>() as u64 {
+        let mut header = MaybeUninit::<Header>::zeroed();
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                header.as_mut_ptr() as *mut u8,
+                std::mem::size_of::<Header>(),
+            )
+        };
+        file.read_exact(bytes).ok()?;
+
+        let header = unsafe { Header::read(header.as_ptr() as *const u8, bytes.len())? };
+        return is_orphaned(header).then_some((header.kind(), Segment::Mapping));
+    }
+
+    if len > MAX_LINK_LEN {
+        return None;
+    }
+    let mut os_id = String::new();
+    file.read_to_string(&mut os_id).ok()?;
+    if !is_os_id(&os_id) {
+        return None;
+    }
+
+    let shm = ShmemConf::new().flink(path).open().ok()?;
+    let header = unsafe { Header::read(shm.as_ptr(), shm.len())? };
+    let kind = header.kind();
+
+    is_orphaned(header).then_some((kind, Segment::Link(shm)))
+}
+
+/// Returns `true` if the segment with the given header is an orphan. Only the kinds of objects
+/// that keep track of their peers can be told apart from ones that are still in use.
+fn is_orphaned(header: &Header) -> bool {
+    header.kind().is_some_and(ObjectKind::tracks_peers) && header.is_orphaned()
+}
+
+/// Returns `true` if the given string looks like the ID of a shared memory mapping.
+fn is_os_id(s: &str) -> bool {
+    s.strip_prefix('/').is_some_and(|name| {
+        !name.is_empty()
+            && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'))
+    })
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Block, BlockOptions, Lifetime, Mutex};
+
+    /// The ID of a process that has already exited.
+    fn dead_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        pid
+    }
+
+    #[test]
+    fn gc_removes_orphans() {
+        let dir = Path::new("/tmp/TEST_GC");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        std::fs::write(dir.join("not_a_segment"), "/shmem_but_not_really").unwrap();
+
+        // Leave segments behind like a crashed process would.
+        for (name, lifetime) in [("orphan", Lifetime::Owner), ("persist", Lifetime::Persist)] {
+            let block: Block<u32> = BlockOptions::new()
+                .lifetime(lifetime)
+                .alloc_with(dir.join(name), 71)
+                .unwrap();
+            std::mem::forget(block);
+
+            let shm = crate::open_shm(dir.join(name)).unwrap();
+            unsafe { &*(shm.as_ptr() as *const Header) }.abandon_to(dead_pid());
+        }
+        let alive: Block<u32> = Block::alloc_with(dir.join("alive"), 72).unwrap();
+
+        // Mutexes don't track the processes that open them, so they're never orphans.
+        let mutex: Mutex<u32> = Mutex::alloc(dir.join("mutex")).unwrap();
+        std::mem::forget(mutex);
+        let mut mutex = crate::open_shm(dir.join("mutex")).unwrap();
+        unsafe { &*(mutex.as_ptr() as *const Header) }.abandon_to(dead_pid());
+
+        let orphans = find_orphans(dir).unwrap();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].path(), dir.join("orphan"));
+        assert_eq!(orphans[0].kind(), Some(ObjectKind::Block));
+        assert!(dir.join("orphan").exists());
+
+        let removed = gc(dir).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(!dir.join("orphan").exists());
+        assert!(dir.join("persist").exists());
+        assert!(dir.join("not_a_segment").exists());
+        assert!(dir.join("mutex").exists());
+        assert_eq!(*alive, 72);
+
+        // The path can be allocated again.
+        let _block: Block<u32> = Block::alloc_with(dir.join("orphan"), 73).unwrap();
+        Block::<u32>::open(dir.join("persist")).unwrap().unlink();
+        mutex.set_owner(true);
+    }
+}
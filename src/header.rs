@@ -0,0 +1,705 @@
+//! Shared Memory Header
+
+
+
+use std::{
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{futex, process, Error, Result, TypeLayout};
+
+
+
+/// The bytes that every segment created by `ration` starts with.
+pub const MAGIC: [u8; 8] = *b"RATION\0\0";
+
+/// The version of the shared memory layout used by this version of `ration`.
+///
+/// This is bumped every time the layout of the [`Header`], or of any object stored after it,
+/// changes in a way that older versions can't understand.
+pub const LAYOUT_VERSION: u32 = 3;
+
+/// The object after the header is still being set up by the process that allocated it.
+const UNINITIALIZED: u32 = 0;
+/// The object after the header is ready to be used.
+const INITIALIZED: u32 = 1;
+
+/// How often [`Header::wait_initialized`] checks that the process initializing the object is
+/// still alive.
+const OWNER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The number of instances that a header can keep track of in its peer table.
+pub const MAX_PEERS: usize = 16;
+
+/// The number of bytes of a type's [layout description](TypeLayout::describe_layout) that a
+/// header keeps.
+const LAYOUT_LEN: usize = 192;
+
+/// The kind of object stored in a shared memory segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ObjectKind {
+    /// A [`Block`](crate::Block).
+    Block = 1,
+    /// An [`Array`](crate::Array).
+    Array = 2,
+    /// A [`Queue`](crate::Queue).
+    Queue = 3,
+    /// A [`Mutex`](crate::Mutex).
+    Mutex = 4,
+    /// A [`RwLock`](crate::RwLock).
+    RwLock = 5,
+    /// A [`SeqBlock`](crate::SeqBlock).
+    SeqBlock = 6,
+    /// A [`Segment`](crate::Segment).
+    Segment = 7,
+}
+
+impl ObjectKind {
+    pub(crate) fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            1 => Some(Self::Block),
+            2 => Some(Self::Array),
+            3 => Some(Self::Queue),
+            4 => Some(Self::Mutex),
+            5 => Some(Self::RwLock),
+            6 => Some(Self::SeqBlock),
+            7 => Some(Self::Segment),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if objects of this kind record their owner and peers in their header, so
+    /// that it can tell when they've been left behind.
+    pub(crate) fn tracks_peers(self) -> bool {
+        matches!(self, Self::Block | Self::Array)
+    }
+}
+
+/// When a segment's shared memory (and the path linked to it) is removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Lifetime {
+    /// When the owning instance is dropped. The instance that allocated the segment starts off
+    /// as its owner, but ownership can be moved with `set_owner` & `disown`.
+    #[default]
+    Owner = 0,
+    /// When the last instance that has the segment open (in any process) is dropped.
+    LastDetach = 1,
+    /// Never, until some instance calls `unlink`.
+    Persist = 2,
+}
+
+impl Lifetime {
+    fn from_raw(raw: u32) -> Self {
+        match raw {
+            1 => Self::LastDetach,
+            2 => Self::Persist,
+            _ => Self::Owner,
+        }
+    }
+}
+
+/// The header that is placed at the start of every shared memory segment.
+///
+/// It describes the object that follows it well enough for [`Header::validate`] to reject
+/// segments that were created by something else, by an incompatible version of `ration`, or for
+/// a different type.
+#[repr(C)]
+pub struct Header {
+    magic: [u8; 8],
+    version: u32,
+    kind: u32,
+    elem_size: u64,
+    elem_align: u64,
+    type_hash: u64,
+    capacity: u64,
+    /// Set to [`INITIALIZED`] (with release ordering) once the object is ready to be used.
+    state: AtomicU32,
+    lifetime: u32,
+    /// The number of instances (in any process) that have the object open.
+    attached: AtomicU32,
+    /// The ID of the process that owns the object, or zero if nothing does.
+    owner: AtomicU32,
+    /// The instances that have the object open, one per slot.
+    peers: [PeerSlot; MAX_PEERS],
+    /// The start of the element type's layout description (if it has one), padded with nul bytes.
+    layout: [u8; LAYOUT_LEN],
+}
+
+/// A slot in a header's peer table.
+#[repr(C)]
+struct PeerSlot {
+    /// The ID of the process that the instance in this slot lives in, or zero if the slot is free.
+    pid: AtomicU32,
+    _reserved: u32,
+    /// When the instance last signaled that it was alive, in milliseconds since the Unix epoch.
+    heartbeat: AtomicU64,
+}
+
+/// An instance (in some process) that has a segment open.
+///
+/// See [`Block::peers`](crate::Block::peers) and [`Array::peers`](crate::Array::peers).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pid: u32,
+    heartbeat: SystemTime,
+}
+
+impl Peer {
+    /// The ID of the process that the instance lives in.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// When the instance opened the segment, or last called `heartbeat`, whichever came last.
+    pub fn last_heartbeat(&self) -> SystemTime {
+        self.heartbeat
+    }
+
+    /// Returns `true` if the process that the instance lives in is still running.
+    ///
+    /// # Note
+    ///
+    /// Process IDs are recycled by the system, so this can return `true` for a dead process whose
+    /// ID has since been handed to some unrelated process. Peers that heartbeat regularly can be
+    /// checked against [`last_heartbeat`](Peer::last_heartbeat) instead.
+    pub fn is_alive(&self) -> bool {
+        process::is_alive(self.pid)
+    }
+}
+
+impl Header {
+    /// Create the header for an object of the given kind, holding `capacity` elements of type `T`.
+    ///
+    /// The header starts off uninitialized, so [`validate`](Header::validate) rejects it until
+    /// [`mark_initialized`](Header::mark_initialized) is called.
+    pub fn new<T>(kind: ObjectKind, capacity: usize) -> Self {
+        Self {
+            magic: MAGIC,
+            version: LAYOUT_VERSION,
+            kind: kind as u32,
+            elem_size: std::mem::size_of::<T>() as u64,
+            elem_align: std::mem::align_of::<T>() as u64,
+            type_hash: type_fingerprint::<T>(),
+            capacity: capacity as u64,
+            state: AtomicU32::new(UNINITIALIZED),
+            lifetime: Lifetime::Owner as u32,
+            attached: AtomicU32::new(0),
+            // Whatever allocates the object starts off as its owner, until it claims (or releases)
+            // ownership for itself.
+            owner: AtomicU32::new(std::process::id()),
+            peers: std::array::from_fn(|_| PeerSlot {
+                pid: AtomicU32::new(0),
+                _reserved: 0,
+                heartbeat: AtomicU64::new(0),
+            }),
+            layout: [0; LAYOUT_LEN],
+        }
+    }
+
+    /// Identify the element type by its [`TypeLayout`] instead of its name, so that
+    /// [`validate_layout`](Header::validate_layout) can check it.
+    pub fn with_layout<T: TypeLayout>(mut self) -> Self {
+        let layout = T::describe_layout();
+        self.type_hash = T::layout_hash();
+
+        // Keep as much of the description as fits, cutting it off at a character boundary.
+        let mut len = layout.len().min(LAYOUT_LEN);
+        if len < layout.len() {
+            len = (0..=LAYOUT_LEN - 3).rev().find(|&i| layout.is_char_boundary(i)).unwrap_or(0);
+            self.layout[len..len + 3].copy_from_slice(b"...");
+        }
+        self.layout[..len].copy_from_slice(&layout.as_bytes()[..len]);
+
+        self
+    }
+
+    /// The element type's layout description, as far as the header kept it.
+    pub fn layout(&self) -> String {
+        let len = self.layout.iter().position(|&b| b == 0).unwrap_or(LAYOUT_LEN);
+        String::from_utf8_lossy(&self.layout[..len]).into_owned()
+    }
+
+    /// Set when the segment holding this header should be removed.
+    pub fn with_lifetime(mut self, lifetime: Lifetime) -> Self {
+        self.lifetime = lifetime as u32;
+        self
+    }
+
+    /// When the segment holding this header should be removed.
+    pub fn lifetime(&self) -> Lifetime {
+        Lifetime::from_raw(self.lifetime)
+    }
+
+    /// Count another instance as having the object open.
+    pub fn attach(&self) {
+        self.attached.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Stop counting an instance as having the object open.
+    ///
+    /// Returns `true` if it was the last one.
+    pub fn detach(&self) -> bool {
+        self.attached.fetch_sub(1, Ordering::AcqRel) == 1
+    }
+
+    /// Record the calling process as the object's owner.
+    pub fn claim_owner(&self) {
+        self.owner.store(std::process::id(), Ordering::Release);
+    }
+
+    /// Stop recording the calling process as the object's owner, if it is.
+    pub fn release_owner(&self) {
+        let _ = self.owner.compare_exchange(
+            std::process::id(),
+            0,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The ID of the process that owns the object, if any does.
+    pub fn owner(&self) -> Option<u32> {
+        match self.owner.load(Ordering::Acquire) {
+            0 => None,
+            pid => Some(pid),
+        }
+    }
+
+    /// Add an instance in the calling process to the peer table, returning the slot it was given.
+    ///
+    /// Slots held by processes that have exited are reused. Returns `None` if every slot is held
+    /// by a live process.
+    pub fn add_peer(&self) -> Option<usize> {
+        let pid = std::process::id();
+        let claim = |slot: usize, current: u32| {
+            let claimed = self.peers[slot]
+                .pid
+                .compare_exchange(current, pid, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok();
+            if claimed {
+                self.heartbeat(slot);
+            }
+            claimed
+        };
+
+        (0..MAX_PEERS)
+            .find(|&slot| claim(slot, 0))
+            .or_else(|| {
+                (0..MAX_PEERS).find(|&slot| {
+                    let current = self.peers[slot].pid.load(Ordering::Acquire);
+                    current != 0 && !process::is_alive(current) && claim(slot, current)
+                })
+            })
+    }
+
+    /// Remove the instance in the given slot from the peer table.
+    pub fn remove_peer(&self, slot: usize) {
+        self.peers[slot].pid.store(0, Ordering::Release);
+    }
+
+    /// Record that the instance in the given slot is still alive.
+    pub fn heartbeat(&self, slot: usize) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.peers[slot].heartbeat.store(now.as_millis() as u64, Ordering::Release);
+    }
+
+    /// The instances in the peer table.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers
+            .iter()
+            .filter_map(|slot| {
+                let pid = slot.pid.load(Ordering::Acquire);
+                let millis = slot.heartbeat.load(Ordering::Acquire);
+
+                (pid != 0).then(|| Peer {
+                    pid,
+                    heartbeat: SystemTime::UNIX_EPOCH + Duration::from_millis(millis),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns `true` if the process that owns the object is still running.
+    pub fn owner_alive(&self) -> bool {
+        self.owner().is_some_and(process::is_alive)
+    }
+
+    /// Returns `true` if every process in the peer table, other than the calling one, has exited.
+    pub fn is_abandoned(&self) -> bool {
+        let pid = std::process::id();
+
+        self.peers().iter().all(|peer| peer.pid() == pid || !peer.is_alive())
+    }
+
+    /// Returns `true` if nothing is ever going to remove the segment holding this header: it
+    /// doesn't [`Persist`](Lifetime::Persist) on purpose, and neither its owner's process nor any
+    /// process in its peer table is still running.
+    pub fn is_orphaned(&self) -> bool {
+        self.lifetime() != Lifetime::Persist
+            && !self.owner_alive()
+            && self.peers().iter().all(|peer| !peer.is_alive())
+    }
+
+    /// Pretend that every instance in the calling process (including the owner) was left behind
+    /// by the given process, which exited without dropping them.
+    #[cfg(test)]
+    pub fn abandon_to(&self, pid: u32) {
+        let own = std::process::id();
+        let _ = self.owner.compare_exchange(own, pid, Ordering::AcqRel, Ordering::Relaxed);
+        for slot in &self.peers {
+            let _ = slot.pid.compare_exchange(own, pid, Ordering::AcqRel, Ordering::Relaxed);
+        }
+    }
+
+    /// The error for opening the object after this header before it's been initialized.
+    fn uninitialized(&self) -> Error {
+        // Until the object is initialized, its owner is the process that allocated it.
+        if self.owner().is_some() && !self.owner_alive() {
+            Error::Closed
+        } else {
+            Error::Uninitialized
+        }
+    }
+
+    /// Mark the object after this header as ready to be used.
+    ///
+    /// Everything written to the object before this call is visible to anyone who validates the
+    /// header afterwards.
+    pub fn mark_initialized(&self) {
+        self.state.store(INITIALIZED, Ordering::Release);
+        futex::wake_all(&self.state);
+    }
+
+    /// Wait until the object after the header at the start of a mapping of `len` bytes has been
+    /// initialized, or until the deadline has passed.
+    ///
+    /// Headers that weren't written by this version of `ration` aren't waited on, and are left for
+    /// [`validate`](Header::validate) to reject.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`validate`](Header::validate).
+    pub unsafe fn wait_initialized(
+        ptr: *const u8,
+        len: usize,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        if len < std::mem::size_of::<Self>() {
+            return Ok(());
+        }
+
+        let header = unsafe { &*(ptr as *const Self) };
+        loop {
+            let state = header.state.load(Ordering::Acquire);
+            if state == INITIALIZED {
+                return Ok(());
+            }
+            // Segments start off zeroed, so a header that hasn't been written yet has no magic.
+            if header.magic != [0; 8]
+                && (header.magic != MAGIC || header.version != LAYOUT_VERSION)
+            {
+                return Ok(());
+            }
+
+            if let err @ Error::Closed = header.uninitialized() {
+                return Err(err);
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => timeout.min(OWNER_POLL_INTERVAL),
+                    _ => return Err(Error::Timeout),
+                },
+                None => OWNER_POLL_INTERVAL,
+            };
+            // Nothing wakes us up if the owner dies, so check on it every so often.
+            futex::wait(&header.state, state, Some(timeout));
+        }
+    }
+
+    /// Read the header at the start of a mapping of `len` bytes, whatever object it describes.
+    ///
+    /// Returns `None` if the mapping doesn't start with a header written by this version of
+    /// `ration`.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`validate`](Header::validate).
+    pub unsafe fn read<'a>(ptr: *const u8, len: usize) -> Option<&'a Self> {
+        if len < std::mem::size_of::<Self>() {
+            return None;
+        }
+
+        let header = unsafe { &*(ptr as *const Self) };
+        (header.magic == MAGIC && header.version == LAYOUT_VERSION).then_some(header)
+    }
+
+    /// Read the header at the start of a mapping of `len` bytes, and make sure it describes an
+    /// object of the given kind holding elements of type `T`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to the start of a readable mapping that is at least `len` bytes long, and
+    /// is aligned for a `Header`.
+    pub unsafe fn validate<'a, T>(
+        ptr: *const u8,
+        len: usize,
+        kind: ObjectKind,
+    ) -> Result<&'a Self> {
+        let header = unsafe { Self::validate_kind(ptr, len, kind)? };
+        if header.elem_size != std::mem::size_of::<T>() as u64
+            || header.elem_align != std::mem::align_of::<T>() as u64
+            || header.type_hash != type_fingerprint::<T>()
+        {
+            return Err(Error::TypeMismatch);
+        }
+
+        Ok(header)
+    }
+
+    /// Like [`validate`](Header::validate), but check the element type against the layout that the
+    /// header was created [`with_layout`](Header::with_layout).
+    ///
+    /// # Safety
+    ///
+    /// Same as [`validate`](Header::validate).
+    pub unsafe fn validate_layout<'a, T: TypeLayout>(
+        ptr: *const u8,
+        len: usize,
+        kind: ObjectKind,
+    ) -> Result<&'a Self> {
+        let header = unsafe { Self::validate_kind(ptr, len, kind)? };
+        if header.elem_size != std::mem::size_of::<T>() as u64
+            || header.elem_align != std::mem::align_of::<T>() as u64
+            || header.type_hash != T::layout_hash()
+        {
+            return Err(Error::LayoutMismatch {
+                expected: T::describe_layout(),
+                found: header.layout(),
+            });
+        }
+
+        Ok(header)
+    }
+
+    /// Everything [`validate`](Header::validate) checks, except for the element type.
+    unsafe fn validate_kind<'a>(ptr: *const u8, len: usize, kind: ObjectKind) -> Result<&'a Self> {
+        if len < std::mem::size_of::<Self>() {
+            return Err(Error::InvalidBlockSize {
+                expected: std::mem::size_of::<Self>(),
+                found: len,
+            });
+        }
+
+        let header = unsafe { &*(ptr as *const Self) };
+        // Segments start off zeroed, so a header that hasn't been written yet has no magic.
+        if header.magic == [0; 8] {
+            return Err(Error::Uninitialized);
+        }
+        if header.magic != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        if header.version != LAYOUT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                found: header.version,
+            });
+        }
+        if header.state.load(Ordering::Acquire) != INITIALIZED {
+            return Err(header.uninitialized());
+        }
+        if ObjectKind::from_raw(header.kind) != Some(kind) {
+            return Err(Error::KindMismatch {
+                expected: kind,
+                found: header.kind,
+            });
+        }
+
+        Ok(header)
+    }
+
+    /// The kind of object following this header, if it's one this version of `ration` knows of.
+    pub fn kind(&self) -> Option<ObjectKind> {
+        ObjectKind::from_raw(self.kind)
+    }
+
+    /// The number of elements the object following this header was allocated with.
+    pub fn capacity(&self) -> usize {
+        self.capacity as usize
+    }
+
+    /// The offset from the start of the segment at which a value of type `T` can be placed after
+    /// the header.
+    pub const fn data_offset<T>() -> usize {
+        std::mem::size_of::<Self>().next_multiple_of(std::mem::align_of::<T>())
+    }
+}
+
+/// A fingerprint for the type `T`, built from its name, size, and alignment.
+///
+/// # Note
+///
+/// Type names are not guaranteed to be the same across compiler versions, so segments should only
+/// be shared between binaries built with the same toolchain.
+pub fn type_fingerprint<T>() -> u64 {
+    // 64-bit FNV-1a.
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let size = std::mem::size_of::<T>() as u64;
+    let align = std::mem::align_of::<T>() as u64;
+
+    std::any::type_name::<T>()
+        .bytes()
+        .chain(size.to_le_bytes())
+        .chain(align.to_le_bytes())
+        .fold(OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_validates_own_type() {
+        let header = Header::new::<u64>(ObjectKind::Array, 8);
+        header.mark_initialized();
+        let ptr = &header as *const Header as *const u8;
+        let len = std::mem::size_of::<Header>();
+
+        let read = unsafe { Header::validate::<u64>(ptr, len, ObjectKind::Array) }.unwrap();
+        assert_eq!(read.capacity(), 8);
+    }
+
+    #[test]
+    fn header_rejects_mismatches() {
+        let header = Header::new::<u64>(ObjectKind::Array, 8);
+        header.mark_initialized();
+        let ptr = &header as *const Header as *const u8;
+        let len = std::mem::size_of::<Header>();
+
+        assert!(matches!(
+            unsafe { Header::validate::<u64>(ptr, len, ObjectKind::Block) },
+            Err(Error::KindMismatch { expected: ObjectKind::Block, .. }),
+        ));
+        assert!(matches!(
+            unsafe { Header::validate::<i64>(ptr, len, ObjectKind::Array) },
+            Err(Error::TypeMismatch),
+        ));
+        assert!(matches!(
+            unsafe { Header::validate::<u64>(ptr, len - 1, ObjectKind::Array) },
+            Err(Error::InvalidBlockSize { found, .. }) if found == len - 1,
+        ));
+
+        let garbage = [0xabab_abab_abab_abab_u64; std::mem::size_of::<Header>() / 8];
+        let ptr = garbage.as_ptr() as *const u8;
+        assert!(matches!(
+            unsafe { Header::validate::<u64>(ptr, len, ObjectKind::Array) },
+            Err(Error::InvalidMagic),
+        ));
+    }
+
+    #[test]
+    fn header_rejects_uninitialized() {
+        let zeroed = [0_u64; std::mem::size_of::<Header>() / 8];
+        let ptr = zeroed.as_ptr() as *const u8;
+        let len = std::mem::size_of::<Header>();
+        assert!(matches!(
+            unsafe { Header::validate::<u64>(ptr, len, ObjectKind::Array) },
+            Err(Error::Uninitialized),
+        ));
+
+        let header = Header::new::<u64>(ObjectKind::Array, 8);
+        let ptr = &header as *const Header as *const u8;
+        assert!(matches!(
+            unsafe { Header::validate::<u64>(ptr, len, ObjectKind::Array) },
+            Err(Error::Uninitialized),
+        ));
+
+        header.mark_initialized();
+        assert!(unsafe { Header::validate::<u64>(ptr, len, ObjectKind::Array) }.is_ok());
+    }
+
+    #[test]
+    fn header_peer_table() {
+        let header = Header::new::<u64>(ObjectKind::Block, 1);
+        assert!(header.peers().is_empty());
+        assert_eq!(header.owner(), Some(std::process::id()));
+
+        let slots: Vec<_> = (0..MAX_PEERS).map(|_| header.add_peer().unwrap()).collect();
+        let peers = header.peers();
+        assert_eq!(peers.len(), MAX_PEERS);
+        assert!(peers.iter().all(|peer| peer.pid() == std::process::id() && peer.is_alive()));
+
+        // Every slot is held by a live process.
+        assert_eq!(header.add_peer(), None);
+        header.remove_peer(slots[3]);
+        assert_eq!(header.add_peer(), Some(slots[3]));
+
+        header.release_owner();
+        assert_eq!(header.owner(), None);
+        header.claim_owner();
+        assert_eq!(header.owner(), Some(std::process::id()));
+    }
+
+    #[test]
+    fn header_detects_dead_peers() {
+        // A process that has already exited (and been reaped).
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+
+        let header = Header::new::<u64>(ObjectKind::Block, 1);
+        header.add_peer().unwrap();
+        assert!(header.is_abandoned());
+        assert!(!header.is_orphaned());
+
+        header.owner.store(dead, Ordering::Release);
+        header.peers[1].pid.store(dead, Ordering::Release);
+        assert!(!header.owner_alive());
+        assert!(header.is_abandoned());
+        assert!(!header.is_orphaned());
+
+        // A live process in the table means it isn't abandoned.
+        header.peers[2].pid.store(std::os::unix::process::parent_id(), Ordering::Release);
+        assert!(!header.is_abandoned());
+        assert!(!header.is_orphaned());
+
+        header.remove_peer(0);
+        header.remove_peer(2);
+        assert!(header.is_orphaned());
+        assert!(!header.with_lifetime(Lifetime::Persist).is_orphaned());
+    }
+
+    #[test]
+    fn header_checks_layout() {
+        let header = Header::new::<(u32, f32)>(ObjectKind::Block, 1).with_layout::<(u32, f32)>();
+        header.mark_initialized();
+        let ptr = &header as *const Header as *const u8;
+        let len = std::mem::size_of::<Header>();
+
+        let valid = unsafe { Header::validate_layout::<(u32, f32)>(ptr, len, ObjectKind::Block) };
+        assert!(valid.is_ok());
+        match unsafe { Header::validate_layout::<(f32, u32)>(ptr, len, ObjectKind::Block) } {
+            Err(Error::LayoutMismatch { expected, found }) => {
+                assert_eq!(expected, "(f32, u32)");
+                assert_eq!(found, "(u32, f32)");
+            }
+            other => panic!("expected a layout mismatch, got {:?}", other.map(|_| ())),
+        }
+
+        // Long descriptions are cut short.
+        type Eight = (u64, u64, u64, u64, u64, u64, u64, u64);
+        type Long = (Eight, Eight, Eight, Eight, Eight);
+        let header = Header::new::<Long>(ObjectKind::Block, 1).with_layout::<Long>();
+        assert!(Long::describe_layout().len() > LAYOUT_LEN);
+        assert_eq!(header.layout().len(), LAYOUT_LEN);
+        assert!(header.layout().ends_with("..."));
+    }
+}
@@ -0,0 +1,341 @@
+//! Type Layouts
+
+
+
+use std::{
+    cell::{Cell, RefCell, UnsafeCell},
+    marker::PhantomData,
+    mem::MaybeUninit,
+    num::{
+        NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize, NonZeroU128,
+        NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize, Wrapping,
+    },
+    sync::atomic::{
+        AtomicBool, AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize, AtomicU16, AtomicU32,
+        AtomicU64, AtomicU8, AtomicUsize,
+    },
+};
+
+use crate::{OffsetPtr, RelPtr};
+
+
+
+/// Types that can describe their own layout: the names, offsets, and types of their fields, all
+/// the way down.
+///
+/// [`Block`](crate::Block) and [`Array`](crate::Array) store a hash of this description (and as
+/// much of the description as fits) in their segment's header when they're allocated, and check it
+/// when they're opened. So a segment written as `struct A { x: u32, y: f32 }` can't be read as
+/// `struct B { y: f32, x: u32 }`, even though they're the same size, and the
+/// [`LayoutMismatch`](crate::Error::LayoutMismatch) error says what's different.
+///
+/// # Deriving
+///
+/// `TypeLayout` can be derived for structs and enums whose fields are all `TypeLayout`:
+/// ```
+/// use ration::TypeLayout;
+///
+/// #[derive(TypeLayout)]
+/// #[repr(C)]
+/// struct Position {
+///     x: f32,
+///     y: f32,
+/// }
+///
+/// assert_eq!(Position::describe_layout(), "Position { x: f32 @ 0, y: f32 @ 4 }");
+/// ```
+pub trait TypeLayout {
+    /// A description of the type's layout, which is the same in every process (and every build)
+    /// as long as the type's definition is.
+    fn describe_layout() -> String;
+
+    /// A hash of the type's [`describe_layout`](TypeLayout::describe_layout).
+    fn layout_hash() -> u64 {
+        // 64-bit FNV-1a.
+        const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+
+        Self::describe_layout()
+            .bytes()
+            .fold(OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+    }
+}
+
+macro_rules! impl_type_layout {
+    ($($ty:ident),* $(,)?) => {
+        $(impl TypeLayout for $ty {
+            fn describe_layout() -> String {
+                stringify!($ty).to_string()
+            }
+        })*
+    };
+}
+
+impl_type_layout!(
+    bool, char, f32, f64,
+    u8, u16, u32, u64, u128, usize,
+    i8, i16, i32, i64, i128, isize,
+    NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU128, NonZeroUsize,
+    NonZeroI8, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI128, NonZeroIsize,
+    AtomicBool, AtomicU8, AtomicU16, AtomicU32, AtomicU64, AtomicUsize,
+    AtomicI8, AtomicI16, AtomicI32, AtomicI64, AtomicIsize,
+);
+
+impl TypeLayout for () {
+    fn describe_layout() -> String {
+        "()".to_string()
+    }
+}
+
+impl<T: TypeLayout, const N: usize> TypeLayout for [T; N] {
+    fn describe_layout() -> String {
+        format!("[{}; {N}]", T::describe_layout())
+    }
+}
+
+macro_rules! impl_type_layout_wrapper {
+    ($($wrapper:ident),* $(,)?) => {
+        $(impl<T: TypeLayout> TypeLayout for $wrapper<T> {
+            fn describe_layout() -> String {
+                format!(concat!(stringify!($wrapper), "<{}>"), T::describe_layout())
+            }
+        })*
+    };
+}
+
+impl_type_layout_wrapper!(Option, MaybeUninit, Wrapping, Cell, UnsafeCell);
+
+// The layout of this doesn't depend on its type at all, so it doesn't describe it.
+impl<T: ?Sized> TypeLayout for PhantomData<T> {
+    fn describe_layout() -> String {
+        "PhantomData".to_string()
+    }
+}
+
+impl<T: TypeLayout> TypeLayout for OffsetPtr<T> {
+    fn describe_layout() -> String {
+        format!("OffsetPtr<{}>", describe_pointee::<T>())
+    }
+}
+
+impl<T: TypeLayout> TypeLayout for RelPtr<T> {
+    fn describe_layout() -> String {
+        format!("RelPtr<{}>", describe_pointee::<T>())
+    }
+}
+
+thread_local! {
+    /// The names of the derived types that are being described on this thread, innermost last.
+    static DESCRIBING: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Describe the layout of `T` with `describe`, keeping track of it while that runs. This wraps
+/// every derived [`TypeLayout::describe_layout`].
+#[doc(hidden)]
+pub fn describe_tracked<T: ?Sized>(describe: impl FnOnce() -> String) -> String {
+    struct Untrack;
+
+    impl Drop for Untrack {
+        fn drop(&mut self) {
+            DESCRIBING.with_borrow_mut(|describing| describing.pop());
+        }
+    }
+
+    DESCRIBING.with_borrow_mut(|describing| describing.push(std::any::type_name::<T>()));
+    let _untrack = Untrack;
+
+    describe()
+}
+
+/// Describe the target of a pointer.
+///
+/// Pointers often point at a type that holds them (like the next node of a linked list), which
+/// would make describing their targets recurse forever. A target that's already being described
+/// is written as `^n` instead, where `n` counts how many types further out it is.
+fn describe_pointee<T: TypeLayout>() -> String {
+    let name = std::any::type_name::<T>();
+    let depth = DESCRIBING.with_borrow(|describing| {
+        describing.iter().rev().position(|describing| *describing == name)
+    });
+
+    match depth {
+        Some(depth) => format!("^{depth}"),
+        None => T::describe_layout(),
+    }
+}
+
+macro_rules! impl_type_layout_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: TypeLayout),+> TypeLayout for ($($name,)+) {
+            fn describe_layout() -> String {
+                let elements = [$($name::describe_layout()),+];
+                if elements.len() == 1 {
+                    format!("({},)", elements[0])
+                } else {
+                    format!("({})", elements.join(", "))
+                }
+            }
+        }
+    };
+}
+
+impl_type_layout_tuple!(A);
+impl_type_layout_tuple!(A, B);
+impl_type_layout_tuple!(A, B, C);
+impl_type_layout_tuple!(A, B, C, D);
+impl_type_layout_tuple!(A, B, C, D, E);
+impl_type_layout_tuple!(A, B, C, D, E, F);
+impl_type_layout_tuple!(A, B, C, D, E, F, G);
+impl_type_layout_tuple!(A, B, C, D, E, F, G, H);
+
+#[cfg(feature = "arrayvec")]
+impl<const CAP: usize> TypeLayout for arrayvec::ArrayString<CAP> {
+    fn describe_layout() -> String {
+        format!("ArrayString<{CAP}>")
+    }
+}
+
+#[cfg(feature = "arrayvec")]
+impl<T: TypeLayout, const CAP: usize> TypeLayout for arrayvec::ArrayVec<T, CAP> {
+    fn describe_layout() -> String {
+        format!("ArrayVec<{}, {CAP}>", T::describe_layout())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TypeLayout;
+
+    #[derive(TypeLayout)]
+    #[repr(C)]
+    struct A {
+        x: u32,
+        y: f32,
+    }
+
+    #[derive(TypeLayout)]
+    #[repr(C)]
+    struct B {
+        y: f32,
+        x: u32,
+    }
+
+    #[derive(TypeLayout)]
+    #[repr(C)]
+    struct Nested<T> {
+        inner: [A; 2],
+        pair: (u8, T),
+        next: RelPtr<Nested<T>>,
+    }
+
+    #[derive(TypeLayout)]
+    #[repr(C)]
+    struct Even {
+        next: OffsetPtr<Odd>,
+    }
+
+    #[derive(TypeLayout)]
+    #[repr(C)]
+    struct Odd {
+        next: OffsetPtr<Even>,
+    }
+
+    #[derive(TypeLayout)]
+    #[repr(u8)]
+    #[allow(dead_code)]
+    enum Shape {
+        Empty,
+        Circle(f32),
+        Rect { w: f32, h: f32 },
+    }
+
+    #[derive(TypeLayout)]
+    #[repr(C, u16)]
+    #[allow(dead_code)]
+    enum CShape {
+        Empty,
+        Line(u8, u64) = 5,
+        Dot { x: u16 },
+    }
+
+    #[derive(TypeLayout)]
+    #[repr(u8)]
+    #[allow(dead_code)]
+    enum Before {
+        A = 1,
+        B = 2,
+    }
+
+    #[derive(TypeLayout)]
+    #[repr(u8)]
+    #[allow(dead_code)]
+    enum After {
+        A = 2,
+        B = 1,
+    }
+
+    #[derive(TypeLayout)]
+    #[repr(i8)]
+    #[allow(dead_code)]
+    enum Signed {
+        A = 1,
+        B = 2,
+    }
+
+    #[derive(TypeLayout)]
+    #[allow(dead_code)]
+    enum Plain {
+        A,
+        B(u32),
+    }
+
+    #[test]
+    fn layout_describes_fields() {
+        assert_eq!(A::describe_layout(), "A { x: u32 @ 0, y: f32 @ 4 }");
+        assert_ne!(A::layout_hash(), B::layout_hash());
+
+        assert_eq!(
+            Nested::<u16>::describe_layout(),
+            "Nested { inner: [A { x: u32 @ 0, y: f32 @ 4 }; 2] @ 0, pair: (u8, u16) @ 16, \
+             next: RelPtr<^0> @ 24 }",
+        );
+        assert_ne!(Nested::<u16>::layout_hash(), Nested::<i16>::layout_hash());
+
+        assert_eq!(OffsetPtr::<A>::describe_layout(), "OffsetPtr<A { x: u32 @ 0, y: f32 @ 4 }>");
+        assert_ne!(OffsetPtr::<u64>::layout_hash(), OffsetPtr::<u32>::layout_hash());
+        assert_eq!(
+            Even::describe_layout(),
+            "Even { next: OffsetPtr<Odd { next: OffsetPtr<^1> @ 0 }> @ 0 }",
+        );
+
+        assert_eq!(
+            Shape::describe_layout(),
+            "repr(u8) Shape { Empty = 0, Circle(f32 @ 4) = 1, \
+             Rect { w: f32 @ 4, h: f32 @ 8 } = 2 }",
+        );
+        assert_eq!(
+            CShape::describe_layout(),
+            "repr(C, u16) CShape { Empty = 0, Line(u8 @ 8, u64 @ 16) = 5, Dot { x: u16 @ 8 } = 6 }",
+        );
+    }
+
+    #[test]
+    fn layout_describes_enums() {
+        // Discriminants and their types are part of an enum's layout.
+        assert_eq!(Before::describe_layout(), "repr(u8) Before { A = 1, B = 2 }");
+        assert_ne!(Before::layout_hash(), After::layout_hash());
+        assert_ne!(Before::layout_hash(), Signed::layout_hash());
+        assert_eq!(Plain::describe_layout(), "Plain { A = 0, B(u32) = 1 }");
+
+        // The offsets match where the fields really are.
+        let shape = Shape::Rect { w: 1.0, h: 2.0 };
+        let Shape::Rect { h, .. } = &shape else { unreachable!() };
+        assert_eq!(h as *const f32 as usize - &shape as *const Shape as usize, 8);
+        let shape = CShape::Line(1, 2);
+        let CShape::Line(_, second) = &shape else { unreachable!() };
+        assert_eq!(second as *const u64 as usize - &shape as *const CShape as usize, 16);
+    }
+}
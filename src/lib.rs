@@ -10,14 +10,68 @@
 //!
 //! If the path you provide to `alloc` or `open` for some data type, `ration` will attempt to
 //! place your data structure into `/dev/shm/`.
+//!
+//! # Segment layout
+//!
+//! Every segment allocated by `ration` starts with a small header (see [`MAGIC`] and
+//! [`LAYOUT_VERSION`]) that records what kind of object it holds, and for which type. Opening a
+//! segment validates that header, so opening an [`Array`] as a [`Block`], or a `Block<u32>` as a
+//! `Block<i32>`, fails with an [`Error`] instead of reinterpreting the memory.
+//!
+//! A [`Segment`] holds many named objects in one mapping, each laid out just like it would be in a
+//! segment of its own.
+
 
 
+// Lets the derive macros refer to `::ration` from inside this crate too.
+extern crate self as ration;
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
 pub mod array;
+pub mod backend;
 pub mod block;
+mod futex;
+mod gc;
+mod header;
+mod layout;
+pub mod mutex;
+mod process;
+pub mod queue;
+mod region;
+pub mod relptr;
+pub mod segment;
+pub mod rwlock;
+pub mod seqblock;
+mod shm_safe;
+mod watch;
 
 pub use array::*;
+pub use backend::*;
 pub use block::*;
+pub use gc::{find_orphans, gc, Orphan};
+pub use mutex::*;
+pub use queue::*;
+pub use relptr::*;
+pub use segment::*;
+pub use rwlock::*;
+pub use seqblock::*;
+
+#[cfg(feature = "allocator")]
+pub use ration_allocator::{SharedAllocator, SharedBox, SharedVec};
+pub use header::{Lifetime, ObjectKind, Peer, LAYOUT_VERSION, MAGIC, MAX_PEERS};
+pub use shm_safe::ShmSafe;
+pub use layout::TypeLayout;
+#[doc(hidden)]
+pub use layout::describe_tracked;
+/// Derive [`ShmSafe`](trait@ShmSafe) for a `#[repr(C)]` struct or enum whose fields are all
+/// `ShmSafe`.
+pub use ration_derive::ShmSafe;
+/// Derive [`TypeLayout`](trait@TypeLayout) for a struct or enum whose fields are all `TypeLayout`.
+pub use ration_derive::TypeLayout;
 
 
 
@@ -27,16 +81,218 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     /// Generic shared memory error.
     Shm(shared_memory::ShmemError),
-    /// Expected a different block size from the one that was given.
-    InvalidBlockSize,
+    /// Nothing has been allocated at the given path (or the segment linked to it is gone).
+    NotFound {
+        path: PathBuf,
+    },
+    /// The calling process isn't allowed to create (or open) the segment at the given path.
+    PermissionDenied {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The segment is smaller than the object that it was opened as.
+    InvalidBlockSize {
+        expected: usize,
+        found: usize,
+    },
     /// Attempted to allocated a block that has already been allocated.
     BlockAlreadyAllocated,
+    /// The segment doesn't start with the [`MAGIC`] bytes, so it wasn't created by `ration`.
+    InvalidMagic,
+    /// The segment was created with a different [`LAYOUT_VERSION`] than this one.
+    UnsupportedVersion {
+        found: u32,
+    },
+    /// The segment holds a different kind of object than the one it was opened as.
+    KindMismatch {
+        expected: ObjectKind,
+        found: u32,
+    },
+    /// The segment holds elements of a different type than the one it was opened with.
+    TypeMismatch,
+    /// The segment holds elements with a different [`TypeLayout`](trait@TypeLayout) than the type
+    /// it was opened with. Both layouts are described (though the one found in the segment may
+    /// have been cut short).
+    LayoutMismatch {
+        expected: String,
+        found: String,
+    },
+    /// The segment is still being set up by the process that allocated it.
+    Uninitialized,
+    /// The process that allocated the segment exited before it finished setting it up, so it's
+    /// never going to be initialized. [`gc`] removes segments like these.
+    Closed,
+    /// Waiting for something (like a segment to be allocated) took longer than the given timeout.
+    Timeout,
+    /// The name of an object in a [`Segment`] is empty, too long, or contains a nul byte.
+    InvalidName,
+    /// A [`Segment`] of `capacity` bytes doesn't have enough room left for another object.
+    Full {
+        capacity: usize,
+    },
+    /// An object can't hold `capacity` elements, because they wouldn't fit in memory (or because an
+    /// [`Array`] can't have a capacity of zero).
+    InvalidCapacity {
+        capacity: usize,
+    },
+    /// Reading (or removing) a file failed.
+    Io(std::io::Error),
 }
 
+impl Error {
+    /// Convert an I/O error raised for the given path, picking out the kinds that callers are
+    /// likely to handle.
+    pub(crate) fn from_io(source: std::io::Error, path: &Path) -> Self {
+        match source.kind() {
+            std::io::ErrorKind::NotFound => Error::NotFound {
+                path: path.to_path_buf(),
+            },
+            std::io::ErrorKind::PermissionDenied => Error::PermissionDenied {
+                path: path.to_path_buf(),
+                source,
+            },
+            std::io::ErrorKind::AlreadyExists => Error::BlockAlreadyAllocated,
+            _ => Error::Io(source),
+        }
+    }
+
+    /// Convert an error from the `shared_memory` crate (raised for the given path), picking out the
+    /// ones that callers are likely to handle.
+    fn from_shm(e: shared_memory::ShmemError, path: &Path) -> Self {
+        use shared_memory::ShmemError;
+
+        let source = match &e {
+            ShmemError::LinkExists => return Error::BlockAlreadyAllocated,
+            ShmemError::LinkDoesNotExist => std::io::ErrorKind::NotFound.into(),
+            ShmemError::LinkCreateFailed(source) | ShmemError::LinkOpenFailed(source) => {
+                match source.raw_os_error() {
+                    Some(errno) => std::io::Error::from_raw_os_error(errno),
+                    None => source.kind().into(),
+                }
+            }
+            // When the link is still there, but the mapping it points to has been removed, this is
+            // `ENOENT` too.
+            ShmemError::MapCreateFailed(errno) | ShmemError::MapOpenFailed(errno) => {
+                std::io::Error::from_raw_os_error(*errno as i32)
+            }
+            _ => return Error::Shm(e),
+        };
+        match Self::from_io(source, path) {
+            Error::Io(_) => Error::Shm(e),
+            error => error,
+        }
+    }
+}
+
+
+
+/// Create a new shared memory mapping of `size` bytes, linked to the given path.
+pub(crate) fn create_shm(path: impl AsRef<Path>, size: usize) -> Result<shared_memory::Shmem> {
+    let path = path.as_ref();
+    shared_memory::ShmemConf::new()
+        .flink(path)
+        .size(size)
+        .create()
+        .map_err(|e| Error::from_shm(e, path))
+}
+
+/// Open the shared memory mapping linked to the given path.
+pub(crate) fn open_shm(path: impl AsRef<Path>) -> Result<shared_memory::Shmem> {
+    let path = path.as_ref();
+    shared_memory::ShmemConf::new()
+        .flink(path)
+        .open()
+        .map_err(|e| Error::from_shm(e, path))
+}
+
+/// Open the memory identified by the given path with the given backend, waiting for it to be
+/// allocated and for the object in it to be initialized first (for as long as `timeout`, if given).
+pub(crate) fn open_wait(
+    backend: &dyn Backend,
+    path: &Path,
+    timeout: Option<Duration>,
+) -> Result<Box<dyn Memory>> {
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    // Start watching before the first attempt, so that nothing linked after it is missed.
+    let watch = watch::Watch::new(backend.watch_path(path).as_deref());
+    loop {
+        match backend.open(path) {
+            Ok(memory) => {
+                unsafe {
+                    header::Header::wait_initialized(memory.as_ptr(), memory.len(), deadline)?
+                };
+                return Ok(memory);
+            }
+            // Nothing has been linked to the path yet, or the link hasn't been written yet.
+            Err(Error::NotFound { .. } | Error::Shm(_)) => {
+                if !watch.wait(deadline) {
+                    return Err(Error::Timeout);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        match self {
+            Error::Shm(e) => write!(f, "shared memory error: {e}"),
+            Error::NotFound { path } => {
+                write!(f, "nothing has been allocated at {}", path.display())
+            }
+            Error::PermissionDenied { path, .. } => {
+                write!(f, "permission denied for the segment at {}", path.display())
+            }
+            Error::InvalidBlockSize { expected, found } => write!(
+                f,
+                "segment is too small: expected at least {expected} bytes, found {found}",
+            ),
+            Error::BlockAlreadyAllocated => f.write_str("block has already been allocated"),
+            Error::InvalidMagic => f.write_str("segment wasn't created by ration"),
+            Error::UnsupportedVersion { found } => write!(
+                f,
+                "segment has layout version {found}, but this version of ration only supports \
+                 {LAYOUT_VERSION}",
+            ),
+            Error::KindMismatch { expected, found } => match ObjectKind::from_raw(*found) {
+                Some(found) => write!(f, "segment holds a {found:?} object, expected {expected:?}"),
+                None => write!(f, "segment holds an unknown kind of object ({found}), expected \
+                                   {expected:?}"),
+            },
+            Error::TypeMismatch => f.write_str("segment holds elements of a different type"),
+            Error::LayoutMismatch { expected, found } => write!(
+                f,
+                "segment holds elements of a different type: expected `{expected}`, found \
+                 `{found}`",
+            ),
+            Error::Uninitialized => f.write_str("segment hasn't been initialized yet"),
+            Error::Closed => {
+                f.write_str("segment was abandoned by its owner before being initialized")
+            }
+            Error::Timeout => f.write_str("timed out"),
+            Error::InvalidName => {
+                f.write_str("object names must be non-empty, short, and nul-free")
+            }
+            Error::Full { capacity } => {
+                write!(f, "segment of {capacity} bytes has no room left for another object")
+            }
+            Error::InvalidCapacity { capacity } => {
+                write!(f, "a capacity of {capacity} elements isn't supported")
+            }
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+        }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Shm(e) => Some(e),
+            Error::PermissionDenied { source: e, .. } | Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
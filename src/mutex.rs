@@ -0,0 +1,545 @@
+//! Shared Memory Mutex
+
+
+
+use std::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    futex,
+    header::{Header, ObjectKind},
+    process,
+    Backend,
+    Error,
+    Memory,
+    Result,
+    ShmemBackend,
+    ShmSafe,
+};
+
+
+
+/// The lock isn't held by anyone. Otherwise, the lock word holds the ID of the thread holding it.
+const UNLOCKED: u32 = 0;
+/// Set in the lock word when somebody may be waiting for the lock.
+const CONTENDED: u32 = 1 << 31;
+/// The bits of the lock word that hold the ID of the thread holding the lock.
+const HOLDER_MASK: u32 = !CONTENDED;
+
+/// How often waiters check whether the thread holding the lock is still alive.
+const PROBE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A typed, shared block of memory that can only be accessed by one process (or thread) at a
+/// time.
+///
+/// The lock lives in shared memory alongside the data it protects, and waiting for it parks the
+/// waiter on a futex, so it works across processes the same way [`std::sync::Mutex`] does across
+/// threads.
+///
+/// # Owner death
+///
+/// The lock records the ID of the thread holding it. If that thread's process dies without
+/// releasing the lock, the next call to [`lock`](Mutex::lock) notices and takes the lock over, but
+/// returns it wrapped in an [`OwnerDied`] error, since the dead process may have left the data
+/// half-modified. The new holder can inspect and repair the data through that error before
+/// continuing.
+///
+/// Whether the holder is alive is checked by its thread ID, which only means something inside one
+/// PID namespace. Every process that shares a mutex has to be in the same one: a holder in another
+/// namespace (like a different container sharing `/dev/shm`) looks dead, and its lock is taken
+/// over while it's still holding it. Thread IDs are also reused by the system, so a lock whose
+/// holder died can look held for as long as some unrelated thread has that holder's ID.
+///
+/// # Example
+/// *In your "parent" process:*
+/// ```no_run
+/// use ration::Mutex;
+///
+/// let mutex: Mutex<u64> = Mutex::alloc("/dev/shm/MY_MUTEX").unwrap();
+/// *mutex.lock().unwrap() = 71;
+/// ```
+/// *In your "child" process:*
+/// ```no_run
+/// use ration::Mutex;
+///
+/// let mutex: Mutex<u64> = Mutex::open("/dev/shm/MY_MUTEX").unwrap();
+/// let mut value = mutex.lock().unwrap();
+/// *value += 1;
+/// println!("MY_MUTEX: {}", *value); // 72
+/// ```
+pub struct Mutex<T: ShmSafe> {
+    memory: Box<dyn Memory>,
+    owner: bool,
+
+    lock: *const AtomicU32,
+    ptr: *mut T,
+}
+
+// SAFETY: Access to the data is synchronized through the lock.
+unsafe impl<T: ShmSafe + Send> Send for Mutex<T> {}
+unsafe impl<T: ShmSafe + Send> Sync for Mutex<T> {}
+
+impl<T: ShmSafe> Mutex<T> {
+    /// Allocate a new shared mutex at the given path, protecting a value of the given type.
+    ///
+    /// Like a [`Block`](crate::Block), the protected value starts off uninitialized, so make sure
+    /// to give it a value before anything else reads it.
+    ///
+    /// To allocate a mutex with a different [`Backend`], use [`MutexOptions`].
+    pub fn alloc(path: impl AsRef<Path>) -> Result<Self> {
+        MutexOptions::new().alloc(path)
+    }
+
+    /// Open a shared mutex identified by the given path and type.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        MutexOptions::new().open(path)
+    }
+
+    /// Create a new mutex in the given memory.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be writable, and at least [`block_size`](Mutex::block_size) bytes long.
+    unsafe fn init(memory: Box<dyn Memory>) -> Self {
+        let shm = memory.as_ptr();
+
+        unsafe {
+            (shm as *mut Header)
+                .write(Header::new::<T>(ObjectKind::Mutex, 1).with_layout::<T>());
+
+            let lock = shm.add(std::mem::size_of::<Header>()) as *mut AtomicU32;
+            let ptr = shm.add(Self::data_offset()) as *mut T;
+
+            lock.write(AtomicU32::new(UNLOCKED));
+
+            (*(shm as *const Header)).mark_initialized();
+
+            Self {
+                memory,
+                owner: true,
+                lock,
+                ptr,
+            }
+        }
+    }
+
+    /// Open the mutex that was created in the given memory.
+    fn attach(memory: Box<dyn Memory>) -> Result<Self> {
+        // Make sure the mutex was allocated by `ration`, and for this type.
+        unsafe {
+            Header::validate_layout::<T>(memory.as_ptr(), memory.len(), ObjectKind::Mutex)?;
+        }
+
+        if memory.len() < Self::block_size() {
+            return Err(Error::InvalidBlockSize {
+                expected: Self::block_size(),
+                found: memory.len(),
+            });
+        }
+
+        unsafe {
+            let lock = memory.as_ptr().add(std::mem::size_of::<Header>()) as *const AtomicU32;
+            let ptr = memory.as_ptr().add(Self::data_offset()) as *mut T;
+
+            Ok(Self {
+                memory,
+                owner: false,
+                lock,
+                ptr,
+            })
+        }
+    }
+
+    fn data_offset() -> usize {
+        (std::mem::size_of::<Header>() + std::mem::size_of::<AtomicU32>())
+            .next_multiple_of(std::mem::align_of::<T>())
+    }
+
+    fn block_size() -> usize {
+        Self::data_offset() + std::mem::size_of::<T>()
+    }
+}
+
+impl<T: ShmSafe> Mutex<T> {
+    /// Returns `true` if the underlying shared memory mapping is owned by this mutex instance.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    fn lock_word(&self) -> &AtomicU32 {
+        unsafe { &*self.lock }
+    }
+
+    /// Acquire the lock, waiting for whoever holds it to release it first.
+    ///
+    /// Returns an [`OwnerDied`] error (which still holds the lock) if the last holder's process
+    /// died while holding it.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let lock = self.lock_word();
+        let tid = process::current_tid();
+
+        if lock.compare_exchange(UNLOCKED, tid, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            return Ok(MutexGuard::new(self));
+        }
+
+        loop {
+            let current = lock.load(Ordering::Relaxed);
+
+            if current == UNLOCKED {
+                // Keep the lock marked as contended when it's acquired this way, since there
+                // might be other waiters.
+                if lock.compare_exchange(
+                    UNLOCKED,
+                    tid | CONTENDED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ).is_ok() {
+                    return Ok(MutexGuard::new(self));
+                }
+                continue;
+            }
+
+            if !process::is_alive(current & HOLDER_MASK) {
+                // The holder died without releasing the lock; take it over.
+                if lock.compare_exchange(
+                    current,
+                    tid | CONTENDED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ).is_ok() {
+                    return Err(OwnerDied { guard: MutexGuard::new(self) });
+                }
+                continue;
+            }
+
+            // Mark the lock as contended so that whoever holds it wakes us up, and then wait for
+            // it to be released. Wake up every so often to make sure the holder is still alive.
+            if current & CONTENDED != 0
+                || lock.compare_exchange(
+                    current,
+                    current | CONTENDED,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ).is_ok()
+            {
+                futex::wait(lock, current | CONTENDED, Some(PROBE_INTERVAL));
+            }
+        }
+    }
+
+    /// Try to acquire the lock without waiting.
+    ///
+    /// Returns `None` if the lock is currently held by a live thread, and an [`OwnerDied`] error
+    /// (which still holds the lock) if the last holder's process died while holding it.
+    pub fn try_lock(&self) -> Option<LockResult<MutexGuard<'_, T>>> {
+        if let Some(guard) = self.try_lock_unchecked() {
+            return Some(Ok(guard));
+        }
+
+        let lock = self.lock_word();
+        let current = lock.load(Ordering::Relaxed);
+        if current != UNLOCKED && !process::is_alive(current & HOLDER_MASK) {
+            let tid = process::current_tid();
+            if lock.compare_exchange(
+                current,
+                tid | (current & CONTENDED),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ).is_ok() {
+                return Some(Err(OwnerDied { guard: MutexGuard::new(self) }));
+            }
+        }
+
+        None
+    }
+
+    /// Try to acquire the lock if nobody is holding it, without checking whether a holder died.
+    fn try_lock_unchecked(&self) -> Option<MutexGuard<'_, T>> {
+        self.lock_word()
+            .compare_exchange(UNLOCKED, process::current_tid(), Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard::new(self))
+    }
+
+    /// Returns the ID of the thread currently holding the lock, if it's held.
+    ///
+    /// On Linux, this is the holder's TID. On other platforms, it's the holder's process ID.
+    pub fn holder(&self) -> Option<u32> {
+        match self.lock_word().load(Ordering::Relaxed) & HOLDER_MASK {
+            UNLOCKED => None,
+            holder => Some(holder),
+        }
+    }
+
+    fn unlock(&self) {
+        if self.lock_word().swap(UNLOCKED, Ordering::Release) & CONTENDED != 0 {
+            let _ = futex::wake_one(self.lock_word());
+        }
+    }
+}
+
+impl<T: ShmSafe> Drop for Mutex<T> {
+    fn drop(&mut self) {
+        // Instances that already have the mutex open keep it mapped until they're dropped too.
+        if self.owner {
+            self.memory.unlink();
+        }
+    }
+}
+
+impl<T: ShmSafe + std::fmt::Debug> std::fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        d.field("addr", &self.ptr);
+        match self.try_lock_unchecked() {
+            Some(guard) => d.field("obj", guard.deref()),
+            None => d.field("obj", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+
+
+/// Options for allocating (and opening) a [`Mutex`], like which [`Backend`] it lives in.
+///
+/// # Example
+/// ```no_run
+/// use ration::{FileBackend, Mutex, MutexOptions};
+///
+/// // Keep the mutex (and the value it protects) in a plain file.
+/// let mutex: Mutex<u64> = MutexOptions::new()
+///     .backend(FileBackend)
+///     .alloc("/var/lib/my_app/counter")
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct MutexOptions {
+    backend: Arc<dyn Backend>,
+}
+
+impl Default for MutexOptions {
+    fn default() -> Self {
+        Self {
+            backend: Arc::new(ShmemBackend),
+        }
+    }
+}
+
+impl MutexOptions {
+    /// The default options, which are what [`Mutex::alloc`] uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the backend that the mutex's shared memory is created (or opened) with. Defaults to
+    /// [`ShmemBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Allocate a mutex to shared memory identified by the given path. See [`Mutex::alloc`].
+    pub fn alloc<T: ShmSafe>(&self, path: impl AsRef<Path>) -> Result<Mutex<T>> {
+        let memory = self.backend.create(path.as_ref(), Mutex::<T>::block_size())?;
+
+        Ok(unsafe { Mutex::init(memory) })
+    }
+
+    /// Open a mutex in shared memory identified by the given path. See [`Mutex::open`].
+    pub fn open<T: ShmSafe>(&self, path: impl AsRef<Path>) -> Result<Mutex<T>> {
+        Mutex::attach(self.backend.open(path.as_ref())?)
+    }
+}
+
+
+
+/// An acquired lock on a [`Mutex`]. The lock is released when this is dropped.
+///
+/// The lock word records the thread that acquired the lock, so the guard can't be sent to another
+/// thread: if the original thread then exited, the lock would look abandoned while still held.
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>(_: T) {}
+///
+/// let mutex: ration::Mutex<u64> = ration::Mutex::alloc("/dev/shm/MY_MUTEX").unwrap();
+/// assert_send(mutex.lock().unwrap());
+/// ```
+pub struct MutexGuard<'a, T: ShmSafe> {
+    mutex: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+// SAFETY: Sharing the guard only hands out `&T`, the same as sharing the data itself would.
+unsafe impl<T: ShmSafe + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<'a, T: ShmSafe> MutexGuard<'a, T> {
+    fn new(mutex: &'a Mutex<T>) -> Self {
+        Self { mutex, _not_send: PhantomData }
+    }
+}
+
+impl<T: ShmSafe> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.ptr }
+    }
+}
+
+impl<T: ShmSafe> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.ptr }
+    }
+}
+
+impl<T: ShmSafe> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+impl<T: ShmSafe + std::fmt::Debug> std::fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+
+
+/// The result of locking a [`Mutex`].
+pub type LockResult<G> = std::result::Result<G, OwnerDied<G>>;
+
+/// An error returned when a [`Mutex`] was locked after its last holder's process died while
+/// holding it.
+///
+/// The lock has still been acquired, and the guard can be taken out of this error to inspect and
+/// repair the protected data. Once that guard is dropped, the mutex goes back to working normally.
+///
+/// # Note
+///
+/// The holder is only known to be dead because no thread with its ID exists in this process' PID
+/// namespace. If the holder was in another namespace, it may still be alive and holding the lock.
+/// See [`Mutex`]'s owner death section.
+pub struct OwnerDied<G> {
+    guard: G,
+}
+
+impl<G> OwnerDied<G> {
+    /// Take the guard out of this error, acknowledging that the data may need repairing.
+    pub fn into_guard(self) -> G {
+        self.guard
+    }
+
+    /// Get a reference to the guard held by this error.
+    pub fn get_ref(&self) -> &G {
+        &self.guard
+    }
+
+    /// Get a mutable reference to the guard held by this error.
+    pub fn get_mut(&mut self) -> &mut G {
+        &mut self.guard
+    }
+}
+
+impl<G> std::fmt::Debug for OwnerDied<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnerDied").finish_non_exhaustive()
+    }
+}
+
+impl<G> std::fmt::Display for OwnerDied<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the previous holder of the lock died while holding it")
+    }
+}
+
+impl<G> std::error::Error for OwnerDied<G> {}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mutex_test_1() {
+        let mutex: Mutex<u64> = Mutex::alloc("/tmp/TEST_MUTEX_1").unwrap();
+        assert!(mutex.is_owner());
+        *mutex.lock().unwrap() = 71;
+
+        let ref_mutex: Mutex<u64> = Mutex::open("/tmp/TEST_MUTEX_1").unwrap();
+        assert!(!ref_mutex.is_owner());
+        {
+            let mut value = ref_mutex.lock().unwrap();
+            assert_eq!(*value, 71);
+            assert!(mutex.try_lock().is_none());
+            assert_eq!(mutex.holder(), Some(process::current_tid()));
+            *value += 1;
+        }
+
+        assert_eq!(mutex.holder(), None);
+        assert_eq!(*mutex.try_lock().unwrap().unwrap(), 72);
+    }
+
+    #[test]
+    fn mutex_multithreading() {
+        const THREADS: u64 = 8;
+        const INCREMENTS: u64 = 10_000;
+
+        let mutex: Mutex<u64> = Mutex::alloc("/tmp/TEST_MUTEX_MTHREADING").unwrap();
+        *mutex.lock().unwrap() = 0;
+
+        let handles = (0..THREADS)
+            .map(|_| std::thread::spawn(|| {
+                let mutex: Mutex<u64> = Mutex::open("/tmp/TEST_MUTEX_MTHREADING").unwrap();
+                for _ in 0..INCREMENTS {
+                    // Deliberately not atomic, so that lost updates would show up.
+                    let mut value = mutex.lock().unwrap();
+                    let current = *value;
+                    *value = current + 1;
+                }
+            }))
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(*mutex.lock().unwrap(), THREADS * INCREMENTS);
+    }
+
+    #[test]
+    fn mutex_owner_died() {
+        let mutex: Mutex<u64> = Mutex::alloc("/tmp/TEST_MUTEX_OWNERDIED").unwrap();
+        *mutex.lock().unwrap() = 0;
+
+        // Hold the lock from a thread that exits without ever releasing it.
+        std::thread::spawn(|| {
+            let mutex: Mutex<u64> = Mutex::open("/tmp/TEST_MUTEX_OWNERDIED").unwrap();
+            let mut value = mutex.lock().unwrap();
+            *value = 0xdead;
+            std::mem::forget(value);
+        }).join().unwrap();
+
+        let mut guard = match mutex.lock() {
+            Ok(_) => panic!("lock should report that its owner died"),
+            Err(owner_died) => owner_died.into_guard(),
+        };
+        assert_eq!(*guard, 0xdead);
+        assert_eq!(mutex.holder(), Some(process::current_tid()));
+
+        // Repair the data, and everything goes back to normal.
+        *guard = 0;
+        drop(guard);
+        assert_eq!(*mutex.lock().unwrap(), 0);
+    }
+}
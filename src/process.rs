@@ -0,0 +1,44 @@
+//! Process Helpers
+
+
+
+/// The ID of the calling thread, as seen by the whole system.
+///
+/// On Linux, this is the thread's TID, which can be probed with [`is_alive`] from any process. On
+/// other platforms, this falls back to the ID of the calling process.
+pub(crate) fn current_tid() -> u32 {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        std::process::id()
+    }
+}
+
+/// Returns `true` if the thread (or process) with the given ID still exists.
+///
+/// # Note
+///
+/// IDs are recycled by the system, so this can return `true` for a dead thread whose ID has
+/// since been handed to some unrelated thread. IDs are also only meaningful inside one PID
+/// namespace, so this returns `false` for a live thread in another namespace (like a different
+/// container sharing `/dev/shm`).
+pub(crate) fn is_alive(id: u32) -> bool {
+    #[cfg(all(unix, not(miri)))]
+    {
+        // Signal 0 doesn't deliver anything, it only checks that the target exists.
+        if unsafe { libc::kill(id as libc::pid_t, 0) } == 0 {
+            return true;
+        }
+        // The target exists, we just aren't allowed to signal it.
+        std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    // Other processes can't be probed here (or under Miri), so assume they're still around.
+    #[cfg(any(not(unix), miri))]
+    {
+        let _ = id;
+        true
+    }
+}
@@ -0,0 +1,469 @@
+//! Shared Memory Queue
+
+
+
+use std::{
+    mem::MaybeUninit,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    header::{Header, ObjectKind},
+    Backend,
+    Error,
+    Memory,
+    Result,
+    ShmemBackend,
+    ShmSafe,
+};
+
+
+
+/// A bounded, lock-free queue that can store `capacity` elements of type `T`, and can be pushed
+/// to and popped from by any number of processes at once.
+///
+/// Unlike an [`Array`](crate::Array), every slot in a queue carries its own sequence number, so
+/// producers and consumers claim slots one at a time and never step on each other.
+///
+/// # Example
+/// *In your "dispatcher" process:*
+/// ```no_run
+/// use ration::Queue;
+///
+/// let queue: Queue<u32> = Queue::alloc("/tmp/MY_QUEUE", 64).unwrap();
+/// for job in 0..32 {
+///     queue.push(job).unwrap();
+/// }
+/// ```
+/// *In each of your "worker" processes:*
+/// ```no_run
+/// use ration::Queue;
+///
+/// let queue: Queue<u32> = Queue::open("/tmp/MY_QUEUE").unwrap();
+/// while let Some(job) = queue.pop() {
+///     println!("Working on job #{job}...");
+/// }
+/// ```
+pub struct Queue<T: ShmSafe> {
+    memory: Box<dyn Memory>,
+    owner: bool,
+
+    cursors: *const Cursors,
+    base: *mut Slot<T>,
+    capacity: usize,
+}
+
+// SAFETY: Every access to the queue's slots is synchronized through their sequence numbers.
+unsafe impl<T: ShmSafe + Send> Send for Queue<T> {}
+unsafe impl<T: ShmSafe + Send> Sync for Queue<T> {}
+
+/// The read and write positions of a queue, shared by every instance of it.
+///
+/// Both are running totals that are never wrapped, and `position % capacity` is the slot the
+/// position refers to.
+#[repr(C)]
+struct Cursors {
+    /// The position of the next slot to be pushed to.
+    enqueue: AtomicUsize,
+    /// The position of the next slot to be popped from.
+    dequeue: AtomicUsize,
+}
+
+#[repr(C)]
+struct Slot<T> {
+    /// Equal to the slot's position when it's ready to be pushed to, and to its position plus one
+    /// when it's ready to be popped from.
+    sequence: AtomicUsize,
+    value: MaybeUninit<T>,
+}
+
+impl<T: ShmSafe> Queue<T> {
+    /// Allocate a queue to shared memory identified by the given path, with the given capacity.
+    ///
+    /// To allocate a queue with a different [`Backend`], use [`QueueOptions`].
+    pub fn alloc(path: impl AsRef<Path>, capacity: usize) -> Result<Self> {
+        QueueOptions::new().alloc(path, capacity)
+    }
+
+    /// Open a queue in shared memory identified by the given path.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        QueueOptions::new().open(path)
+    }
+
+    /// Create a new queue with the given capacity in the given memory.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be writable, and at least [`block_size`](Queue::block_size) bytes long.
+    unsafe fn init(memory: Box<dyn Memory>, capacity: usize) -> Self {
+        let shm = memory.as_ptr();
+
+        unsafe {
+            (shm as *mut Header)
+                .write(Header::new::<T>(ObjectKind::Queue, capacity).with_layout::<T>());
+
+            let cursors = shm.add(std::mem::size_of::<Header>()) as *mut Cursors;
+            let base = shm.add(Self::slots_offset()) as *mut Slot<T>;
+
+            cursors.write(Cursors {
+                enqueue: AtomicUsize::new(0),
+                dequeue: AtomicUsize::new(0),
+            });
+            for i in 0..capacity {
+                base.add(i).write(Slot {
+                    sequence: AtomicUsize::new(i),
+                    value: MaybeUninit::uninit(),
+                });
+            }
+
+            (*(shm as *const Header)).mark_initialized();
+
+            Self {
+                memory,
+                owner: true,
+                cursors,
+                base,
+                capacity,
+            }
+        }
+    }
+
+    /// Open the queue that was created in the given memory.
+    fn attach(memory: Box<dyn Memory>) -> Result<Self> {
+        // Make sure the queue was allocated by `ration`, and for this type.
+        let header = unsafe {
+            Header::validate_layout::<T>(memory.as_ptr(), memory.len(), ObjectKind::Queue)?
+        };
+        let capacity = header.capacity();
+        let size = Self::block_size(capacity).ok_or(Error::InvalidCapacity { capacity })?;
+
+        if memory.len() < size {
+            return Err(Error::InvalidBlockSize {
+                expected: size,
+                found: memory.len(),
+            });
+        }
+
+        unsafe {
+            let cursors = memory.as_ptr().add(std::mem::size_of::<Header>()) as *const Cursors;
+            let base = memory.as_ptr().add(Self::slots_offset()) as *mut Slot<T>;
+
+            Ok(Self {
+                memory,
+                owner: false,
+                cursors,
+                base,
+                capacity,
+            })
+        }
+    }
+
+    fn slots_offset() -> usize {
+        (std::mem::size_of::<Header>() + std::mem::size_of::<Cursors>())
+            .next_multiple_of(std::mem::align_of::<Slot<T>>())
+    }
+
+    /// The number of bytes needed to hold a queue of this type with the given capacity, or `None`
+    /// if that many slots wouldn't fit in memory.
+    fn block_size(capacity: usize) -> Option<usize> {
+        std::mem::size_of::<Slot<T>>().checked_mul(capacity)?.checked_add(Self::slots_offset())
+    }
+
+    fn cursors(&self) -> &Cursors {
+        unsafe { &*self.cursors }
+    }
+
+    fn slot(&self, position: usize) -> &Slot<T> {
+        unsafe { &*self.base.add(position % self.capacity) }
+    }
+
+    /// Returns the number of elements this queue can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of elements in the queue.
+    ///
+    /// If other processes are using the queue, this is only a snapshot that may already be out of
+    /// date by the time it's returned.
+    pub fn len(&self) -> usize {
+        let dequeue = self.cursors().dequeue.load(Ordering::Acquire);
+        let enqueue = self.cursors().enqueue.load(Ordering::Acquire);
+
+        enqueue.saturating_sub(dequeue).min(self.capacity)
+    }
+
+    /// Returns `true` if the queue contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Push an element to the back of the queue.
+    ///
+    /// Returns the element back if the queue is full.
+    pub fn push(&self, element: T) -> std::result::Result<(), T> {
+        if self.capacity == 0 {
+            return Err(element);
+        }
+
+        let mut position = self.cursors().enqueue.load(Ordering::Relaxed);
+        loop {
+            let slot = self.slot(position);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(position) as isize;
+
+            if diff == 0 {
+                // The slot is free; try to claim it.
+                match self.cursors().enqueue.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe {
+                            (slot.value.as_ptr() as *mut T).write(element);
+                        }
+                        // Hand the slot over to the consumers.
+                        slot.sequence.store(position.wrapping_add(1), Ordering::Release);
+
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                }
+            } else if diff < 0 {
+                // The slot still holds the element from the last time around; the queue is full.
+                return Err(element);
+            } else {
+                // Another producer claimed this slot first.
+                position = self.cursors().enqueue.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Pop an element from the front of the queue.
+    pub fn pop(&self) -> Option<T> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let mut position = self.cursors().dequeue.load(Ordering::Relaxed);
+        loop {
+            let slot = self.slot(position);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(position.wrapping_add(1)) as isize;
+
+            if diff == 0 {
+                // The slot is filled; try to claim it.
+                match self.cursors().dequeue.compare_exchange_weak(
+                    position,
+                    position.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let element = unsafe { slot.value.as_ptr().read() };
+                        // Hand the slot back to the producers, for the next time around.
+                        slot.sequence.store(
+                            position.wrapping_add(self.capacity),
+                            Ordering::Release,
+                        );
+
+                        return Some(element);
+                    }
+                    Err(current) => position = current,
+                }
+            } else if diff < 0 {
+                // Nothing has been pushed to this slot yet; the queue is empty.
+                return None;
+            } else {
+                // Another consumer claimed this slot first.
+                position = self.cursors().dequeue.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T: ShmSafe> Queue<T> {
+    /// Returns `true` if the underlying shared memory mapping is owned by this queue instance.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+}
+
+impl<T: ShmSafe> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // Instances that already have the queue open keep it mapped until they're dropped too.
+        if self.owner {
+            self.memory.unlink();
+        }
+    }
+}
+
+impl<T: ShmSafe> std::fmt::Debug for Queue<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Queue")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+
+
+/// Options for allocating (and opening) a [`Queue`], like which [`Backend`] it lives in.
+///
+/// # Example
+/// ```no_run
+/// use ration::{FileBackend, Queue, QueueOptions};
+///
+/// // Keep the queue (and any jobs still in it) in a plain file.
+/// let queue: Queue<u32> = QueueOptions::new()
+///     .backend(FileBackend)
+///     .alloc("/var/lib/my_app/jobs", 64)
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct QueueOptions {
+    backend: Arc<dyn Backend>,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            backend: Arc::new(ShmemBackend),
+        }
+    }
+}
+
+impl QueueOptions {
+    /// The default options, which are what [`Queue::alloc`] uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the backend that the queue's shared memory is created (or opened) with. Defaults to
+    /// [`ShmemBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Allocate a queue to shared memory identified by the given path, with the given capacity.
+    /// See [`Queue::alloc`].
+    pub fn alloc<T: ShmSafe>(&self, path: impl AsRef<Path>, capacity: usize) -> Result<Queue<T>> {
+        let size = Queue::<T>::block_size(capacity).ok_or(Error::InvalidCapacity { capacity })?;
+        let memory = self.backend.create(path.as_ref(), size)?;
+
+        Ok(unsafe { Queue::init(memory, capacity) })
+    }
+
+    /// Open a queue in shared memory identified by the given path. See [`Queue::open`].
+    pub fn open<T: ShmSafe>(&self, path: impl AsRef<Path>) -> Result<Queue<T>> {
+        Queue::attach(self.backend.open(path.as_ref())?)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_fifo() {
+        let queue: Queue<u32> = Queue::alloc("/tmp/TEST_QUEUE_FIFO", 4).unwrap();
+        assert!(queue.is_owner());
+        assert!(queue.is_empty());
+
+        for i in 0..4 {
+            assert_eq!(queue.push(i), Ok(()));
+        }
+        assert_eq!(queue.push(4), Err(4));
+        assert_eq!(queue.len(), 4);
+
+        let ref_queue: Queue<u32> = Queue::open("/tmp/TEST_QUEUE_FIFO").unwrap();
+        assert!(!ref_queue.is_owner());
+        assert_eq!(ref_queue.capacity(), 4);
+        assert_eq!(ref_queue.pop(), Some(0));
+        assert_eq!(queue.pop(), Some(1));
+
+        // Wrap around.
+        assert_eq!(ref_queue.push(4), Ok(()));
+        assert_eq!(ref_queue.push(5), Ok(()));
+        let rest = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(rest, vec![2, 3, 4, 5]);
+        assert!(ref_queue.is_empty());
+    }
+
+    #[test]
+    fn queue_capacity_overflow() {
+        assert!(matches!(
+            Queue::<u64>::alloc("/tmp/TEST_QUEUE_OVERFLOW", usize::MAX / 8),
+            Err(Error::InvalidCapacity { capacity }) if capacity == usize::MAX / 8,
+        ));
+        assert!(matches!(
+            Queue::<u64>::open("/tmp/TEST_QUEUE_OVERFLOW"),
+            Err(Error::NotFound { .. }),
+        ));
+    }
+
+    #[test]
+    fn queue_many_producers_many_consumers() {
+        const PRODUCERS: u64 = 4;
+        const CONSUMERS: u64 = 4;
+        const PER_PRODUCER: u64 = 10_000;
+
+        let _queue: Queue<u64> = Queue::alloc("/tmp/TEST_QUEUE_MPMC", 32).unwrap();
+
+        let producers = (0..PRODUCERS)
+            .map(|p| std::thread::spawn(move || {
+                let queue: Queue<u64> = Queue::open("/tmp/TEST_QUEUE_MPMC").unwrap();
+                for i in 0..PER_PRODUCER {
+                    let mut job = p * PER_PRODUCER + i;
+                    while let Err(j) = queue.push(job) {
+                        job = j;
+                        std::thread::yield_now();
+                    }
+                }
+            }))
+            .collect::<Vec<_>>();
+
+        let consumed = std::sync::Arc::new(AtomicUsize::new(0));
+        let consumers = (0..CONSUMERS)
+            .map(|_| {
+                let consumed = consumed.clone();
+                std::thread::spawn(move || {
+                    let queue: Queue<u64> = Queue::open("/tmp/TEST_QUEUE_MPMC").unwrap();
+                    let mut jobs = Vec::new();
+                    while consumed.load(Ordering::SeqCst) < (PRODUCERS * PER_PRODUCER) as usize {
+                        match queue.pop() {
+                            Some(job) => {
+                                jobs.push(job);
+                                consumed.fetch_add(1, Ordering::SeqCst);
+                            }
+                            None => std::thread::yield_now(),
+                        }
+                    }
+                    jobs
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut jobs = consumers
+            .into_iter()
+            .flat_map(|c| c.join().unwrap())
+            .collect::<Vec<_>>();
+        jobs.sort_unstable();
+
+        // Every job was popped exactly once.
+        assert_eq!(jobs, (0..PRODUCERS * PER_PRODUCER).collect::<Vec<_>>());
+    }
+}
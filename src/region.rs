@@ -0,0 +1,171 @@
+//! Shared Memory Regions
+
+
+
+use std::rc::Rc;
+
+use crate::{
+    header::{Header, Lifetime},
+    Memory,
+};
+
+
+
+/// The part of a shared memory mapping that an object lives in.
+///
+/// Most objects have a mapping all to themselves, but the objects in a [`Segment`](crate::Segment)
+/// share the segment's mapping, each living in its own region of it. Either way, the region starts
+/// with the object's [`Header`].
+///
+/// Regions that cover a whole mapping also carry out the segment's [`Lifetime`] when they're
+/// dropped, as long as they were [`attach`](Region::attach)ed. Every attached region is listed in
+/// its object's peer table until then.
+pub(crate) struct Region {
+    memory: Rc<dyn Memory>,
+    start: usize,
+    len: usize,
+    /// Whether the object has the mapping to itself.
+    whole: bool,
+    /// Whether this instance created the object (or was made its owner since).
+    owner: bool,
+    /// Whether this instance has been [`attach`](Region::attach)ed to the object's header.
+    attached: bool,
+    /// Whether the segment should be unlinked when this instance is dropped, no matter what.
+    unlink: bool,
+    /// The slot that this instance holds in the header's peer table, if it got one.
+    peer: Option<usize>,
+}
+
+impl Region {
+    /// The whole of the given mapping, which was created by this instance if `owner` is `true`.
+    pub fn whole(memory: Box<dyn Memory>, owner: bool) -> Self {
+        let len = memory.len();
+
+        Self {
+            memory: Rc::from(memory),
+            start: 0,
+            len,
+            whole: true,
+            owner,
+            attached: false,
+            unlink: false,
+            peer: None,
+        }
+    }
+
+    /// The `len` bytes of the given mapping that start at `start`.
+    pub fn part(memory: &Rc<dyn Memory>, start: usize, len: usize, owner: bool) -> Self {
+        debug_assert!(start + len <= memory.len());
+
+        Self {
+            memory: Rc::clone(memory),
+            start,
+            len,
+            whole: false,
+            owner,
+            attached: false,
+            unlink: false,
+            peer: None,
+        }
+    }
+
+    /// A pointer to the start of the region.
+    pub fn as_ptr(&self) -> *mut u8 {
+        unsafe { self.memory.as_ptr().add(self.start) }
+    }
+
+    /// The size of the region, in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if this instance created the object in the region.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    /// Make this instance the object's owner, or stop it from being one.
+    pub fn set_owner(&mut self, owner: bool) {
+        if owner {
+            self.header().claim_owner();
+        } else if self.is_owner() {
+            self.header().release_owner();
+        }
+        self.owner = owner;
+    }
+
+    /// Unlink the segment when this instance is dropped, no matter what its lifetime is.
+    pub fn unlink_on_drop(&mut self) {
+        self.unlink = true;
+    }
+
+    /// A pointer to the start of the whole mapping that the region is a part of.
+    pub fn mapping_ptr(&self) -> *mut u8 {
+        self.memory.as_ptr()
+    }
+
+    /// The size of the whole mapping that the region is a part of, in bytes.
+    pub fn mapping_len(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// The header at the start of the region.
+    pub fn header(&self) -> &Header {
+        unsafe { &*(self.as_ptr() as *const Header) }
+    }
+
+    /// Count this instance as having the object open, once the object's header has been written
+    /// (or validated).
+    ///
+    /// Only regions that cover a whole mapping are counted in the header's attach count.
+    pub fn attach(&mut self) {
+        if self.attached {
+            return;
+        }
+        self.attached = true;
+
+        if self.whole {
+            self.header().attach();
+        }
+        self.peer = self.header().add_peer();
+        if self.is_owner() {
+            self.header().claim_owner();
+        }
+    }
+
+    /// Record that this instance is still alive in the header's peer table.
+    pub fn heartbeat(&self) {
+        if let Some(slot) = self.peer {
+            self.header().heartbeat(slot);
+        }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        if !self.attached {
+            return;
+        }
+
+        if let Some(slot) = self.peer {
+            self.header().remove_peer(slot);
+        }
+        if self.is_owner() {
+            self.header().release_owner();
+        }
+        if !self.whole {
+            return;
+        }
+
+        let last = self.header().detach();
+        let unlink = match self.header().lifetime() {
+            _ if self.unlink => true,
+            Lifetime::Owner => self.is_owner(),
+            Lifetime::LastDetach => last,
+            Lifetime::Persist => false,
+        };
+        if unlink {
+            self.memory.unlink();
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Shared Memory Pointers
+//!
+//! Every process maps a segment at a different address, so a normal pointer stored in shared
+//! memory means nothing to the other processes. The pointers in this module store offsets instead,
+//! which mean the same thing in every mapping of the segment:
+//!
+//! - An [`OffsetPtr`] stores its target's offset from the start of the segment. It can be copied
+//!   anywhere (even out of shared memory), but needs a [`Mapping`] of the segment to be followed.
+//! - A [`RelPtr`] stores its target's offset from the pointer itself. It must live in the same
+//!   segment as its target, and can't be moved on its own.
+
+
+
+use std::marker::PhantomData;
+
+
+
+/// A mapping of a shared memory segment that offsets can be resolved against.
+pub trait Mapping {
+    /// A pointer to the start of the segment, in this process' mapping of it.
+    fn base_ptr(&self) -> *mut u8;
+
+    /// The size of the segment, in bytes.
+    fn mapped_len(&self) -> usize;
+
+    /// Get the offset of the given reference from the start of the segment, if it points into it.
+    fn offset_of<T>(&self, value: &T) -> Option<usize> {
+        let offset = (value as *const T as usize).checked_sub(self.base_ptr() as usize)?;
+        (offset + std::mem::size_of::<T>() <= self.mapped_len()).then_some(offset)
+    }
+
+    /// Get a pointer to a `T` at the given offset, if one fits in the segment there.
+    fn ptr_at<T>(&self, offset: usize) -> Option<*mut T> {
+        // Offset 0 always holds the segment header.
+        if offset == 0 || offset.checked_add(std::mem::size_of::<T>())? > self.mapped_len() {
+            return None;
+        }
+        let ptr = unsafe { self.base_ptr().add(offset) };
+        ptr.cast::<T>().is_aligned().then_some(ptr.cast())
+    }
+}
+
+
+
+/// A pointer to a `T` stored as an offset from the start of its segment.
+///
+/// # Example
+/// ```no_run
+/// use ration::{Block, Mapping, OffsetPtr};
+///
+/// let mut block: Block<[u32; 4]> = Block::alloc("/tmp/MY_NUMBERS").unwrap();
+/// *block = [1, 2, 3, 4];
+///
+/// let third = OffsetPtr::new(&block, &block[2]);
+/// // ...hand `third` to another process, which opens "/tmp/MY_NUMBERS" and then...
+/// // SAFETY: `third` points at an initialized `u32` that nothing is writing to.
+/// assert_eq!(unsafe { third.get(&block) }, Some(&3));
+/// ```
+#[repr(transparent)]
+pub struct OffsetPtr<T> {
+    offset: usize,
+    _marker: PhantomData<*const T>,
+}
+
+// SAFETY: An offset is just a number; following it requires a mapping.
+unsafe impl<T> Send for OffsetPtr<T> {}
+unsafe impl<T> Sync for OffsetPtr<T> {}
+
+impl<T> OffsetPtr<T> {
+    /// A pointer to nothing.
+    pub const fn null() -> Self {
+        Self::from_offset(0)
+    }
+
+    /// Create a pointer from the offset of its target.
+    ///
+    /// An offset of zero is a null pointer.
+    pub const fn from_offset(offset: usize) -> Self {
+        Self {
+            offset,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Create a pointer to the given value, which must be in the given mapping.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value isn't inside the mapping.
+    pub fn new(mapping: &impl Mapping, value: &T) -> Self {
+        let offset = mapping
+            .offset_of(value)
+            .expect("value does not point into the mapping");
+
+        Self::from_offset(offset)
+    }
+
+    /// The offset of the target from the start of the segment.
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns `true` if this pointer doesn't point to anything.
+    pub const fn is_null(&self) -> bool {
+        self.offset == 0
+    }
+
+    /// Get a reference to the target in the given mapping.
+    ///
+    /// Returns `None` if the pointer is null, or if its target doesn't fit in the mapping.
+    ///
+    /// # Safety
+    ///
+    /// Only the bounds and alignment of the target are checked. The caller must make sure that
+    /// the offset points at an initialized, valid `T` (and not, say, into the segment header or
+    /// the middle of some other object), and that nothing in any process writes to it for as long
+    /// as the returned reference is alive.
+    pub unsafe fn get<'a>(&self, mapping: &'a impl Mapping) -> Option<&'a T> {
+        mapping.ptr_at::<T>(self.offset).map(|ptr| unsafe { &*ptr })
+    }
+
+    /// Get a mutable reference to the target in the given mapping.
+    ///
+    /// Returns `None` if the pointer is null, or if its target doesn't fit in the mapping.
+    ///
+    /// # Safety
+    ///
+    /// Only the bounds and alignment of the target are checked. The caller must make sure that
+    /// the offset points at an initialized, valid `T` (and not, say, into the segment header or
+    /// the middle of some other object), and that nothing else in any process reads or writes it
+    /// for as long as the returned reference is alive.
+    pub unsafe fn get_mut<'a>(&self, mapping: &'a mut impl Mapping) -> Option<&'a mut T> {
+        mapping.ptr_at::<T>(self.offset).map(|ptr| unsafe { &mut *ptr })
+    }
+}
+
+impl<T> Clone for OffsetPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for OffsetPtr<T> {}
+
+impl<T> PartialEq for OffsetPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset
+    }
+}
+
+impl<T> Eq for OffsetPtr<T> {}
+
+impl<T> Default for OffsetPtr<T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> std::fmt::Debug for OffsetPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("OffsetPtr").field(&self.offset).finish()
+    }
+}
+
+
+
+/// A pointer to a `T` stored as an offset from the pointer itself.
+///
+/// Since the offset is relative, a `RelPtr` and its target keep pointing at each other no matter
+/// where the segment that holds them is mapped. A `RelPtr` has to live inside the same segment as
+/// its target, so it's usually set in place (with [`set`](RelPtr::set)) inside a shared object.
+///
+/// # Example
+/// ```no_run
+/// use ration::{Block, RelPtr, ShmSafe, TypeLayout};
+///
+/// #[derive(ShmSafe, TypeLayout)]
+/// #[repr(C)]
+/// struct Node {
+///     value: u32,
+///     next: RelPtr<Node>,
+/// }
+///
+/// let mut block: Block<[Node; 2]> = Block::alloc("/tmp/MY_LIST").unwrap();
+/// block[1] = Node { value: 2, next: RelPtr::null() };
+/// block[0].value = 1;
+/// let [first, second] = &mut *block;
+/// first.next.set(second);
+///
+/// // SAFETY: `next` was set to point at the second node, which is initialized.
+/// let second = unsafe { block[0].next.get(&block) }.unwrap();
+/// assert_eq!(second.value, 2);
+/// ```
+#[repr(transparent)]
+pub struct RelPtr<T> {
+    offset: isize,
+    _marker: PhantomData<*const T>,
+}
+
+// SAFETY: An offset is just a number; following it requires a mapping.
+unsafe impl<T> Send for RelPtr<T> {}
+unsafe impl<T> Sync for RelPtr<T> {}
+
+impl<T> RelPtr<T> {
+    /// The offset that marks a null pointer. Unlike zero, this can never point to a real value.
+    const NULL: isize = isize::MIN;
+
+    /// A pointer to nothing.
+    pub const fn null() -> Self {
+        Self {
+            offset: Self::NULL,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns `true` if this pointer doesn't point to anything.
+    pub const fn is_null(&self) -> bool {
+        self.offset == Self::NULL
+    }
+
+    /// Point this pointer at the given value, which should be in the same segment as the pointer.
+    pub fn set(&mut self, value: &T) {
+        self.offset = (value as *const T as isize).wrapping_sub(self as *const Self as isize);
+    }
+
+    /// Make this pointer point to nothing.
+    pub fn clear(&mut self) {
+        self.offset = Self::NULL;
+    }
+
+    /// Get a raw pointer to the target, in this process' mapping of the segment.
+    ///
+    /// Returns `None` if this pointer is null.
+    pub fn as_ptr(&self) -> Option<*mut T> {
+        if self.is_null() {
+            return None;
+        }
+
+        Some((self as *const Self as *mut u8).wrapping_offset(self.offset).cast())
+    }
+
+    /// Convert this pointer into an [`OffsetPtr`] relative to the start of the given mapping.
+    ///
+    /// Returns `None` if this pointer is null, or if it or its target aren't inside the mapping.
+    pub fn resolve(&self, mapping: &impl Mapping) -> Option<OffsetPtr<T>> {
+        // Make sure the pointer itself is in the mapping, so its offset is relative to something
+        // in the segment.
+        mapping.offset_of(self)?;
+
+        let target = self.as_ptr()? as usize;
+        let offset = target.checked_sub(mapping.base_ptr() as usize)?;
+        mapping.ptr_at::<T>(offset)?;
+
+        Some(OffsetPtr::from_offset(offset))
+    }
+
+    /// Get a reference to the target.
+    ///
+    /// Returns `None` if this pointer is null, or if it or its target aren't inside the mapping.
+    ///
+    /// # Safety
+    ///
+    /// The same as for [`OffsetPtr::get`]: the target must be an initialized, valid `T` that
+    /// nothing in any process writes to for as long as the returned reference is alive.
+    pub unsafe fn get<'a>(&self, mapping: &'a impl Mapping) -> Option<&'a T> {
+        unsafe { self.resolve(mapping)?.get(mapping) }
+    }
+}
+
+impl<T> Default for RelPtr<T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> std::fmt::Debug for RelPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut f = f.debug_tuple("RelPtr");
+        if self.is_null() {
+            f.field(&"null");
+        } else {
+            f.field(&self.offset);
+        }
+        f.finish()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Block, ShmSafe, TypeLayout};
+
+    #[derive(ShmSafe, TypeLayout)]
+    #[repr(C)]
+    struct Node {
+        value: u32,
+        next: RelPtr<Node>,
+    }
+
+    #[derive(ShmSafe, TypeLayout)]
+    #[repr(C)]
+    struct List {
+        head: OffsetPtr<Node>,
+        nodes: [Node; 8],
+    }
+
+    #[test]
+    fn relptr_linked_list() {
+        let mut block: Block<List> = Block::alloc("/tmp/TEST_RELPTR_LIST").unwrap();
+        unsafe {
+            std::ptr::write_bytes(&mut *block as *mut List, 0, 1);
+        }
+
+        // Link the nodes together backwards: 7 -> 6 -> ... -> 0.
+        for i in 0..8 {
+            block.nodes[i].value = i as u32;
+            block.nodes[i].next.clear();
+            if i > 0 {
+                let (before, after) = block.nodes.split_at_mut(i);
+                after[0].next.set(&before[i - 1]);
+            }
+        }
+        block.head = OffsetPtr::new(&block, &block.nodes[7]);
+
+        // Walk the list through another mapping of the segment.
+        let ref_block: Block<List> = Block::open("/tmp/TEST_RELPTR_LIST").unwrap();
+        assert_ne!(ref_block.base_ptr(), block.base_ptr());
+
+        let mut values = Vec::new();
+        let mut node = unsafe { ref_block.head.get(&ref_block) };
+        while let Some(n) = node {
+            values.push(n.value);
+            node = unsafe { n.next.get(&ref_block) };
+        }
+        assert_eq!(values, [7, 6, 5, 4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn offsetptr_get_mut() {
+        let mut block: Block<[u64; 4]> = Block::alloc("/tmp/TEST_OFFSETPTR_MUT").unwrap();
+        *block = [0; 4];
+
+        let ptr = OffsetPtr::new(&block, &block[3]);
+        let mut ref_block: Block<[u64; 4]> = Block::open("/tmp/TEST_OFFSETPTR_MUT").unwrap();
+        *unsafe { ptr.get_mut(&mut ref_block) }.unwrap() = 71;
+
+        assert_eq!(block[3], 71);
+    }
+
+    #[test]
+    fn offsetptr_bounds() {
+        let block: Block<u64> = Block::alloc("/tmp/TEST_OFFSETPTR_BOUNDS").unwrap();
+
+        unsafe {
+            assert!(OffsetPtr::<u64>::null().get(&block).is_none());
+            assert!(OffsetPtr::<u64>::from_offset(block.mapped_len()).get(&block).is_none());
+            assert!(block.offset_of(&0_u64).is_none());
+            assert!(OffsetPtr::<u64>::from_offset(1).get(&block).is_none());
+
+            let outside = RelPtr::<u64>::null();
+            assert!(outside.get(&block).is_none());
+        }
+    }
+}
@@ -0,0 +1,651 @@
+//! Shared Memory Reader-Writer Lock
+
+
+
+use std::{
+    ops::{Deref, DerefMut},
+    path::Path,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    futex,
+    header::{Header, ObjectKind},
+    Backend,
+    Error,
+    Memory,
+    Result,
+    ShmemBackend,
+    ShmSafe,
+};
+
+
+
+// The lock state is a single word that holds the number of active readers in its lower 30 bits,
+// and two flags in its upper 2 bits.
+const READ_LOCKED: u32 = 1;
+const MASK: u32 = (1 << 30) - 1;
+/// All of the reader bits set means the lock is held by a writer.
+const WRITE_LOCKED: u32 = MASK;
+const MAX_READERS: u32 = MASK - 1;
+const READERS_WAITING: u32 = 1 << 30;
+const WRITERS_WAITING: u32 = 1 << 31;
+
+/// How many times to spin on the lock before going to sleep on it.
+const SPIN_LIMIT: u32 = 100;
+
+fn is_unlocked(state: u32) -> bool {
+    state & MASK == 0
+}
+
+fn is_write_locked(state: u32) -> bool {
+    state & MASK == WRITE_LOCKED
+}
+
+fn has_readers_waiting(state: u32) -> bool {
+    state & READERS_WAITING != 0
+}
+
+fn has_writers_waiting(state: u32) -> bool {
+    state & WRITERS_WAITING != 0
+}
+
+/// Readers aren't let in while a writer is waiting, so that a steady stream of readers can't keep
+/// writers out forever.
+fn is_read_lockable(state: u32) -> bool {
+    state & MASK < MAX_READERS && !has_readers_waiting(state) && !has_writers_waiting(state)
+}
+
+/// A typed, shared block of memory that can be read by any number of processes (or threads) at a
+/// time, or written by exactly one.
+///
+/// Like a [`Mutex`](crate::Mutex), the lock lives in shared memory alongside the data it
+/// protects, and waiting for it parks the waiter on a futex. Waiting writers take priority over
+/// new readers, so writers can't be starved by a steady stream of readers.
+///
+/// # Example
+/// *In your "parent" process:*
+/// ```no_run
+/// use ration::RwLock;
+///
+/// let config: RwLock<[u32; 8]> = RwLock::alloc("/dev/shm/MY_CONFIG").unwrap();
+/// *config.write() = [71; 8];
+/// ```
+/// *In any number of "child" processes:*
+/// ```no_run
+/// use ration::RwLock;
+///
+/// let config: RwLock<[u32; 8]> = RwLock::open("/dev/shm/MY_CONFIG").unwrap();
+/// println!("MY_CONFIG: {:?}", *config.read()); // [71, 71, 71, 71, 71, 71, 71, 71]
+/// ```
+pub struct RwLock<T: ShmSafe> {
+    memory: Box<dyn Memory>,
+    owner: bool,
+
+    state: *const State,
+    ptr: *mut T,
+}
+
+// SAFETY: Access to the data is synchronized through the lock.
+unsafe impl<T: ShmSafe + Send> Send for RwLock<T> {}
+unsafe impl<T: ShmSafe + Send + Sync> Sync for RwLock<T> {}
+
+#[repr(C)]
+struct State {
+    /// The reader count and flags.
+    state: AtomicU32,
+    /// Bumped every time a writer is woken up, so that writers can wait on it separately from
+    /// readers.
+    writer_notify: AtomicU32,
+}
+
+impl<T: ShmSafe> RwLock<T> {
+    /// Allocate a new shared reader-writer lock at the given path, protecting a value of the given
+    /// type.
+    ///
+    /// Like a [`Block`](crate::Block), the protected value starts off uninitialized, so make sure
+    /// to give it a value before anything else reads it.
+    ///
+    /// To allocate a lock with a different [`Backend`], use [`RwLockOptions`].
+    pub fn alloc(path: impl AsRef<Path>) -> Result<Self> {
+        RwLockOptions::new().alloc(path)
+    }
+
+    /// Open a shared reader-writer lock identified by the given path and type.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        RwLockOptions::new().open(path)
+    }
+
+    /// Create a new lock in the given memory.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be writable, and at least [`block_size`](RwLock::block_size) bytes long.
+    unsafe fn init(memory: Box<dyn Memory>) -> Self {
+        let shm = memory.as_ptr();
+
+        unsafe {
+            (shm as *mut Header)
+                .write(Header::new::<T>(ObjectKind::RwLock, 1).with_layout::<T>());
+
+            let state = shm.add(std::mem::size_of::<Header>()) as *mut State;
+            let ptr = shm.add(Self::data_offset()) as *mut T;
+
+            state.write(State {
+                state: AtomicU32::new(0),
+                writer_notify: AtomicU32::new(0),
+            });
+
+            (*(shm as *const Header)).mark_initialized();
+
+            Self {
+                memory,
+                owner: true,
+                state,
+                ptr,
+            }
+        }
+    }
+
+    /// Open the lock that was created in the given memory.
+    fn attach(memory: Box<dyn Memory>) -> Result<Self> {
+        // Make sure the lock was allocated by `ration`, and for this type.
+        unsafe {
+            Header::validate_layout::<T>(memory.as_ptr(), memory.len(), ObjectKind::RwLock)?;
+        }
+
+        if memory.len() < Self::block_size() {
+            return Err(Error::InvalidBlockSize {
+                expected: Self::block_size(),
+                found: memory.len(),
+            });
+        }
+
+        unsafe {
+            let state = memory.as_ptr().add(std::mem::size_of::<Header>()) as *const State;
+            let ptr = memory.as_ptr().add(Self::data_offset()) as *mut T;
+
+            Ok(Self {
+                memory,
+                owner: false,
+                state,
+                ptr,
+            })
+        }
+    }
+
+    fn data_offset() -> usize {
+        (std::mem::size_of::<Header>() + std::mem::size_of::<State>())
+            .next_multiple_of(std::mem::align_of::<T>())
+    }
+
+    fn block_size() -> usize {
+        Self::data_offset() + std::mem::size_of::<T>()
+    }
+}
+
+impl<T: ShmSafe> RwLock<T> {
+    /// Returns `true` if the underlying shared memory mapping is owned by this lock instance.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    fn state(&self) -> &AtomicU32 {
+        unsafe { &(*self.state).state }
+    }
+
+    fn writer_notify(&self) -> &AtomicU32 {
+        unsafe { &(*self.state).writer_notify }
+    }
+
+    /// Acquire shared read access, waiting for any writer to release the lock first.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let state = self.state().load(Ordering::Relaxed);
+        if !is_read_lockable(state)
+            || self.state()
+                .compare_exchange_weak(state, state + READ_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+        {
+            self.read_contended();
+        }
+
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Try to acquire shared read access without waiting.
+    ///
+    /// Returns `None` if the lock is held by a writer, or if a writer is waiting for it.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.state()
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                is_read_lockable(state).then_some(state + READ_LOCKED)
+            })
+            .ok()
+            .map(|_| RwLockReadGuard { lock: self })
+    }
+
+    /// Acquire exclusive write access, waiting for every reader and writer to release the lock
+    /// first.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        if self.state()
+            .compare_exchange_weak(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.write_contended();
+        }
+
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Try to acquire exclusive write access without waiting.
+    ///
+    /// Returns `None` if the lock is held by anyone.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state()
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                is_unlocked(state).then_some(state + WRITE_LOCKED)
+            })
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+
+    #[cold]
+    fn read_contended(&self) {
+        let mut state = self.spin_read();
+
+        loop {
+            if is_read_lockable(state) {
+                match self.state().compare_exchange_weak(
+                    state,
+                    state + READ_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(s) => {
+                        state = s;
+                        continue;
+                    }
+                }
+            }
+
+            assert!(state & MASK != MAX_READERS, "too many active read locks on a RwLock");
+
+            // Make sure the readers waiting flag is set before going to sleep.
+            if !has_readers_waiting(state) {
+                if let Err(s) = self.state().compare_exchange(
+                    state,
+                    state | READERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = s;
+                    continue;
+                }
+            }
+
+            futex::wait(self.state(), state | READERS_WAITING, None);
+
+            state = self.spin_read();
+        }
+    }
+
+    #[cold]
+    fn write_contended(&self) {
+        let mut state = self.spin_write();
+        let mut other_writers_waiting = 0;
+
+        loop {
+            if is_unlocked(state) {
+                match self.state().compare_exchange_weak(
+                    state,
+                    state | WRITE_LOCKED | other_writers_waiting,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(s) => {
+                        state = s;
+                        continue;
+                    }
+                }
+            }
+
+            // Make sure the writers waiting flag is set before going to sleep.
+            if !has_writers_waiting(state) {
+                if let Err(s) = self.state().compare_exchange(
+                    state,
+                    state | WRITERS_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = s;
+                    continue;
+                }
+            }
+
+            // Other writers might be waiting now too, so keep the flag set once the lock is
+            // acquired.
+            other_writers_waiting = WRITERS_WAITING;
+
+            // Check the state again *after* reading the notification counter, so that a wakeup
+            // between the two can't be missed.
+            let seq = self.writer_notify().load(Ordering::Acquire);
+            state = self.state().load(Ordering::Relaxed);
+            if is_unlocked(state) || !has_writers_waiting(state) {
+                continue;
+            }
+
+            futex::wait(self.writer_notify(), seq, None);
+
+            state = self.spin_write();
+        }
+    }
+
+    fn read_unlock(&self) {
+        let state = self.state().fetch_sub(READ_LOCKED, Ordering::Release) - READ_LOCKED;
+
+        // Readers can only be waiting on a read-locked lock if a writer is waiting too, and that
+        // writer is woken up first.
+        if is_unlocked(state) && has_writers_waiting(state) {
+            self.wake_writer_or_readers(state);
+        }
+    }
+
+    fn write_unlock(&self) {
+        let state = self.state().fetch_sub(WRITE_LOCKED, Ordering::Release) - WRITE_LOCKED;
+
+        if has_writers_waiting(state) || has_readers_waiting(state) {
+            self.wake_writer_or_readers(state);
+        }
+    }
+
+    /// Wake up a waiting writer if there is one, or every waiting reader otherwise.
+    #[cold]
+    fn wake_writer_or_readers(&self, mut state: u32) {
+        debug_assert!(is_unlocked(state));
+
+        if state == WRITERS_WAITING {
+            match self.state().compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    self.wake_writer();
+                    return;
+                }
+                Err(s) => state = s,
+            }
+        }
+
+        if state == READERS_WAITING + WRITERS_WAITING {
+            if self.state()
+                .compare_exchange(state, READERS_WAITING, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+            {
+                // The lock was acquired in the meantime; its new holder will wake the waiters.
+                return;
+            }
+            if self.wake_writer() {
+                return;
+            }
+            // No writer was actually waiting, so wake the readers instead.
+            state = READERS_WAITING;
+        }
+
+        if state == READERS_WAITING
+            && self.state()
+                .compare_exchange(state, 0, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            futex::wake_all(self.state());
+        }
+    }
+
+    fn wake_writer(&self) -> bool {
+        self.writer_notify().fetch_add(1, Ordering::Release);
+        futex::wake_one(self.writer_notify())
+    }
+
+    fn spin_until(&self, f: impl Fn(u32) -> bool) -> u32 {
+        let mut spin = SPIN_LIMIT;
+        loop {
+            let state = self.state().load(Ordering::Relaxed);
+            if f(state) || spin == 0 {
+                return state;
+            }
+            std::hint::spin_loop();
+            spin -= 1;
+        }
+    }
+
+    fn spin_read(&self) -> u32 {
+        self.spin_until(|state| {
+            !is_write_locked(state) || has_readers_waiting(state) || has_writers_waiting(state)
+        })
+    }
+
+    fn spin_write(&self) -> u32 {
+        self.spin_until(|state| is_unlocked(state) || has_writers_waiting(state))
+    }
+}
+
+impl<T: ShmSafe> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        // Instances that already have the lock open keep it mapped until they're dropped too.
+        if self.owner {
+            self.memory.unlink();
+        }
+    }
+}
+
+impl<T: ShmSafe + std::fmt::Debug> std::fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        d.field("addr", &self.ptr);
+        match self.try_read() {
+            Some(guard) => d.field("obj", guard.deref()),
+            None => d.field("obj", &format_args!("<locked>")),
+        };
+        d.finish_non_exhaustive()
+    }
+}
+
+
+
+/// Options for allocating (and opening) a [`RwLock`], like which [`Backend`] it lives in.
+///
+/// # Example
+/// ```no_run
+/// use ration::{FileBackend, RwLock, RwLockOptions};
+///
+/// // Keep the lock (and the value it protects) in a plain file.
+/// let config: RwLock<[u32; 8]> = RwLockOptions::new()
+///     .backend(FileBackend)
+///     .alloc("/var/lib/my_app/config")
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct RwLockOptions {
+    backend: Arc<dyn Backend>,
+}
+
+impl Default for RwLockOptions {
+    fn default() -> Self {
+        Self {
+            backend: Arc::new(ShmemBackend),
+        }
+    }
+}
+
+impl RwLockOptions {
+    /// The default options, which are what [`RwLock::alloc`] uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the backend that the lock's shared memory is created (or opened) with. Defaults to
+    /// [`ShmemBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Allocate a lock to shared memory identified by the given path. See [`RwLock::alloc`].
+    pub fn alloc<T: ShmSafe>(&self, path: impl AsRef<Path>) -> Result<RwLock<T>> {
+        let memory = self.backend.create(path.as_ref(), RwLock::<T>::block_size())?;
+
+        Ok(unsafe { RwLock::init(memory) })
+    }
+
+    /// Open a lock in shared memory identified by the given path. See [`RwLock::open`].
+    pub fn open<T: ShmSafe>(&self, path: impl AsRef<Path>) -> Result<RwLock<T>> {
+        RwLock::attach(self.backend.open(path.as_ref())?)
+    }
+}
+
+
+
+/// Shared read access to a [`RwLock`]. The access is released when this is dropped.
+pub struct RwLockReadGuard<'a, T: ShmSafe> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ShmSafe> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.ptr }
+    }
+}
+
+impl<T: ShmSafe> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+impl<T: ShmSafe + std::fmt::Debug> std::fmt::Debug for RwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+/// Exclusive write access to a [`RwLock`]. The access is released when this is dropped.
+pub struct RwLockWriteGuard<'a, T: ShmSafe> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ShmSafe> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.ptr }
+    }
+}
+
+impl<T: ShmSafe> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.ptr }
+    }
+}
+
+impl<T: ShmSafe> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+impl<T: ShmSafe + std::fmt::Debug> std::fmt::Debug for RwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rwlock_test_1() {
+        let lock: RwLock<u64> = RwLock::alloc("/tmp/TEST_RWLOCK_1").unwrap();
+        assert!(lock.is_owner());
+        *lock.write() = 71;
+
+        let ref_lock: RwLock<u64> = RwLock::open("/tmp/TEST_RWLOCK_1").unwrap();
+        assert!(!ref_lock.is_owner());
+        {
+            // Any number of readers at once.
+            let a = lock.read();
+            let b = ref_lock.read();
+            let c = ref_lock.try_read().unwrap();
+            assert_eq!((*a, *b, *c), (71, 71, 71));
+            assert!(lock.try_write().is_none());
+        }
+        {
+            let mut value = ref_lock.try_write().unwrap();
+            assert!(lock.try_read().is_none());
+            assert!(lock.try_write().is_none());
+            *value += 1;
+        }
+
+        assert_eq!(*lock.read(), 72);
+    }
+
+    #[test]
+    fn rwlock_prefers_writers() {
+        let lock: RwLock<u64> = RwLock::alloc("/tmp/TEST_RWLOCK_WRITERPREF").unwrap();
+        *lock.write() = 0;
+
+        let reader = lock.read();
+        let writer = std::thread::spawn(|| {
+            let lock: RwLock<u64> = RwLock::open("/tmp/TEST_RWLOCK_WRITERPREF").unwrap();
+            *lock.write() = 11;
+        });
+
+        // Wait for the writer to start waiting.
+        while !has_writers_waiting(lock.state().load(Ordering::Relaxed)) {
+            std::thread::yield_now();
+        }
+
+        // New readers have to wait behind the writer.
+        assert!(lock.try_read().is_none());
+
+        drop(reader);
+        writer.join().unwrap();
+        assert_eq!(*lock.read(), 11);
+    }
+
+    #[test]
+    fn rwlock_multithreading() {
+        const THREADS: u64 = 8;
+        const INCREMENTS: u64 = 5_000;
+
+        let lock: RwLock<[u64; 2]> = RwLock::alloc("/tmp/TEST_RWLOCK_MTHREADING").unwrap();
+        *lock.write() = [0, 0];
+
+        let handles = (0..THREADS)
+            .map(|_| std::thread::spawn(|| {
+                let lock: RwLock<[u64; 2]> = RwLock::open("/tmp/TEST_RWLOCK_MTHREADING").unwrap();
+                for _ in 0..INCREMENTS {
+                    {
+                        let mut value = lock.write();
+                        value[0] += 1;
+                        value[1] += 1;
+                    }
+                    // Readers never see a half-finished write.
+                    let value = lock.read();
+                    assert_eq!(value[0], value[1]);
+                }
+            }))
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(*lock.read(), [THREADS * INCREMENTS; 2]);
+    }
+}
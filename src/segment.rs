@@ -0,0 +1,579 @@
+//! Shared Memory Segment
+
+
+
+use std::{
+    path::Path,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    futex,
+    header::{Header, Lifetime, ObjectKind},
+    process,
+    region::Region,
+    Array,
+    Backend,
+    Block,
+    Error,
+    Memory,
+    Result,
+    ShmemBackend,
+    ShmSafe,
+};
+
+
+
+/// The number of objects that a segment can hold.
+const DIRECTORY_SLOTS: usize = 64;
+/// The longest name that an object in a segment can have, in bytes.
+const NAME_LEN: usize = 32;
+/// Every object in a segment starts at a multiple of this many bytes.
+const OBJECT_ALIGN: usize = 128;
+
+/// The directory isn't locked. Otherwise, the lock word holds the ID of the process holding it.
+const UNLOCKED: u32 = 0;
+/// Set in the directory's lock word when somebody may be waiting for the lock.
+const CONTENDED: u32 = 1 << 31;
+/// The bits of the directory's lock word that hold the ID of the process holding the lock.
+const HOLDER_MASK: u32 = !CONTENDED;
+/// How often waiters check whether the process holding the directory's lock is still alive.
+const PROBE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// One large shared memory mapping that holds any number of named objects.
+///
+/// Every [`Block`] and [`Array`] allocated with a path gets a shared memory file of its own, which
+/// gets unwieldy once an application has hundreds of small objects. A segment is a single
+/// mapping that holds a directory of named objects, so a whole application's shared state can be
+/// allocated (and opened) at once.
+///
+/// Objects are created the first time they're asked for, and opened from then on, no matter which
+/// segment instance asks. The returned objects keep the segment's mapping alive, so they can
+/// outlive the segment instance they came from.
+///
+/// # Example
+/// *In your "parent" process:*
+/// ```no_run
+/// use ration::{Array, Block, Segment};
+///
+/// let segment = Segment::alloc("/tmp/MY_APP", 1 << 20).unwrap();
+///
+/// let mut config: Block<u64> = segment.block("config").unwrap();
+/// *config = 71;
+///
+/// let mut queue: Array<u32> = segment.array("queue", 64).unwrap();
+/// queue.push(1);
+/// ```
+/// *In your "child" process:*
+/// ```no_run
+/// use ration::{Array, Block, Segment};
+///
+/// let segment = Segment::open("/tmp/MY_APP").unwrap();
+///
+/// let config: Block<u64> = segment.block("config").unwrap();
+/// println!("config: {}", *config); // 71
+///
+/// let mut queue: Array<u32> = segment.array("queue", 64).unwrap();
+/// println!("queue: {:?}", queue.pop()); // Some(1)
+/// ```
+///
+/// # Note
+///
+/// Space in a segment is never reused, so objects can't be removed from it.
+pub struct Segment {
+    shm: Rc<dyn Memory>,
+    owner: bool,
+    directory: *mut Directory,
+}
+
+/// The table of objects in a segment, stored right after its header.
+#[repr(C)]
+struct Directory {
+    /// Guards the rest of the directory. Holds the ID of the process holding it, so that it can be
+    /// taken over if that process dies.
+    lock: AtomicU32,
+    /// The offset from the start of the segment of the first byte no object uses yet.
+    used: u64,
+    entries: [Entry; DIRECTORY_SLOTS],
+}
+
+/// A named object in a segment. The entry is empty if its offset is zero.
+#[repr(C)]
+struct Entry {
+    name: [u8; NAME_LEN],
+    offset: u64,
+    len: u64,
+}
+
+impl Segment {
+    /// Allocate a new segment at the given path, with room for `size` bytes of objects.
+    ///
+    /// Every object also takes up room for its own header, and some padding.
+    ///
+    /// To allocate a segment with a different [`Backend`], use [`SegmentOptions`].
+    pub fn alloc(path: impl AsRef<Path>, size: usize) -> Result<Self> {
+        SegmentOptions::new().alloc(path, size)
+    }
+
+    /// Open the segment identified by the given path.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        SegmentOptions::new().open(path)
+    }
+
+    /// Create a new segment in the given memory.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be writable, and at least [`objects_offset`](Segment::objects_offset)
+    /// bytes long.
+    unsafe fn init(shm: Box<dyn Memory>) -> Self {
+        unsafe {
+            (shm.as_ptr() as *mut Header)
+                .write(Header::new::<Directory>(ObjectKind::Segment, DIRECTORY_SLOTS));
+
+            let directory = shm.as_ptr().add(Header::data_offset::<Directory>()) as *mut Directory;
+            directory.write(Directory {
+                lock: AtomicU32::new(0),
+                used: Self::objects_offset() as u64,
+                entries: [const { Entry { name: [0; NAME_LEN], offset: 0, len: 0 } };
+                    DIRECTORY_SLOTS],
+            });
+            (*(shm.as_ptr() as *const Header)).mark_initialized();
+
+            Self {
+                shm: Rc::from(shm),
+                owner: true,
+                directory,
+            }
+        }
+    }
+
+    /// Open the segment that was created in the given memory.
+    fn attach(shm: Box<dyn Memory>) -> Result<Self> {
+        // Make sure the segment was allocated by `ration`.
+        unsafe { Header::validate::<Directory>(shm.as_ptr(), shm.len(), ObjectKind::Segment)? };
+
+        if shm.len() < Self::objects_offset() {
+            return Err(Error::InvalidBlockSize {
+                expected: Self::objects_offset(),
+                found: shm.len(),
+            });
+        }
+
+        let directory =
+            unsafe { shm.as_ptr().add(Header::data_offset::<Directory>()) as *mut Directory };
+
+        Ok(Self {
+            shm: Rc::from(shm),
+            owner: false,
+            directory,
+        })
+    }
+
+    // The segment is laid out as:
+    //
+    // | Header | (padding) | Directory | (padding) | object | (padding) | object | ...
+    //
+    // where every object is laid out just like it would be in a segment of its own.
+
+    fn objects_offset() -> usize {
+        (Header::data_offset::<Directory>() + std::mem::size_of::<Directory>())
+            .next_multiple_of(OBJECT_ALIGN)
+    }
+
+    /// Returns `true` if the underlying shared memory mapping is owned by this segment instance.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    /// Returns `true` if the segment holds an object with the given name.
+    pub fn contains(&self, name: &str) -> bool {
+        let Some(key) = name_key(name) else {
+            return false;
+        };
+
+        self.with_directory(|directory| find(directory, &key).is_some())
+    }
+
+    /// Get the block with the given name, allocating it if it doesn't exist yet.
+    ///
+    /// Like [`Block::alloc`], a newly allocated block's value is uninitialized (all zeroes), so
+    /// make sure to write one before anything reads it. Use [`Block::is_owner`] to tell whether the
+    /// block was just allocated.
+    pub fn block<T: ShmSafe>(&self, name: &str) -> Result<Block<T>> {
+        self.object(
+            name,
+            Block::<T>::block_size(),
+            Block::<T>::block_align(),
+            |region| unsafe { Ok(Block::init(region, None, Lifetime::Owner)) },
+            Block::attach,
+        )
+    }
+
+    /// Get the array with the given name, allocating it with the given capacity if it doesn't
+    /// exist yet.
+    ///
+    /// If the array already exists, it keeps the capacity it was allocated with. Either way, the
+    /// capacity can't be zero.
+    pub fn array<T: ShmSafe>(&self, name: &str, capacity: usize) -> Result<Array<T>> {
+        if capacity == 0 {
+            return Err(Error::InvalidCapacity { capacity });
+        }
+        // An array too large to fit in memory certainly doesn't fit in the segment.
+        let size = Array::<T>::block_size(capacity).ok_or(Error::Full {
+            capacity: self.shm.len(),
+        })?;
+
+        self.object(
+            name,
+            size,
+            Array::<T>::block_align(),
+            |region| unsafe { Ok(Array::init(region, capacity, Lifetime::Owner)) },
+            Array::attach,
+        )
+    }
+
+    /// Find the object with the given name, and open it; or make room for it, and initialize it.
+    fn object<O>(
+        &self,
+        name: &str,
+        size: usize,
+        align: usize,
+        init: impl FnOnce(Region) -> Result<O>,
+        attach: impl FnOnce(Region) -> Result<O>,
+    ) -> Result<O> {
+        let key = name_key(name).ok_or(Error::InvalidName)?;
+
+        // The lock is held while the object is initialized, so that nobody can open it half-way.
+        self.with_directory(|directory| {
+            if let Some(entry) = find(directory, &key) {
+                return attach(Region::part(
+                    &self.shm,
+                    entry.offset as usize,
+                    entry.len as usize,
+                    false,
+                ));
+            }
+
+            let start = (directory.used as usize).next_multiple_of(align.max(OBJECT_ALIGN));
+            if start.checked_add(size).is_none_or(|end| end > self.shm.len()) {
+                return Err(Error::Full {
+                    capacity: self.shm.len(),
+                });
+            }
+            let entry = directory
+                .entries
+                .iter_mut()
+                .find(|entry| entry.offset == 0)
+                .ok_or(Error::Full {
+                    capacity: self.shm.len(),
+                })?;
+
+            *entry = Entry {
+                name: key,
+                offset: start as u64,
+                len: size as u64,
+            };
+            directory.used = (start + size) as u64;
+
+            init(Region::part(&self.shm, start, size, true))
+        })
+    }
+
+    /// Run `f` while holding the lock on the directory.
+    ///
+    /// If a process died while holding the lock, it's taken over.
+    fn with_directory<R>(&self, f: impl FnOnce(&mut Directory) -> R) -> R {
+        let lock = unsafe { &(*self.directory).lock };
+        let pid = std::process::id();
+
+        while lock.compare_exchange(UNLOCKED, pid, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            let current = lock.load(Ordering::Relaxed);
+
+            if current == UNLOCKED || !process::is_alive(current & HOLDER_MASK) {
+                // Either the lock was released, or its holder died without releasing it. Keep
+                // the lock marked as contended either way, since there might be other waiters.
+                if lock.compare_exchange(
+                    current,
+                    pid | CONTENDED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ).is_ok() {
+                    break;
+                }
+                continue;
+            }
+
+            // Wait for the lock to be released, checking every so often that its holder is still
+            // alive.
+            if current & CONTENDED != 0
+                || lock.compare_exchange(
+                    current,
+                    current | CONTENDED,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ).is_ok()
+            {
+                futex::wait(lock, current | CONTENDED, Some(PROBE_INTERVAL));
+            }
+        }
+
+        // Release the lock even if `f` panics, since its holder is still alive and so it would
+        // never be taken over.
+        let _guard = DirectoryGuard { lock };
+
+        f(unsafe { &mut *self.directory })
+    }
+}
+
+/// Releases the lock on a segment's directory when dropped.
+struct DirectoryGuard<'a> {
+    lock: &'a AtomicU32,
+}
+
+impl Drop for DirectoryGuard<'_> {
+    fn drop(&mut self) {
+        if self.lock.swap(UNLOCKED, Ordering::Release) & CONTENDED != 0 {
+            futex::wake_one(self.lock);
+        }
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        // Objects opened from the segment keep it mapped until they're dropped too.
+        if self.owner {
+            self.shm.unlink();
+        }
+    }
+}
+
+impl crate::Mapping for Segment {
+    fn base_ptr(&self) -> *mut u8 {
+        self.shm.as_ptr()
+    }
+
+    fn mapped_len(&self) -> usize {
+        self.shm.len()
+    }
+}
+
+impl std::fmt::Debug for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self.with_directory(|directory| {
+            directory
+                .entries
+                .iter()
+                .filter(|entry| entry.offset != 0)
+                .map(|entry| {
+                    let len = entry.name.iter().position(|b| *b == 0).unwrap_or(NAME_LEN);
+                    String::from_utf8_lossy(&entry.name[..len]).into_owned()
+                })
+                .collect::<Vec<_>>()
+        });
+
+        f.debug_struct("Segment")
+            .field("addr", &self.shm.as_ptr())
+            .field("objects", &names)
+            .finish_non_exhaustive()
+    }
+}
+
+
+
+/// Options for allocating (and opening) a [`Segment`], like which [`Backend`] it lives in.
+///
+/// # Example
+/// ```no_run
+/// use ration::{FileBackend, Segment, SegmentOptions};
+///
+/// // Keep the whole application's shared state in a plain file.
+/// let segment = SegmentOptions::new()
+///     .backend(FileBackend)
+///     .alloc("/var/lib/my_app/state", 1 << 20)
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct SegmentOptions {
+    backend: Arc<dyn Backend>,
+}
+
+impl Default for SegmentOptions {
+    fn default() -> Self {
+        Self {
+            backend: Arc::new(ShmemBackend),
+        }
+    }
+}
+
+impl SegmentOptions {
+    /// The default options, which are what [`Segment::alloc`] uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the backend that the segment's shared memory is created (or opened) with. Defaults to
+    /// [`ShmemBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Allocate a segment at the given path, with room for `size` bytes of objects. See
+    /// [`Segment::alloc`].
+    pub fn alloc(&self, path: impl AsRef<Path>, size: usize) -> Result<Segment> {
+        let shm = self.backend.create(path.as_ref(), Segment::objects_offset() + size)?;
+
+        Ok(unsafe { Segment::init(shm) })
+    }
+
+    /// Open the segment identified by the given path. See [`Segment::open`].
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<Segment> {
+        Segment::attach(self.backend.open(path.as_ref())?)
+    }
+}
+
+/// Pad a name out to the size of a directory entry's name.
+fn name_key(name: &str) -> Option<[u8; NAME_LEN]> {
+    if name.is_empty() || name.len() > NAME_LEN || name.contains('\0') {
+        return None;
+    }
+    let mut key = [0; NAME_LEN];
+    key[..name.len()].copy_from_slice(name.as_bytes());
+
+    Some(key)
+}
+
+fn find<'a>(directory: &'a Directory, key: &[u8; NAME_LEN]) -> Option<&'a Entry> {
+    directory
+        .entries
+        .iter()
+        .find(|entry| entry.offset != 0 && entry.name == *key)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Mapping, OffsetPtr};
+
+    #[test]
+    fn segment_test_1() {
+        let segment = Segment::alloc("/tmp/TEST_SEGMENT_1", 1 << 16).unwrap();
+        assert!(segment.is_owner());
+
+        let mut block: Block<(u32, u64)> = segment.block("block").unwrap();
+        assert!(block.is_owner());
+        *block = (1, 2);
+        let mut array: Array<u16> = segment.array("array", 8).unwrap();
+        assert!(array.is_owner());
+        array.push_many([1, 2, 3]);
+        assert!(segment.contains("block") && segment.contains("array"));
+        assert!(!segment.contains("other"));
+
+        let ref_segment = Segment::open("/tmp/TEST_SEGMENT_1").unwrap();
+        assert!(!ref_segment.is_owner());
+
+        let ref_block: Block<(u32, u64)> = ref_segment.block("block").unwrap();
+        assert!(!ref_block.is_owner());
+        assert_eq!(*ref_block, (1, 2));
+
+        // The array keeps its original capacity.
+        let mut ref_array: Array<u16> = ref_segment.array("array", 1).unwrap();
+        assert_eq!(ref_array.capacity(), 8);
+        assert_eq!(ref_array.pop(), Some(1));
+        assert_eq!(array.len(), 2);
+    }
+
+    #[test]
+    fn segment_rejects_other_types() {
+        let segment = Segment::alloc("/tmp/TEST_SEGMENT_TYPES", 1 << 12).unwrap();
+        let _block: Block<u32> = segment.block("value").unwrap();
+
+        assert!(matches!(segment.block::<i32>("value"), Err(Error::LayoutMismatch { .. })));
+        assert!(matches!(
+            segment.array::<u32>("value", 4),
+            Err(Error::KindMismatch { expected: ObjectKind::Array, .. }),
+        ));
+        assert!(matches!(segment.block::<u8>(""), Err(Error::InvalidName)));
+        assert!(matches!(
+            Block::<u32>::open("/tmp/TEST_SEGMENT_TYPES"),
+            Err(Error::KindMismatch { expected: ObjectKind::Block, .. }),
+        ));
+    }
+
+    #[test]
+    fn segment_full() {
+        let segment = Segment::alloc("/tmp/TEST_SEGMENT_FULL", 1 << 12).unwrap();
+
+        assert!(matches!(segment.block::<[u8; 8192]>("big"), Err(Error::Full { .. })));
+        // Sizes that overflow are rejected too, instead of wrapping around to something that fits.
+        assert!(matches!(segment.array::<u64>("huge", usize::MAX / 8), Err(Error::Full { .. })));
+        let capacity = (usize::MAX - 4096) / 2;
+        assert!(matches!(segment.array::<u8>("huge", capacity), Err(Error::Full { .. })));
+        assert!(matches!(
+            segment.array::<u8>("empty", 0),
+            Err(Error::InvalidCapacity { capacity: 0 }),
+        ));
+        for i in 0..DIRECTORY_SLOTS {
+            if let Err(error) = segment.block::<u8>(&i.to_string()) {
+                assert!(matches!(error, Error::Full { .. }));
+                return;
+            }
+        }
+        panic!("segment never filled up");
+    }
+
+    #[test]
+    fn segment_lock_released_on_panic() {
+        let segment = Segment::alloc("/tmp/TEST_SEGMENT_PANIC", 1 << 12).unwrap();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            segment.with_directory(|_| panic!("initializing an object failed"));
+        }));
+        assert!(result.is_err());
+
+        // The lock was released, so the segment can still be used.
+        assert_eq!(unsafe { &(*segment.directory).lock }.load(Ordering::Relaxed), UNLOCKED);
+        let _block: Block<u8> = segment.block("block").unwrap();
+    }
+
+    #[test]
+    fn segment_objects_share_offsets() {
+        let segment = Segment::alloc("/tmp/TEST_SEGMENT_OFFSETS", 1 << 12).unwrap();
+        let mut target: Block<u64> = segment.block("target").unwrap();
+        *target = 71;
+
+        // An offset taken from one object can be followed through another object, or the segment.
+        let mut pointer: Block<OffsetPtr<u64>> = segment.block("pointer").unwrap();
+        *pointer = OffsetPtr::new(&target, &target);
+
+        let ref_segment = Segment::open("/tmp/TEST_SEGMENT_OFFSETS").unwrap();
+        let ref_pointer: Block<OffsetPtr<u64>> = ref_segment.block("pointer").unwrap();
+        assert_eq!(unsafe { ref_pointer.get(&ref_segment) }, Some(&71));
+        assert_eq!(unsafe { ref_pointer.get(&ref_pointer) }, Some(&71));
+        assert!(ref_segment.mapped_len() >= 1 << 12);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "spawns a process")]
+    fn segment_lock_taken_over() {
+        let segment = Segment::alloc("/tmp/TEST_SEGMENT_LOCK", 1 << 12).unwrap();
+
+        // Leave the directory locked by a process that has certainly exited.
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        unsafe { (*segment.directory).lock.store(pid, Ordering::Relaxed) };
+
+        let _block: Block<u64> = segment.block("block").unwrap();
+        assert!(segment.contains("block"));
+        assert_eq!(unsafe { (*segment.directory).lock.load(Ordering::Relaxed) }, UNLOCKED);
+    }
+}
@@ -0,0 +1,419 @@
+//! Shared Memory Sequence-Locked Block
+
+
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{fence, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    header::{Header, ObjectKind},
+    Backend,
+    Error,
+    Memory,
+    Result,
+    ShmemBackend,
+    ShmSafe,
+};
+
+
+
+/// How many times to spin before starting to yield, while waiting for a write to finish.
+const SPIN_LIMIT: u32 = 6;
+/// How many times to yield before starting to sleep, while waiting for a write to finish.
+const YIELD_LIMIT: u32 = 10;
+/// How long to sleep for between checks, once a write has been in progress for a while.
+const SLEEP_INTERVAL: Duration = Duration::from_millis(1);
+
+
+
+/// A typed, shared block of memory that can be read without ever seeing a half-written value.
+///
+/// Writes bump a sequence counter in shared memory before and after modifying the value, and
+/// reads copy the value out, retrying whenever the counter shows that a write overlapped the copy.
+/// Writers never wait for readers, so this is a good fit for small values that are written often
+/// by one process and read by many (telemetry, for example).
+///
+/// # Example
+/// *In your "writer" process:*
+/// ```no_run
+/// use ration::SeqBlock;
+///
+/// let stats: SeqBlock<[u64; 4]> = SeqBlock::alloc("/dev/shm/MY_STATS").unwrap();
+/// stats.store([0; 4]);
+/// stats.write(|stats| stats[0] += 1);
+/// ```
+/// *In your "reader" processes:*
+/// ```no_run
+/// use ration::SeqBlock;
+///
+/// let stats: SeqBlock<[u64; 4]> = SeqBlock::open("/dev/shm/MY_STATS").unwrap();
+/// println!("MY_STATS: {:?}", stats.read()); // [1, 0, 0, 0]
+/// ```
+pub struct SeqBlock<T: Copy + ShmSafe> {
+    memory: Box<dyn Memory>,
+    owner: bool,
+
+    seq: *const AtomicUsize,
+    ptr: *mut T,
+}
+
+// SAFETY: Access to the data is synchronized through the sequence counter.
+unsafe impl<T: Copy + ShmSafe + Send> Send for SeqBlock<T> {}
+unsafe impl<T: Copy + ShmSafe + Send> Sync for SeqBlock<T> {}
+
+impl<T: Copy + ShmSafe> SeqBlock<T> {
+    /// Allocate a new shared sequence-locked block at the given path, and of the given type.
+    ///
+    /// Like a [`Block`](crate::Block), the value starts off uninitialized, so make sure to
+    /// [`store`](SeqBlock::store) one before anything reads it.
+    ///
+    /// To allocate a block with a different [`Backend`], use [`SeqBlockOptions`].
+    pub fn alloc(path: impl AsRef<Path>) -> Result<Self> {
+        SeqBlockOptions::new().alloc(path)
+    }
+
+    /// Open a shared sequence-locked block identified by the given path and type.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        SeqBlockOptions::new().open(path)
+    }
+
+    /// Create a new block in the given memory.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be writable, and at least [`block_size`](SeqBlock::block_size) bytes long.
+    unsafe fn init(memory: Box<dyn Memory>) -> Self {
+        let shm = memory.as_ptr();
+
+        unsafe {
+            (shm as *mut Header)
+                .write(Header::new::<T>(ObjectKind::SeqBlock, 1).with_layout::<T>());
+
+            let seq = shm.add(std::mem::size_of::<Header>()) as *mut AtomicUsize;
+            let ptr = shm.add(Self::data_offset()) as *mut T;
+
+            seq.write(AtomicUsize::new(0));
+
+            (*(shm as *const Header)).mark_initialized();
+
+            Self {
+                memory,
+                owner: true,
+                seq,
+                ptr,
+            }
+        }
+    }
+
+    /// Open the block that was created in the given memory.
+    fn attach(memory: Box<dyn Memory>) -> Result<Self> {
+        // Make sure the block was allocated by `ration`, and for this type.
+        unsafe {
+            Header::validate_layout::<T>(memory.as_ptr(), memory.len(), ObjectKind::SeqBlock)?;
+        }
+
+        if memory.len() < Self::block_size() {
+            return Err(Error::InvalidBlockSize {
+                expected: Self::block_size(),
+                found: memory.len(),
+            });
+        }
+
+        unsafe {
+            let seq = memory.as_ptr().add(std::mem::size_of::<Header>()) as *const AtomicUsize;
+            let ptr = memory.as_ptr().add(Self::data_offset()) as *mut T;
+
+            Ok(Self {
+                memory,
+                owner: false,
+                seq,
+                ptr,
+            })
+        }
+    }
+
+    fn data_offset() -> usize {
+        (std::mem::size_of::<Header>() + std::mem::size_of::<AtomicUsize>())
+            .next_multiple_of(std::mem::align_of::<T>())
+    }
+
+    fn block_size() -> usize {
+        Self::data_offset() + std::mem::size_of::<T>()
+    }
+
+    fn seq(&self) -> &AtomicUsize {
+        unsafe { &*self.seq }
+    }
+
+    /// Returns `true` if the underlying shared memory mapping is owned by this block instance.
+    pub fn is_owner(&self) -> bool {
+        self.owner
+    }
+
+    /// Get a consistent copy of the value, retrying for as long as writes overlap the copy.
+    ///
+    /// Retries back off from spinning to sleeping, but this still waits forever if a writer's
+    /// process died in the middle of a write. Use [`read_timeout`](SeqBlock::read_timeout) to put
+    /// a bound on that.
+    pub fn read(&self) -> T {
+        let mut attempt = 0;
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+            backoff(&mut attempt);
+        }
+    }
+
+    /// Get a consistent copy of the value, retrying for up to `timeout` while writes overlap the
+    /// copy.
+    ///
+    /// Returns `None` if no copy could be made before `timeout` passed.
+    pub fn read_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut attempt = 0;
+        loop {
+            if let Some(value) = self.try_read() {
+                return Some(value);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            backoff(&mut attempt);
+        }
+    }
+
+    /// Try to get a consistent copy of the value, without retrying.
+    ///
+    /// Returns `None` if a write overlapped the copy.
+    pub fn try_read(&self) -> Option<T> {
+        let before = self.seq().load(Ordering::Acquire);
+        if before & 1 != 0 {
+            // A write is in progress.
+            return None;
+        }
+
+        // NOTE: This read may race with a write, but the result is thrown away if it did.
+        let value = unsafe { self.ptr.read_volatile() };
+
+        fence(Ordering::Acquire);
+        let after = self.seq().load(Ordering::Relaxed);
+
+        (before == after).then_some(value)
+    }
+
+    /// Modify the value in place.
+    ///
+    /// If some other instance is writing at the same time, this waits for it to finish first. If
+    /// `f` panics, whatever it wrote up to that point is published as the new value.
+    pub fn write(&self, f: impl FnOnce(&mut T)) {
+        // Claim the write by making the sequence odd.
+        let mut seq = self.seq().load(Ordering::Relaxed);
+        let mut attempt = 0;
+        loop {
+            if seq & 1 != 0 {
+                backoff(&mut attempt);
+                seq = self.seq().load(Ordering::Relaxed);
+                continue;
+            }
+            match self.seq().compare_exchange_weak(
+                seq,
+                seq.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(s) => seq = s,
+            }
+        }
+        // Keep the write below from being reordered before the sequence bump.
+        fence(Ordering::Release);
+
+        // Make the sequence even again once done, publishing the write, even if `f` panics.
+        let _publish = Publish { seq: self.seq(), next: seq.wrapping_add(2) };
+
+        f(unsafe { &mut *self.ptr });
+    }
+
+    /// Replace the value.
+    pub fn store(&self, value: T) {
+        self.write(|t| *t = value);
+    }
+}
+
+impl<T: Copy + ShmSafe> Drop for SeqBlock<T> {
+    fn drop(&mut self) {
+        // Instances that already have the block open keep it mapped until they're dropped too.
+        if self.owner {
+            self.memory.unlink();
+        }
+    }
+}
+
+impl<T: Copy + ShmSafe + std::fmt::Debug> std::fmt::Debug for SeqBlock<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SeqBlock")
+            .field("addr", &self.ptr)
+            .field("obj", &self.read())
+            .finish_non_exhaustive()
+    }
+}
+
+
+
+/// Options for allocating (and opening) a [`SeqBlock`], like which [`Backend`] it lives in.
+///
+/// # Example
+/// ```no_run
+/// use ration::{FileBackend, SeqBlock, SeqBlockOptions};
+///
+/// // Keep the block in a plain file.
+/// let stats: SeqBlock<[u64; 4]> = SeqBlockOptions::new()
+///     .backend(FileBackend)
+///     .alloc("/var/lib/my_app/stats")
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct SeqBlockOptions {
+    backend: Arc<dyn Backend>,
+}
+
+impl Default for SeqBlockOptions {
+    fn default() -> Self {
+        Self {
+            backend: Arc::new(ShmemBackend),
+        }
+    }
+}
+
+impl SeqBlockOptions {
+    /// The default options, which are what [`SeqBlock::alloc`] uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the backend that the block's shared memory is created (or opened) with. Defaults to
+    /// [`ShmemBackend`].
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Allocate a block to shared memory identified by the given path. See [`SeqBlock::alloc`].
+    pub fn alloc<T: Copy + ShmSafe>(&self, path: impl AsRef<Path>) -> Result<SeqBlock<T>> {
+        let memory = self.backend.create(path.as_ref(), SeqBlock::<T>::block_size())?;
+
+        Ok(unsafe { SeqBlock::init(memory) })
+    }
+
+    /// Open a block in shared memory identified by the given path. See [`SeqBlock::open`].
+    pub fn open<T: Copy + ShmSafe>(&self, path: impl AsRef<Path>) -> Result<SeqBlock<T>> {
+        SeqBlock::attach(self.backend.open(path.as_ref())?)
+    }
+}
+
+
+
+/// Ends a write by storing the next even sequence number when dropped.
+struct Publish<'a> {
+    seq: &'a AtomicUsize,
+    next: usize,
+}
+
+impl Drop for Publish<'_> {
+    fn drop(&mut self) {
+        self.seq.store(self.next, Ordering::Release);
+    }
+}
+
+/// Wait a little before checking on a write in progress again, waiting longer the more attempts
+/// have been made.
+fn backoff(attempt: &mut u32) {
+    if *attempt < SPIN_LIMIT {
+        for _ in 0..1 << *attempt {
+            std::hint::spin_loop();
+        }
+    } else if *attempt < SPIN_LIMIT + YIELD_LIMIT {
+        std::thread::yield_now();
+    } else {
+        std::thread::sleep(SLEEP_INTERVAL);
+        return;
+    }
+    *attempt += 1;
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seqblock_test_1() {
+        let block: SeqBlock<(u32, f32)> = SeqBlock::alloc("/tmp/TEST_SEQBLOCK_1").unwrap();
+        assert!(block.is_owner());
+        block.store((1, 0.5));
+
+        let ref_block: SeqBlock<(u32, f32)> = SeqBlock::open("/tmp/TEST_SEQBLOCK_1").unwrap();
+        assert!(!ref_block.is_owner());
+        assert_eq!(ref_block.read(), (1, 0.5));
+
+        ref_block.write(|(a, b)| {
+            *a += 1;
+            *b *= 2.0;
+        });
+        assert_eq!(block.try_read(), Some((2, 1.0)));
+    }
+
+    #[test]
+    fn seqblock_no_torn_reads() {
+        const WRITES: u64 = 100_000;
+
+        let block: SeqBlock<[u64; 16]> = SeqBlock::alloc("/tmp/TEST_SEQBLOCK_TORN").unwrap();
+        block.store([0; 16]);
+
+        let writer = std::thread::spawn(|| {
+            let block: SeqBlock<[u64; 16]> = SeqBlock::open("/tmp/TEST_SEQBLOCK_TORN").unwrap();
+            for i in 1..=WRITES {
+                block.store([i; 16]);
+            }
+        });
+
+        loop {
+            let value = block.read();
+            // Every element is always from the same write.
+            assert!(value.iter().all(|v| *v == value[0]));
+            if value[0] == WRITES {
+                break;
+            }
+        }
+
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn seqblock_write_panics() {
+        let block: SeqBlock<u64> = SeqBlock::alloc("/tmp/TEST_SEQBLOCK_PANIC").unwrap();
+        block.store(1);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            block.write(|value| {
+                *value = 2;
+                panic!("writer failed");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(block.try_read(), Some(2));
+
+        // A writer that never finishes (say, because its process died) only holds readers up for
+        // as long as they're willing to wait.
+        block.seq().fetch_add(1, Ordering::Relaxed);
+        assert_eq!(block.read_timeout(Duration::from_millis(10)), None);
+    }
+}
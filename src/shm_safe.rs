@@ -0,0 +1,143 @@
+//! Shared Memory Safety
+
+
+
+use std::{
+    cell::{Cell, UnsafeCell},
+    marker::PhantomData,
+    mem::MaybeUninit,
+    num::{
+        NonZeroI128, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI8, NonZeroIsize, NonZeroU128,
+        NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8, NonZeroUsize, Wrapping,
+    },
+    sync::atomic::{
+        AtomicBool, AtomicI16, AtomicI32, AtomicI64, AtomicI8, AtomicIsize, AtomicU16, AtomicU32,
+        AtomicU64, AtomicU8, AtomicUsize,
+    },
+};
+
+use crate::{OffsetPtr, RelPtr, TypeLayout};
+
+
+
+/// Types that can be placed in shared memory, and read from any process that maps it.
+///
+/// A type is `ShmSafe` if it holds no pointers, references, or handles into the memory of the
+/// process that wrote it. A `Block<String>` would compile fine otherwise, but every other process
+/// would read a pointer into memory that it doesn't have, and crash (or worse). Use
+/// [`OffsetPtr`] and [`RelPtr`] to point at things in the same mapping instead.
+///
+/// [`Block`](crate::Block) and [`Array`](crate::Array) only hold `ShmSafe` types.
+///
+/// # Deriving
+///
+/// `ShmSafe` can be derived for `#[repr(C)]` structs and enums whose fields are all `ShmSafe`.
+/// Every `ShmSafe` type also has to describe its [`TypeLayout`], which can be derived too:
+/// ```no_run
+/// use ration::{Block, ShmSafe, TypeLayout};
+///
+/// #[derive(ShmSafe, TypeLayout)]
+/// #[repr(C)]
+/// struct Position {
+///     x: f32,
+///     y: f32,
+/// }
+///
+/// let block: Block<Position> = Block::alloc_with("/tmp/MY_POSITION", Position { x: 0.0, y: 0.0 })
+///     .unwrap();
+/// ```
+/// Types that carry pointers are rejected at compile time:
+/// ```compile_fail
+/// use ration::{ShmSafe, TypeLayout};
+///
+/// #[derive(ShmSafe, TypeLayout)]
+/// #[repr(C)]
+/// struct Message {
+///     text: String,
+/// }
+/// ```
+/// ...as are [`OffsetPtr`]s and [`RelPtr`]s to them, since whatever those point at is in shared
+/// memory too:
+/// ```compile_fail
+/// use ration::{Block, OffsetPtr};
+///
+/// let block: Block<OffsetPtr<String>> = Block::alloc("/tmp/MY_POINTER").unwrap();
+/// ```
+/// ...and so are types without a `#[repr(C)]`, since the compiler is free to lay them out
+/// differently in every binary:
+/// ```compile_fail
+/// use ration::{ShmSafe, TypeLayout};
+///
+/// #[derive(ShmSafe, TypeLayout)]
+/// struct Position {
+///     x: f32,
+///     y: f32,
+/// }
+/// ```
+///
+/// # Safety
+///
+/// The type (and everything it contains) must not hold any pointers, references, or other
+/// process-local handles (like file descriptors), and must be the same size and layout in every
+/// process that opens it.
+///
+/// # Note
+///
+/// Tuples are `ShmSafe` when their elements are, but Rust doesn't promise to lay them out the same
+/// way in every binary, so only share them between binaries built with the same toolchain.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be placed in shared memory",
+    note = "types that hold pointers (like `String`, `Vec`, and `Box`) only make sense in the \
+            process that created them",
+    note = "derive `ShmSafe` for `#[repr(C)]` types whose fields are all `ShmSafe`",
+)]
+pub unsafe trait ShmSafe: TypeLayout {}
+
+macro_rules! impl_shm_safe {
+    ($($ty:ty),* $(,)?) => {
+        $(unsafe impl ShmSafe for $ty {})*
+    };
+}
+
+impl_shm_safe!(
+    (), bool, char, f32, f64,
+    u8, u16, u32, u64, u128, usize,
+    i8, i16, i32, i64, i128, isize,
+    NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU128, NonZeroUsize,
+    NonZeroI8, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI128, NonZeroIsize,
+    AtomicBool, AtomicU8, AtomicU16, AtomicU32, AtomicU64, AtomicUsize,
+    AtomicI8, AtomicI16, AtomicI32, AtomicI64, AtomicIsize,
+);
+
+unsafe impl<T: ShmSafe, const N: usize> ShmSafe for [T; N] {}
+unsafe impl<T: ShmSafe> ShmSafe for Option<T> {}
+unsafe impl<T: ShmSafe> ShmSafe for MaybeUninit<T> {}
+unsafe impl<T: ShmSafe> ShmSafe for Wrapping<T> {}
+unsafe impl<T: ShmSafe> ShmSafe for Cell<T> {}
+unsafe impl<T: ShmSafe> ShmSafe for UnsafeCell<T> {}
+unsafe impl<T: ?Sized> ShmSafe for PhantomData<T> {}
+
+// These only store offsets, which mean the same thing in every mapping, but whatever they point
+// to has to be in shared memory too.
+unsafe impl<T: ShmSafe> ShmSafe for OffsetPtr<T> {}
+unsafe impl<T: ShmSafe> ShmSafe for RelPtr<T> {}
+
+macro_rules! impl_shm_safe_tuple {
+    ($($name:ident),+) => {
+        unsafe impl<$($name: ShmSafe),+> ShmSafe for ($($name,)+) {}
+    };
+}
+
+impl_shm_safe_tuple!(A);
+impl_shm_safe_tuple!(A, B);
+impl_shm_safe_tuple!(A, B, C);
+impl_shm_safe_tuple!(A, B, C, D);
+impl_shm_safe_tuple!(A, B, C, D, E);
+impl_shm_safe_tuple!(A, B, C, D, E, F);
+impl_shm_safe_tuple!(A, B, C, D, E, F, G);
+impl_shm_safe_tuple!(A, B, C, D, E, F, G, H);
+
+#[cfg(feature = "arrayvec")]
+unsafe impl<const CAP: usize> ShmSafe for arrayvec::ArrayString<CAP> {}
+#[cfg(feature = "arrayvec")]
+unsafe impl<T: ShmSafe, const CAP: usize> ShmSafe for arrayvec::ArrayVec<T, CAP> {}
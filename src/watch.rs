@@ -0,0 +1,130 @@
+//! Path Watching
+//!
+//! Waits for files to be created or written, so that instances can open segments as soon as their
+//! allocator has linked them. Uses inotify on Linux, and falls back to polling elsewhere (and under
+//! Miri, or for backends whose memory doesn't show up as a file).
+
+
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+
+
+/// How often to check again when there's nothing to watch.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A watch on the directory that holds a path, which collects changes from the moment it's
+/// created until it's dropped, so that nothing that happens between two waits is missed.
+pub(crate) struct Watch {
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(miri)))]
+    fd: Option<std::os::fd::OwnedFd>,
+}
+
+impl Watch {
+    /// Start watching the directory that holds `path`.
+    ///
+    /// Without a path (or if the directory can't be watched), waiting just polls.
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(miri)))]
+    pub(crate) fn new(path: Option<&Path>) -> Self {
+        use std::{
+            ffi::CString,
+            os::{
+                fd::{AsRawFd, FromRawFd, OwnedFd},
+                unix::ffi::OsStrExt,
+            },
+        };
+
+        let dir = path.map(|path| match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        });
+        let Some(dir) = dir.and_then(|dir| CString::new(dir.as_os_str().as_bytes()).ok()) else {
+            return Self { fd: None };
+        };
+
+        let fd = unsafe {
+            let fd = libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK);
+            if fd < 0 {
+                return Self { fd: None };
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+
+        let mask = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_MODIFY | libc::IN_CLOSE_WRITE;
+        let watched = unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) >= 0 };
+
+        Self { fd: watched.then_some(fd) }
+    }
+
+    #[cfg(any(not(any(target_os = "linux", target_os = "android")), miri))]
+    pub(crate) fn new(_path: Option<&Path>) -> Self {
+        Self {}
+    }
+
+    /// Block the current thread until something in the watched directory is created or written
+    /// (since the last wait), or until the deadline.
+    ///
+    /// Returns `false` if the deadline has already passed.
+    #[cfg(all(any(target_os = "linux", target_os = "android"), not(miri)))]
+    pub(crate) fn wait(&self, deadline: Option<Instant>) -> bool {
+        use std::os::fd::AsRawFd;
+
+        let Some(fd) = &self.fd else {
+            return poll(deadline);
+        };
+        let Some(timeout) = time_left(deadline) else {
+            return false;
+        };
+        let timeout_ms = match timeout {
+            // Round up, so that waiting doesn't end just before the deadline.
+            Some(timeout) => timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+
+        unsafe {
+            let mut poll_fd = libc::pollfd {
+                fd: fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            libc::poll(&mut poll_fd, 1, timeout_ms);
+
+            // Throw the events away, so that the next wait only wakes up for new ones.
+            let mut events = [0_u8; 4096];
+            while libc::read(fd.as_raw_fd(), events.as_mut_ptr().cast(), events.len()) > 0 {}
+        }
+
+        true
+    }
+
+    #[cfg(any(not(any(target_os = "linux", target_os = "android")), miri))]
+    pub(crate) fn wait(&self, deadline: Option<Instant>) -> bool {
+        poll(deadline)
+    }
+}
+
+/// Sleep for a short while, or until the deadline.
+///
+/// Returns `false` if the deadline has already passed.
+fn poll(deadline: Option<Instant>) -> bool {
+    let Some(timeout) = time_left(deadline) else {
+        return false;
+    };
+    std::thread::sleep(timeout.map_or(POLL_INTERVAL, |timeout| timeout.min(POLL_INTERVAL)));
+
+    true
+}
+
+/// How long is left until the deadline (`Some(None)` if there isn't one). Returns `None` if the
+/// deadline has already passed.
+fn time_left(deadline: Option<Instant>) -> Option<Option<Duration>> {
+    let Some(deadline) = deadline else {
+        return Some(None);
+    };
+    let left = deadline.checked_duration_since(Instant::now())?;
+
+    (!left.is_zero()).then_some(Some(left))
+}